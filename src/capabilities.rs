@@ -0,0 +1,122 @@
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use crate::error::{Error, Result};
+use crate::mlc::registry_ex::RootKey;
+
+///What an installer may change, declared in its [`Info`](crate::oak::Info) so it can be reviewed without reading the script,
+///and enforced while it runs.
+///
+///Each kind that is left out (`None`) isn't restricted. An empty list allows nothing of that kind
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct Capabilities {
+    ///Folders that files may be written, moved or deleted in
+    #[serde(default)]
+    pub write_roots: Option<Vec<PathBuf>>,
+
+    ///Registry keys, with their hive (such as `HKLM\Software\App`, or just `HKCU`), that may be written or deleted along with their subkeys
+    #[serde(default)]
+    pub registry_keys: Option<Vec<String>>,
+
+    ///Hosts that files may be downloaded from, along with their subdomains
+    #[serde(default)]
+    pub hosts: Option<Vec<String>>,
+}
+
+impl Capabilities {
+
+    ///Check that the logical path `path` may be changed
+    pub fn check_path(&self, path: &Path) -> Result<()> {
+        match &self.write_roots {
+            Some(roots) if !roots.iter().any(|root| is_under(path.to_string_lossy().as_ref(), root.to_string_lossy().as_ref())) => {
+                Err(Error::Capability(format!("{:?} is outside the declared write roots", path)))
+            }
+            _ => {Ok(())}
+        }
+    }
+
+    ///Check that the registry key `key` of `root` may be changed
+    pub fn check_key(&self, root: &RootKey, key: &str) -> Result<()> {
+        let full = format!("{:?}\\{}", root, key);
+
+        match &self.registry_keys {
+            Some(keys) if !keys.iter().any(|declared| is_under(full.as_str(), declared.as_str())) => {
+                Err(Error::Capability(format!("{} is outside the declared registry keys", full)))
+            }
+            _ => {Ok(())}
+        }
+    }
+
+    ///Check that files may be downloaded from `url`
+    pub fn check_url(&self, url: &str) -> Result<()> {
+        let hosts = match &self.hosts {
+            Some(hosts) => {hosts}
+            None => {return Ok(())}
+        };
+
+        let host = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_lowercase))
+            .ok_or(Error::Capability(format!("{} has no host", url)))?;
+
+        let declared = |allowed: &String| {
+            let allowed = allowed.to_lowercase();
+            host == allowed || host.ends_with(format!(".{}", allowed).as_str())
+        };
+
+        if hosts.iter().any(declared) {
+            Ok(())
+        } else {
+            Err(Error::Capability(format!("{} is not a declared host", host)))
+        }
+    }
+
+}
+
+impl Display for Capabilities {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        fn list<T, F: Fn(&T) -> String>(f: &mut Formatter<'_>, title: &str, items: &Option<Vec<T>>, show: F) -> std::fmt::Result {
+            match items {
+                None => {writeln!(f, "    {}: anything (not declared)", title)}
+                Some(items) if items.is_empty() => {writeln!(f, "    {}: nothing", title)}
+                Some(items) => {
+                    writeln!(f, "    {}:", title)?;
+
+                    for item in items {
+                        writeln!(f, "        {}", show(item))?;
+                    }
+
+                    Ok(())
+                }
+            }
+        }
+
+        list(f, "Files written under", &self.write_roots, |root| root.to_string_lossy().to_string())?;
+        list(f, "Registry keys written under", &self.registry_keys, String::clone)?;
+        list(f, "Downloads from", &self.hosts, String::clone)
+    }
+}
+
+///Is `path` the same as or inside `root`. Both are normalised first, comparing without case and treating `\` and `/` alike,
+///so Windows paths and registry keys can be checked on any platform
+pub fn is_under(path: &str, root: &str) -> bool {
+    let path = normalise(path);
+    let root = normalise(root);
+
+    path.len() >= root.len() && path[..root.len()] == root[..]
+}
+
+///Split a path into lower case components, resolving `.` and `..`
+fn normalise(path: &str) -> Vec<String> {
+    let mut components: Vec<String> = Vec::new();
+
+    for component in path.split(['/', '\\']) {
+        match component {
+            "" | "." => {}
+            ".." => {components.pop();}
+            _ => {components.push(component.to_lowercase())}
+        }
+    }
+
+    components
+}
@@ -13,10 +13,36 @@ pub enum Error {
     FSExtra(fs_extra::error::Error),
     Zip(zip::result::ZipError),
     Reqwest(reqwest::Error),
+    #[cfg(windows)]
     Registry(registry::Error),
     SedRegex(sedregex::ErrorKind),
     SerdeJson(serde_json::Error),
     Win32API(String),
+    Lua(rlua::Error),
+    ///An archived entry doesn't match the manifest, so the archive is truncated or corrupted
+    Corrupted(String),
+    ///The archive isn't signed by the expected key
+    Signature(String),
+    ///The exe has an archive appended, but it is damaged
+    Trailer(String),
+    ///The stub given to build an installer from isn't an executable
+    Stub(String),
+    ///A payload reference in a script is malformed, or couldn't be archived
+    Preprocess(String),
+    ///A file declared in the `<files>` section of a source doesn't exist, or its patterns are invalid or match nothing
+    Payload(String),
+    ///A source file couldn't be parsed, or the script or modules it names couldn't be read
+    Source(String),
+    ///A script would leave its uninstaller incomplete, or has a syntax error. Holds every problem found, one per line
+    Lint(String),
+    ///An installer tried to change something its capabilities don't declare
+    Capability(String),
+    ///A zip entry or temporary path would end up outside the folder it must stay in, through `..` or by being absolute
+    PathTraversal(String),
+    ///The server refused a download, or it didn't match its expected SHA-256
+    Download(String),
+    ///The connection broke part way through a download, so asking again may succeed
+    Connection(std::io::Error),
 }
 
 impl Display for Error {
@@ -51,14 +77,26 @@ impl From<reqwest::Error> for Error {
     fn from(e: reqwest::Error) -> Self { Error::Reqwest(e) }
 }
 
+#[cfg(windows)]
 impl From<registry::key::Error> for Error {
     fn from(e: registry::key::Error) -> Self { Error::Registry(registry::Error::from(e)) }
 }
 
+#[cfg(windows)]
 impl From<registry::value::Error> for Error {
     fn from(e: registry::value::Error) -> Self { Error::Registry(registry::Error::from(e)) }
 }
 
+#[cfg(windows)]
+impl From<registry::iter::keys::Error> for Error {
+    fn from(e: registry::iter::keys::Error) -> Self { Error::Registry(registry::Error::from(e)) }
+}
+
+#[cfg(windows)]
+impl From<registry::iter::values::Error> for Error {
+    fn from(e: registry::iter::values::Error) -> Self { Error::Registry(registry::Error::from(e)) }
+}
+
 impl From<sedregex::ErrorKind> for Error {
     fn from(e: ErrorKind) -> Self {
         Error::SedRegex(e)
@@ -69,4 +107,10 @@ impl From<serde_json::Error> for Error {
     fn from(e: serde_json::Error) -> Self {
         Error::SerdeJson(e)
     }
-}
\ No newline at end of file
+}
+
+impl From<rlua::Error> for Error {
+    fn from(e: rlua::Error) -> Self {
+        Error::Lua(e)
+    }
+}
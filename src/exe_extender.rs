@@ -1,58 +1,325 @@
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
 use std::io::{Seek, SeekFrom, Write, Read};
 use std::path::Path;
+use ed25519_dalek::{Signature, SIGNATURE_LENGTH};
+use sha2::{Digest, Sha256};
+use crate::error::{Error, Result};
 
-///Get total length of the exe, and the archive offset
-pub fn get_meta() -> (u64, u64) {
+///Marks the end of an exe that has an archive appended
+pub const MAGIC: &[u8; 8] = b"OAKTRAIL";
 
-    let exe_path = std::env::current_exe().unwrap();
+///The version of the trailer layout written by [`extend_exe`]
+pub const VERSION: u16 = 1;
 
-    let mut fh = OpenOptions::new().read(true).open(&exe_path).unwrap();
+///Signature, offset, length, CRC, version and magic
+const TRAILER_LENGTH: u64 = SIGNATURE_LENGTH as u64 + 8 + 8 + 4 + 2 + 8;
 
-    let length = fh.metadata().unwrap().len();
+///How far back from the end to look for a trailer that has had data appended after it
+const APPENDED_SEARCH: u64 = 1024 * 1024;
 
-    fh.seek(SeekFrom::End(-8)).unwrap();
+///Describes the archive appended to an exe. It is stored at the very end of the exe, after the archive:
+///
+///| Field     | Size | Contents                                                  |
+///|-----------|------|-----------------------------------------------------------|
+///| signature | 64   | ed25519 signature of the archive, or zeros if it's unsigned |
+///| offset    | 8    | Where the archive starts (the length of the stub)          |
+///| length    | 8    | The length of the archive                                  |
+///| crc       | 4    | CRC-32 of the archive                                      |
+///| version   | 2    | [`VERSION`]                                                |
+///| magic     | 8    | [`MAGIC`]                                                  |
+///
+///Numbers are big-endian.
+pub struct Trailer {
+    pub offset: u64,
+    pub length: u64,
+    pub crc: u32,
+    pub signature: Option<Signature>,
+}
+
+impl Trailer {
+    ///Parse a trailer, returning `None` if `bytes` doesn't end with [`MAGIC`]
+    fn from_bytes(bytes: &[u8]) -> Result<Option<Self>> {
+        let (signature, rest) = bytes.split_at(SIGNATURE_LENGTH);
+        let (offset, rest) = rest.split_at(8);
+        let (length, rest) = rest.split_at(8);
+        let (crc, rest) = rest.split_at(4);
+        let (version, magic) = rest.split_at(2);
+
+        if magic != MAGIC {
+            return Ok(None);
+        }
+
+        let version = u16::from_be_bytes(version.try_into().unwrap());
+
+        if version != VERSION {
+            return Err(Error::Trailer(format!("the archive has format version {}, but only version {} is supported", version, VERSION)));
+        }
+
+        Ok(Some(Self {
+            offset: u64::from_be_bytes(offset.try_into().unwrap()),
+            length: u64::from_be_bytes(length.try_into().unwrap()),
+            crc: u32::from_be_bytes(crc.try_into().unwrap()),
+            signature: if signature.iter().all(|b| *b == 0) {
+                None
+            } else {
+                Some(Signature::from_bytes(signature.try_into().unwrap()))
+            },
+        }))
+    }
+
+    ///The size of the exe this trailer describes
+    fn exe_size(&self) -> Option<u64> {
+        self.offset.checked_add(self.length)?.checked_add(TRAILER_LENGTH)
+    }
 
-    let mut v = [0u8; 8];
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(TRAILER_LENGTH as usize);
 
-    fh.read_exact(& mut v).unwrap();
+        //Unsigned archives have a signature of all zeros
+        bytes.extend_from_slice(self.signature.as_ref().map(Signature::to_bytes).unwrap_or([0u8; SIGNATURE_LENGTH]).as_ref());
+        bytes.extend_from_slice(&self.offset.to_be_bytes());
+        bytes.extend_from_slice(&self.length.to_be_bytes());
+        bytes.extend_from_slice(&self.crc.to_be_bytes());
+        bytes.extend_from_slice(&VERSION.to_be_bytes());
+        bytes.extend_from_slice(MAGIC);
 
-    (u64::from_be_bytes(v), length)
+        bytes
+    }
 }
 
-///Take an exe and append an archive to it
-pub fn extend_exe(archive_path: &Path, new_exe: &Path, length: u64) {
+fn damaged(reason: &str) -> Error {
+    Error::Trailer(reason.to_string())
+}
+
+///Read the trailer of the exe at `exe`.
+///
+///Returns `None` if the exe has no archive appended, and an error if it has one but the exe has been truncated or appended to.
+pub fn read_trailer(exe: &Path) -> Result<Option<Trailer>> {
+
+    let mut fh = OpenOptions::new().read(true).open(exe)?;
+
+    let size = fh.metadata()?.len();
+
+    if size < TRAILER_LENGTH {
+        return Ok(None);
+    }
+
+    let start = size.saturating_sub(APPENDED_SEARCH);
 
-    let exe_path = std::env::current_exe().unwrap();
+    let mut tail = Vec::new();
 
-    std::fs::copy(&exe_path, new_exe).unwrap();
+    fh.seek(SeekFrom::Start(start))?;
+    fh.read_to_end(& mut tail)?;
 
-    let mut exe = OpenOptions::new().write(true).append(true).open(new_exe).unwrap();
+    match Trailer::from_bytes(&tail[tail.len() - TRAILER_LENGTH as usize..])? {
+        Some(trailer) => {
+            if trailer.exe_size() != Some(size) {
+                return Err(damaged("the file is not the size recorded in its trailer, so it has been truncated or appended to"));
+            }
 
-    let mut archive = OpenOptions::new().read(true).open(archive_path).unwrap();
+            Ok(Some(trailer))
+        }
+        None => {
+            //A trailer followed by more data means something was appended to an installer.
+            //The magic could also just appear in the exe, so only a trailer that describes where it was found counts
+            for end in (TRAILER_LENGTH as usize..tail.len()).rev() {
+                if &tail[end - MAGIC.len()..end] == MAGIC {
+                    if let Ok(Some(trailer)) = Trailer::from_bytes(&tail[end - TRAILER_LENGTH as usize..end]) {
+                        if trailer.exe_size() == Some(start + end as u64) {
+                            return Err(damaged("data has been appended after the archive"));
+                        }
+                    }
+                }
+            }
 
-    std::io::copy(& mut archive, & mut exe).unwrap();
+            Ok(None)
+        }
+    }
+}
 
-    exe.write_all(length.to_be_bytes().as_ref()).unwrap();
+///Read the trailer of the running exe
+pub fn current_trailer() -> Result<Option<Trailer>> {
+    read_trailer(std::env::current_exe()?.as_path())
 }
 
-///Take an appended exe and get the archive
-pub fn get_archive(archive: &Path, length: u64, offset: u64) {
+///The first bytes of the executable formats a stub can be: PE, ELF, and 32 and 64 bit Mach-O in either byte order, or universal
+const EXECUTABLE_MAGIC: [&[u8]; 7] = [b"MZ", b"\x7fELF", &[0xfe, 0xed, 0xfa, 0xce], &[0xfe, 0xed, 0xfa, 0xcf], &[0xce, 0xfa, 0xed, 0xfe], &[0xcf, 0xfa, 0xed, 0xfe], &[0xca, 0xfe, 0xba, 0xbe]];
+
+///Check that `stub` looks like an executable, so a mistyped stub path is caught when building rather than when the installer is run
+fn check_stub(stub: &Path) -> Result<()> {
+    let mut start = Vec::new();
 
+    OpenOptions::new().read(true).open(stub)?.take(4).read_to_end(& mut start)?;
+
+    if EXECUTABLE_MAGIC.iter().any(|magic| start.starts_with(magic)) {
+        Ok(())
+    } else {
+        Err(Error::Stub(format!("{:?} is not an executable", stub)))
+    }
+}
 
-    let exe_path = std::env::current_exe().unwrap();
+///Take the stub of the exe at `stub` (the whole exe if it has no archive appended) and write it to `new_exe`,
+///followed by the archive at `archive_path` and the trailer, which holds the signature if there is one.
+///
+///The stub doesn't have to be the running exe, or even be for the same platform, so installers can be built for other platforms
+pub fn extend_exe(stub: &Path, archive_path: &Path, new_exe: &Path, signature: Option<&Signature>) -> Result<()> {
 
-    let mut exe = OpenOptions::new().read(true).open(exe_path.as_path()).unwrap();
+    check_stub(stub)?;
 
+    let offset = match read_trailer(stub)? {
+        Some(trailer) => {trailer.offset}
+        None => {std::fs::metadata(stub)?.len()}
+    };
 
+    let mut exe = OpenOptions::new().write(true).create(true).truncate(true).open(new_exe)?;
 
-    exe.seek(SeekFrom::Start(offset)).unwrap();
-    let mut t = exe.take(length - offset - 8);
+    std::io::copy(& mut OpenOptions::new().read(true).open(stub)?.take(offset), & mut exe)?;
 
+    let mut archive = Checksums::new(OpenOptions::new().read(true).open(archive_path)?);
 
-    {let mut archive = OpenOptions::new().create(true).write(true).open(&archive).unwrap();
+    let length = std::io::copy(& mut archive, & mut exe)?;
 
-    std::io::copy(& mut t, & mut archive).unwrap();}
+    let trailer = Trailer {
+        offset,
+        length,
+        crc: archive.crc.finalize(),
+        signature: signature.cloned(),
+    };
 
+    exe.write_all(trailer.to_bytes().as_slice())?;
+
+    Ok(())
 }
 
+///Open the archive described by `trailer` in place in the exe at `exe`, checking it against the CRC.
+///
+///Also gives the SHA-256 of the archive, hashed in the same pass, to check the signature against
+pub fn get_archive(exe: &Path, trailer: &Trailer) -> Result<(Window, [u8; 32])> {
+
+    let window = Window::new(OpenOptions::new().read(true).open(exe)?, trailer.offset, trailer.length);
+
+    let mut t = Checksums::new(window.try_clone()?);
+
+    std::io::copy(& mut t, & mut std::io::sink())?;
+
+    if t.crc.finalize() != trailer.crc {
+        return Err(damaged("the archive doesn't match the checksum in the trailer"));
+    }
+
+    Ok((window, t.sha256.finalize().into()))
+}
+
+///A reader over part of a file, so an archive appended to an exe can be read without copying it out.
+///
+///Every read seeks the file first, so clones made with [`Window::try_clone`] can be used independently
+pub struct Window {
+    file: File,
+    start: u64,
+    length: u64,
+    position: u64,
+}
+
+impl Window {
+    ///A window over the `length` bytes of `file` starting at `start`
+    pub fn new(file: File, start: u64, length: u64) -> Self {
+        Self {
+            file,
+            start,
+            length,
+            position: 0,
+        }
+    }
+
+    ///A window over the whole of `file`
+    pub fn whole(file: File) -> Result<Self> {
+        let length = file.metadata()?.len();
+
+        Ok(Self::new(file, 0, length))
+    }
+
+    ///Get another window over the same part of the file, starting at the beginning
+    pub fn try_clone(&self) -> Result<Self> {
+        Ok(Self::new(self.file.try_clone()?, self.start, self.length))
+    }
+
+    ///Get a window over the `length` bytes starting `start` bytes into this one
+    pub fn slice(&self, start: u64, length: u64) -> Result<Self> {
+        if start.checked_add(length).filter(|end| *end <= self.length).is_none() {
+            return Err(Error::IO(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "the slice is past the end of the window")));
+        }
+
+        Ok(Self::new(self.file.try_clone()?, self.start + start, length))
+    }
+}
+
+impl Read for Window {
+    fn read(& mut self, buf: & mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.length.saturating_sub(self.position);
+
+        if remaining == 0 {
+            return Ok(0);
+        }
+
+        let size = std::cmp::min(buf.len() as u64, remaining) as usize;
+
+        self.file.seek(SeekFrom::Start(self.start + self.position))?;
+
+        let read = self.file.read(& mut buf[..size])?;
+        self.position += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl Seek for Window {
+    fn seek(& mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => {Some(offset)}
+            SeekFrom::End(offset) => {self.length.checked_add_signed(offset)}
+            SeekFrom::Current(offset) => {self.position.checked_add_signed(offset)}
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => {Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid seek to a negative position"))}
+        }
+    }
+}
+
+///Open an archive, either appended to the exe at `path` or, if it has no trailer, the whole of `path`.
+///Also gives the signature of an appended archive
+pub fn open_archive(path: &Path) -> Result<(Window, Option<Signature>)> {
+    match read_trailer(path)? {
+        Some(trailer) => {Ok((get_archive(path, &trailer)?.0, trailer.signature))}
+        None => {Ok((Window::whole(OpenOptions::new().read(true).open(path)?)?, None))}
+    }
+}
+
+///Computes the CRC-32 and SHA-256 of everything read through it
+struct Checksums<R: Read> {
+    inner: R,
+    crc: crc32fast::Hasher,
+    sha256: Sha256,
+}
+
+impl<R: Read> Checksums<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            crc: crc32fast::Hasher::new(),
+            sha256: Sha256::new(),
+        }
+    }
+}
+
+impl<R: Read> Read for Checksums<R> {
+    fn read(& mut self, buf: & mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.crc.update(&buf[..read]);
+        self.sha256.update(&buf[..read]);
+        Ok(read)
+    }
+}
@@ -0,0 +1,373 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tempfile::TempDir;
+use zip::write::FileOptions;
+use zip::DateTime;
+use zip::{ZipArchive, ZipWriter};
+use crate::error::{Error, Result};
+use crate::path_type::enclosed;
+
+///Anything that can be both read and seeked, used for files opened through a [`FileSystem`]
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+///The file operations used by the installer functions.
+///
+///The required methods are the primitives every file system must provide. The provided methods are built from those
+///primitives, and can be overridden where the file system has a better way of doing them.
+pub trait FileSystem {
+    fn exists(&self, path: &Path) -> bool;
+
+    fn is_file(&self, path: &Path) -> bool;
+
+    fn is_dir(&self, path: &Path) -> bool;
+
+    ///Open an existing file for reading
+    fn open(&self, path: &Path) -> Result<Box<dyn ReadSeek + '_>>;
+
+    ///Create or truncate the file at `path` and fill it with `contents`
+    fn write(&self, path: &Path, contents: &mut dyn Read) -> Result<()>;
+
+    ///Create a folder. The parent folder must already exist
+    fn create_dir(&self, path: &Path) -> Result<()>;
+
+    ///List the paths of everything directly inside a folder
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>>;
+
+    ///Remove a file, or a folder and everything in it
+    fn remove(&self, path: &Path) -> Result<()>;
+
+    ///Move a file or folder
+    fn rename(&self, source: &Path, destination: &Path) -> Result<()>;
+
+    ///Create a folder and any missing ancestors
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        if self.is_dir(path) {
+            return Ok(());
+        }
+
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+
+        self.create_dir(path)
+    }
+
+    ///Copy a file, or a folder and everything in it
+    fn copy(&self, source: &Path, destination: &Path) -> Result<()> {
+        if self.is_dir(source) {
+            self.create_dir(destination)?;
+
+            for path in self.read_dir(source)? {
+                self.copy(path.as_path(), destination.join(path.file_name().unwrap()).as_path())?;
+            }
+
+            Ok(())
+        } else {
+            self.write(destination, & mut self.open(source)?)
+        }
+    }
+
+    ///Create a zip archive at `archive` containing everything in `folder`
+    fn zip(&self, archive: &Path, folder: &Path) -> Result<()> {
+        let mut buffer = Cursor::new(Vec::new());
+
+        write_zip(self, folder, & mut buffer)?;
+
+        buffer.seek(SeekFrom::Start(0))?;
+
+        self.write(archive, & mut buffer)
+    }
+
+    ///Extract the zip archive at `archive` into `folder`
+    fn unzip(&self, archive: &Path, folder: &Path) -> Result<()> {
+        extract_zip(self, self.open(archive)?, folder)
+    }
+
+    ///Get a path on the real disk that Lua's io library can open in place of `path`.
+    ///File systems that aren't the real disk give a scratch copy in `temp`, so any writes to it are discarded
+    fn local_path(&self, path: &Path, temp: &TempDir) -> Result<PathBuf> {
+        let (mut file, scratch) = tempfile::NamedTempFile::new_in(temp.path())?.keep().map_err(std::io::Error::from)?;
+
+        if self.is_file(path) {
+            std::io::copy(& mut self.open(path)?, & mut file)?;
+        }
+
+        Ok(scratch)
+    }
+}
+
+///Zip everything in `folder` into `writer`. The same contents always give the same zip, so that archived folders can be deduplicated
+pub fn write_zip<F: FileSystem + ?Sized, W: Write + Seek>(fs: &F, folder: &Path, writer: W) -> Result<()> {
+    let mut zip = ZipWriter::new(writer);
+
+    let options = FileOptions::default().last_modified_time(DateTime::default());
+
+    let mut queue = vec![folder.to_path_buf()];
+
+    while let Some(next) = queue.pop() {
+        let mut paths = fs.read_dir(next.as_path())?;
+        paths.sort();
+
+        for path in paths {
+            let name = path
+                .strip_prefix(folder)
+                .unwrap()
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+                .join("/");
+
+            if fs.is_dir(path.as_path()) {
+                zip.add_directory(name, options)?;
+                queue.push(path);
+            } else {
+                zip.start_file(name, options)?;
+                std::io::copy(& mut fs.open(path.as_path())?, & mut zip)?;
+            }
+        }
+    }
+
+    zip.finish()?;
+
+    Ok(())
+}
+
+///Extract every entry of the zip archive in `reader` into `folder`, creating it if needed
+pub fn extract_zip<F: FileSystem + ?Sized, R: Read + Seek>(fs: &F, reader: R, folder: &Path) -> Result<()> {
+    let mut archive = ZipArchive::new(reader)?;
+
+    fs.create_dir_all(folder)?;
+
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+
+        //A malicious or broken archive could otherwise write anywhere
+        let path = enclosed(folder, file.name())?;
+
+        if file.name().ends_with('/') {
+            fs.create_dir_all(path.as_path())?;
+        } else {
+            if let Some(parent) = path.parent() {
+                fs.create_dir_all(parent)?;
+            }
+
+            fs.write(path.as_path(), & mut file)?;
+        }
+    }
+
+    Ok(())
+}
+
+///The real disk
+pub struct DiskFileSystem;
+
+impl FileSystem for DiskFileSystem {
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn ReadSeek + '_>> {
+        Ok(Box::new(OpenOptions::new().read(true).open(path)?))
+    }
+
+    fn write(&self, path: &Path, contents: &mut dyn Read) -> Result<()> {
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(path)?;
+        std::io::copy(contents, & mut file)?;
+        Ok(())
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<()> {
+        std::fs::create_dir(path)?;
+        Ok(())
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+
+        for entry in std::fs::read_dir(path)? {
+            paths.push(entry?.path());
+        }
+
+        Ok(paths)
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        if path.is_dir() {
+            std::fs::remove_dir_all(path)?;
+        } else if path.is_file() || path.is_symlink() {
+            std::fs::remove_file(path)?;
+        } else {
+            return Err(Error::DoesntExist);
+        }
+
+        Ok(())
+    }
+
+    fn rename(&self, source: &Path, destination: &Path) -> Result<()> {
+        if source.is_dir() {
+            let options = fs_extra::dir::CopyOptions { content_only: true, ..Default::default() };
+
+            fs_extra::dir::move_dir(source, destination, &options)?;
+        } else {
+            let options = fs_extra::file::CopyOptions::default();
+
+            fs_extra::file::move_file(source, destination, &options)?;
+        }
+
+        Ok(())
+    }
+
+    fn copy(&self, source: &Path, destination: &Path) -> Result<()> {
+        if source.is_file() {
+            std::fs::copy(source, destination)?;
+        } else {
+            let options = fs_extra::dir::CopyOptions { content_only: true, ..Default::default() };
+
+            fs_extra::dir::copy(source, destination, &options)?;
+        }
+
+        Ok(())
+    }
+
+    fn zip(&self, archive: &Path, folder: &Path) -> Result<()> {
+        zip_extensions::write::zip_create_from_directory(&archive.to_path_buf(), &folder.to_path_buf())?;
+        Ok(())
+    }
+
+    fn local_path(&self, path: &Path, _temp: &TempDir) -> Result<PathBuf> {
+        Ok(path.to_path_buf())
+    }
+}
+
+enum Node {
+    File(Vec<u8>),
+    Directory,
+}
+
+///A file system held entirely in memory, used for dry runs and to test installer scripts deterministically without
+///touching the disk.
+///
+///Any path without a parent (such as `/` or `C:\`) is treated as an existing root folder.
+pub struct MemoryFileSystem {
+    nodes: Mutex<BTreeMap<PathBuf, Node>>,
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> Self {
+        Self {
+            nodes: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    ///Get the contents of a file, if it exists
+    pub fn contents(&self, path: &Path) -> Option<Vec<u8>> {
+        match self.nodes.lock().unwrap().get(path) {
+            Some(Node::File(contents)) => {Some(contents.clone())}
+            _ => {None}
+        }
+    }
+
+    ///List the path of every file and folder, in order
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.nodes.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn exists(&self, path: &Path) -> bool {
+        path.parent().is_none() || self.nodes.lock().unwrap().contains_key(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        matches!(self.nodes.lock().unwrap().get(path), Some(Node::File(_)))
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.parent().is_none() || matches!(self.nodes.lock().unwrap().get(path), Some(Node::Directory))
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn ReadSeek + '_>> {
+        Ok(Box::new(Cursor::new(self.contents(path).ok_or(Error::DoesntExist)?)))
+    }
+
+    fn write(&self, path: &Path, contents: &mut dyn Read) -> Result<()> {
+        if self.is_dir(path) || !path.parent().map(|parent| self.is_dir(parent)).unwrap_or(false) {
+            return Err(Error::DoesntExist);
+        }
+
+        let mut buffer = Vec::new();
+        contents.read_to_end(& mut buffer)?;
+
+        self.nodes.lock().unwrap().insert(path.to_path_buf(), Node::File(buffer));
+
+        Ok(())
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<()> {
+        if self.exists(path) {
+            return Err(Error::AlreadyExists);
+        }
+
+        if !path.parent().map(|parent| self.is_dir(parent)).unwrap_or(false) {
+            return Err(Error::DoesntExist);
+        }
+
+        self.nodes.lock().unwrap().insert(path.to_path_buf(), Node::Directory);
+
+        Ok(())
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        if !self.is_dir(path) {
+            return Err(Error::DoesntExist);
+        }
+
+        Ok(self.nodes.lock().unwrap().keys().filter(|p| p.parent() == Some(path)).cloned().collect())
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        let mut guard = self.nodes.lock().unwrap();
+
+        if guard.remove(path).is_none() {
+            return Err(Error::DoesntExist);
+        }
+
+        guard.retain(|p, _| !p.starts_with(path));
+
+        Ok(())
+    }
+
+    fn rename(&self, source: &Path, destination: &Path) -> Result<()> {
+        if !self.exists(source) {
+            return Err(Error::DoesntExist);
+        }
+
+        if self.exists(destination) {
+            return Err(Error::AlreadyExists);
+        }
+
+        let mut guard = self.nodes.lock().unwrap();
+
+        let moved: Vec<PathBuf> = guard.keys().filter(|p| p.starts_with(source)).cloned().collect();
+
+        for path in moved {
+            let node = guard.remove(path.as_path()).unwrap();
+            guard.insert(destination.join(path.strip_prefix(source).unwrap()), node);
+        }
+
+        Ok(())
+    }
+}
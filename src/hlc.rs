@@ -1,23 +1,59 @@
-use crate::error::Result;
+use crate::error::{Error, Result};
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
 use std::fs::OpenOptions;
-use clap::lazy_static::lazy_static;
 use std::path::{Path, PathBuf};
 use tempfile::TempDir;
-use crate::exe_extender::{extend_exe, get_meta};
-use crate::oak::{Info, OakRead, OakWrite, OakType, UninstallLocation};
-use crate::path_type::Inverse;
+use crate::exe_extender::{extend_exe, Window};
+use crate::oak::{Info, OakRead, OakWrite, OakType, PayloadEntry, UninstallLocation};
+use crate::source::{PayloadFile, Source};
+use crate::journal::Journal;
+use crate::path_type::{reroot, Inverse};
+use crate::plan::Plan;
+use crate::filesystem::{DiskFileSystem, FileSystem, MemoryFileSystem};
+use crate::registry_backend::RegistryBackend;
+use crate::signing;
+use crate::preprocess;
+use crate::lint;
+use crate::mlc::functions;
+use crate::preprocess::Token;
+use ed25519_dalek::{Signature, VerifyingKey};
+
+
+///Run an installer or uninstaller, making any registry changes through `backend`, and remapping every path under `target_root` if given
+pub fn execute(archive: Window, sha256: &[u8; 32], signature: Option<&Signature>, backend: & dyn RegistryBackend, target_root: Option<&Path>) -> bool {
+    failure(_execute(archive, sha256, signature, backend, target_root))
+}
 
+///Report an error that stopped an installer or uninstaller from running, as a failure
+fn failure(result: Result<bool>) -> bool {
+    result.unwrap_or_else(|e| {
+        println!("Install error: {}", e);
+        true
+    })
+}
 
-pub fn execute<P: AsRef<Path>>(archive: P) -> bool {
+fn _execute(archive: Window, sha256: &[u8; 32], signature: Option<&Signature>, backend: & dyn RegistryBackend, target_root: Option<&Path>) -> Result<bool> {
 
-    //Open the archive
-    let info = {
-        let a = OakRead::new(&archive).unwrap();
+    //The archive was checked against the CRC in the trailer when it was opened, and each entry is checked against the
+    //manifest as it is extracted, so it isn't read again here
+    let read = OakRead::open(archive)?;
 
-        a.info().unwrap()
-    };
+    let info = read.info()?;
+
+    //This has to happen before any of the script is run
+    let trusted = signing::pinned_key().and_then(|pinned| match (pinned, &info.oak_type) {
+        (Some(pinned), OakType::Installer) => {signing::verify_digest(sha256, signature, &pinned)}
+        (Some(_), OakType::Uninstaller) => {signing::check_recorded_digest(sha256, std::env::current_exe()?.as_path())}
+        (None, _) => {Ok(())}
+    });
+
+    if let Err(e) = trusted {
+        println!("The archive can't be trusted: {}", e);
+        return Ok(true);
+    }
 
-    let tmpdir = TempDir::new().unwrap();
+    let tmpdir = TempDir::new()?;
     let tmp_un = tmpdir.path().join("uninstaller");
 
     let uninstaller = {
@@ -37,18 +73,36 @@ pub fn execute<P: AsRef<Path>>(archive: P) -> bool {
     //Get the OakType field of the _info data
     match info.oak_type {
         OakType::Installer => {
-            let result = _install(archive, Some(tmp_un.as_path()));
+            let uninstaller = reroot(uninstaller.ok_or(Error::DoesntExist)?.as_path(), target_root);
+
+            let journal = Journal::location(&uninstaller);
+
+            if !recover(journal.as_path(), backend, target_root)? {
+                return Ok(true);
+            }
+
+            let result = _install(read, Some(tmp_un.as_path()), Some(journal.as_path()), backend, target_root)?;
 
             if !result {
-                let (_, length) = get_meta();
+                extend_exe(std::env::current_exe()?.as_path(), tmp_un.as_path(), uninstaller.as_path(), None)?;
+
+                //Uninstallers are made here rather than on the build machine, so they can't be signed. Instead the installer
+                //records the uninstaller it wrote, and stubs that check signatures only run that uninstaller
+                signing::record(OpenOptions::new().read(true).open(tmp_un.as_path())?, uninstaller.as_path())?;
 
-                extend_exe(tmp_un.as_path(), uninstaller.unwrap().as_path(), length);
+                //Only forget the journal once the uninstaller is safely in place
+                Journal::open(&journal)?.remove()?;
             }
 
-            result
+            Ok(result)
         }
         OakType::Uninstaller => {
-            _install::<P, PathBuf>(archive, None)
+            //An installation that was interrupted while replacing this uninstaller leaves its journal next to it
+            if !recover(Journal::location(std::env::current_exe()?).as_path(), backend, target_root)? {
+                return Ok(true);
+            }
+
+            _install::<PathBuf>(read, None, None, backend, target_root)
         }
     }
 
@@ -56,148 +110,495 @@ pub fn execute<P: AsRef<Path>>(archive: P) -> bool {
 
 }
 
-// Really simple language for oak
-pub fn create_installer(_source: &str, _installer_path: &Path, info: &Info) -> Result<()> {
+///Create an installer from a source, archiving every file and folder its code references and those declared in its `files`,
+///along with the Lua modules in its `modules` folder. Relative references are resolved against the folder holding the source.
+///
+///If the source is `offline`, every url the code or modules download that is written as a literal is fetched now and archived,
+///so the installer doesn't need a network for them. Gives the urls that were fetched.
+pub fn create_installer(source: &Source, installer_path: &Path) -> Result<Vec<String>> {
+
+    let name = source.path.to_string_lossy();
+    let base = source.base.as_path();
+    let info = &source.info;
+
+    let oak_writer = OakWrite::new(installer_path);
+
+    oak_writer.info(info);
+
+    let mut payload = BTreeMap::new();
+
+    for file in &source.files.file {
+        if payload.contains_key(&file.name) {
+            return Err(Error::Payload(format!("{}: declared more than once", file.name)));
+        }
+
+        payload.insert(file.name.clone(), archive_payload(&oak_writer, file).map_err(|message| Error::Payload(format!("{}: {}", file.name, message)))?);
+    }
 
-    lazy_static! {
-        static ref RE: regex::Regex = regex::Regex::new("([\\s]+)data[\\s]+p\"([^\"]+)\"[\\s]*").unwrap();
+    if !payload.is_empty() {
+        oak_writer.payload(&payload);
     }
 
-    let original_source = _source;
+    //Archive each referenced file or folder, and replace the reference with the name it is archived under
+    let reference = |path: &str| {
+        if preprocess::is_pattern(path) {
+            return archive_pattern(&oak_writer, base, path);
+        }
 
-    let oak_writer = OakWrite::new(_installer_path);
+        let path = base.join(path);
 
-    oak_writer.info(info);
+        if !DiskFileSystem.exists(path.as_path()) {
+            return Err(format!("{:?} doesn't exist", path));
+        }
+
+        Ok(preprocess::lua_string(oak_writer.archive(&DiskFileSystem, path).map_err(|e| e.to_string())?.as_str()))
+    };
 
-    //Loop over all data commands, add each file (named as an argument to the data command)
-    //replace with argument with the name (returned when a file is added to the archive)
-    /*let cow = RE.replace_all(original_source.as_str(), |caps: &regex::Captures| {
-        let name = oak_writer.archive(&caps[2]);
-        format!("{}data \"{}\"", &caps[1], name) //We use &cap[1] to preserve the original whitespace (including an end of line line break and maintain readability
-    });*/
+    let mut urls = Vec::new();
 
-    let mut quotes = false;
+    //Modules can reference files too
+    if let Some(modules) = source.modules.as_deref() {
+        let mut code = BTreeMap::new();
 
-    let mut source = String::new();
+        for (module, path) in find_modules(modules)? {
+            let module_name = path.to_string_lossy().to_string();
+            let expanded = preprocess::expand(std::fs::read_to_string(path.as_path())?.as_str(), module_name.as_str(), reference)?;
 
-    let mut file = None;
+            lint::lint(expanded.as_str(), module_name.as_str(), &info.capabilities)?;
+
+            urls.extend(download_urls(expanded.as_str()));
+
+            code.insert(module, expanded);
+        }
+
+        oak_writer.modules(&code);
+    }
 
-    for (i, c) in original_source.as_bytes().iter().enumerate() {
-        if *c == '\"' as u8 {
-            quotes = !quotes;
-        } else if *c == '$' as u8 && !quotes {
+    let code = preprocess::expand(source.code.as_str(), name.as_ref(), reference)?;
 
-            if file.is_none() {
-                file = Some(i+1);
-            } else {
-                let start = file.unwrap();
-                let finish = i;
+    //Mistakes that would leave the uninstaller incomplete fail the build, rather than the install
+    lint::lint(code.as_str(), name.as_ref(), &info.capabilities)?;
 
+    urls.extend(download_urls(code.as_str()));
 
-                let path = PathBuf::from(&original_source[start+1..finish-1]);
+    let mut downloads = BTreeMap::new();
 
-                let name = oak_writer.archive(path);
+    if source.offline {
+        for url in urls {
+            if let Entry::Vacant(entry) = downloads.entry(url) {
+                //Fetching a url the installer couldn't download anyway would only hide the mistake
+                info.capabilities.check_url(entry.key())?;
 
-                source.push_str("\"");
-                source.push_str(name.as_str());
-                source.push_str("\"");
+                let prefetched = prefetch(&oak_writer, entry.key())?;
 
-                file = None;
+                entry.insert(prefetched);
             }
-            continue;
         }
 
-        if file.is_none() {
-            source.push(*c as char);
+        if !downloads.is_empty() {
+            oak_writer.downloads(&downloads);
         }
     }
 
-    oak_writer.commands(source.as_str());
+    oak_writer.commands(code.as_str());
 
+    Ok(downloads.into_keys().collect())
+}
 
+///Archive every file matching a glob pattern, giving a Lua table that maps the path of each (relative to the folder the
+///pattern searches in, separated by `/`) to the name it is archived under. A relative pattern is resolved against `base`
+fn archive_pattern(oak_writer: &OakWrite, base: &Path, pattern: &str) -> std::result::Result<String, String> {
+    let folder = preprocess::pattern_base(pattern);
 
-    Ok(())
+    let relative = Path::new(pattern).strip_prefix(folder.as_path()).unwrap().to_string_lossy().to_string();
+
+    let mut table = String::from("{");
+
+    for (path, name) in archive_matching(oak_writer, base.join(folder).as_path(), &[relative], &[])? {
+        table.push_str(format!("[{}] = {}, ", preprocess::lua_string(path.as_str()), preprocess::lua_string(name.as_str())).as_str());
+    }
 
+    table.push('}');
+
+    Ok(table)
 }
 
-fn _install<P: AsRef<Path>, Q: AsRef<Path>>(installer: P, uninstaller: Option<Q>) -> bool {
+///Find the urls in `source` that are downloaded with a literal, as in `oak.download("https://...", ...)`. Urls worked out
+///while installing can't be found
+fn download_urls(source: &str) -> Vec<String> {
+    let tokens: Vec<Token> = preprocess::tokenize(source).into_iter().map(|(_, token)| token).collect();
 
-    let failed = {
-        //Open installer
-        let mut read = OakRead::new(installer).unwrap();
+    let mut urls = Vec::new();
+
+    for (i, token) in tokens.iter().enumerate() {
+        let call = match token {
+            Token::Name(function) if function == "__download" => {true}
+            Token::Name(function) if function == "download" => {
+                i > 1 && tokens[i - 1] == Token::Symbol('.') && tokens[i - 2] == Token::Name(String::from("oak"))
+            }
+            _ => {false}
+        };
+
+        if let (true, Some(Token::Symbol('(')), Some(Token::String(Some(url)))) = (call, tokens.get(i + 1), tokens.get(i + 2)) {
+            urls.push(url.clone());
+        }
+    }
+
+    urls
+}
+
+///Download `url` into the archive, giving the name it is archived under
+fn prefetch(oak_writer: &OakWrite, url: &str) -> Result<String> {
+    let temp = TempDir::new()?;
+
+    let path = temp.path().join("download");
+
+    functions::fetch(url, & mut std::fs::File::create(path.as_path())?, &|_, _| {})?;
+
+    oak_writer.archive(&DiskFileSystem, path)
+}
+
+///Find the Lua modules in `folder`, naming each by its path within it with `.` between the parts, as `require` does
+fn find_modules(folder: &Path) -> Result<BTreeMap<String, PathBuf>> {
+    if !folder.is_dir() {
+        return Err(Error::Source(format!("the modules folder {:?} doesn't exist", folder)));
+    }
+
+    let pattern = PathBuf::from(glob::Pattern::escape(folder.to_string_lossy().as_ref())).join("**").join("*.lua");
+
+    let mut modules = BTreeMap::new();
+
+    for entry in glob::glob(pattern.to_string_lossy().as_ref()).map_err(|e| Error::Source(e.to_string()))? {
+        let path = entry.map_err(|e| Error::Source(e.to_string()))?;
+
+        let name = path.strip_prefix(folder).unwrap().with_extension("")
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().to_string())
+            .collect::<Vec<_>>()
+            .join(".");
+
+        modules.insert(name, path);
+    }
+
+    Ok(modules)
+}
+
+///Archive a file or folder declared in the `<files>` section of a source
+fn archive_payload(oak_writer: &OakWrite, file: &PayloadFile) -> std::result::Result<PayloadEntry, String> {
+    if !DiskFileSystem.exists(file.path.as_path()) {
+        return Err(format!("{:?} doesn't exist", file.path));
+    }
+
+    if file.include.is_empty() && file.exclude.is_empty() {
+        return Ok(PayloadEntry::Single(oak_writer.archive(&DiskFileSystem, file.path.as_path()).map_err(|e| e.to_string())?));
+    }
+
+    //Excluding files from a folder implies including everything else
+    let include = if file.include.is_empty() {
+        vec!["**/*".to_string()]
+    } else {
+        file.include.clone()
+    };
+
+    Ok(PayloadEntry::Tree(archive_matching(oak_writer, file.path.as_path(), &include, &file.exclude)?))
+}
+
+///Archive every file under `base` matching one of the `include` patterns and none of the `exclude` patterns, which are relative
+///to `base`. Gives a map from the path of each file (relative to `base`, separated by `/`) to the name it is archived under
+fn archive_matching(oak_writer: &OakWrite, base: &Path, include: &[String], exclude: &[String]) -> std::result::Result<BTreeMap<String, String>, String> {
+    let options = glob::MatchOptions { require_literal_separator: true, ..Default::default() };
+
+    let exclude = exclude.iter()
+        .map(|pattern| glob::Pattern::new(pattern).map_err(|e| format!("invalid pattern {:?}: {}", pattern, e)))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    //The base is a literal path, so any pattern characters in it have to be escaped
+    let escaped = PathBuf::from(glob::Pattern::escape(base.to_string_lossy().as_ref()));
+
+    let mut files = BTreeMap::new();
+
+    for pattern in include {
+        let pattern = escaped.join(pattern);
+
+        for entry in glob::glob_with(pattern.to_string_lossy().as_ref(), options).map_err(|e| format!("invalid pattern {:?}: {}", pattern, e))? {
+            let path = entry.map_err(|e| e.to_string())?;
+
+            //Folders are covered by the files in them
+            if !path.is_file() {
+                continue;
+            }
+
+            let relative = path.strip_prefix(base).unwrap_or(path.as_path()).to_path_buf();
+
+            if !exclude.iter().any(|pattern| pattern.matches_path_with(relative.as_path(), options)) {
+                let key = relative.components()
+                    .map(|component| component.as_os_str().to_string_lossy().to_string())
+                    .collect::<Vec<_>>()
+                    .join("/");
+
+                files.insert(key, path);
+            }
+        }
+    }
 
-        //Get code
-        let code = read.commands().unwrap();
+    if files.is_empty() {
+        return Err(format!("{:?} doesn't match any files", base.join(include.join(", "))));
+    }
+
+    files.into_iter()
+        .map(|(key, path)| Ok((key, oak_writer.archive(&DiskFileSystem, path.as_path()).map_err(|e| e.to_string())?)))
+        .collect()
+}
+
+fn _install<Q: AsRef<Path>>(read: OakRead, uninstaller: Option<Q>, journal: Option<&Path>, backend: & dyn RegistryBackend, target_root: Option<&Path>) -> Result<bool> {
+
+    let failed = {
+        let info = read.info()?;
 
         //Open uninstaller
-        let mut write = uninstaller.as_ref().map(|u| OakWrite::new(u));
+        let mut write = uninstaller.as_ref().map(OakWrite::new);
 
 
-        let temp = tempfile::TempDir::new().unwrap();
+        let temp = tempfile::TempDir::new()?;
 
-        let inverses = match &uninstaller {
-            None => {None}
-            Some(_) => {Some(Inverse::new())}
+        let inverses = match (&uninstaller, journal) {
+            (None, _) => {None}
+            (Some(_), None) => {Some(Inverse::new())}
+            (Some(_), Some(journal)) => {Some(Inverse::with_journal(Journal::create(journal, target_root)?))}
         };
 
+        let failed = match info.oak_type {
+            OakType::Installer => {
+                //Get code
+                let code = read.commands()?;
 
-        let res = crate::mlc::run(code.as_str(), & mut read, write.as_ref(), inverses.as_ref(), &temp);
-
-        if let Some(writer) = & mut write {
-            let st = inverses.unwrap().combine();
+                crate::mlc::run(code.as_str(), &read, write.as_ref(), inverses.as_ref(), &temp, None, &DiskFileSystem, backend, target_root).is_err()
+            }
+            OakType::Uninstaller => {
+                //Uninstallers hold a list of inverses which are executed natively
+                let ops = read.inverses()?;
+
+                match crate::mlc::replay(ops.as_slice(), &read, &DiskFileSystem, backend, &temp, target_root) {
+                    Ok(_) => {false}
+                    Err(e) => {
+                        println!("Uninstall error: {}", e);
+                        true
+                    }
+                }
+            }
+        };
 
-            writer.commands(st.as_str());
+        if let (Some(writer), Some(inverses)) = (& mut write, &inverses) {
+            writer.inverses(inverses.ops().as_slice());
 
-            writer.info( &Info::default().set_type(OakType::Uninstaller).set_uninstaller_location(UninstallLocation::Null) );
+            writer.info( Info::default().set_type(OakType::Uninstaller).set_uninstaller_location(UninstallLocation::Null) );
         }
 
 
-        res.is_err()
+        failed
     };
 
     if failed {
 
         if let Some(u) = uninstaller {
-            _install(u.as_ref(), None::<PathBuf>);
+            _install(OakRead::new(u.as_ref())?, None::<PathBuf>, None, backend, target_root)?;
 
 
-            std::fs::remove_file(u).unwrap();
+            std::fs::remove_file(u)?;
+        }
+
+        //The changes have been undone, so the journal is no longer needed
+        if let Some(journal) = journal {
+            Journal::open(journal)?.remove()?;
         }
 
     }
 
-    failed
+    Ok(failed)
 }
 
-pub fn install<P: AsRef<Path>>(installer: P, uninstaller: P) -> bool {
-    _install(installer, Some(uninstaller))
+///Run a bare installer archive, writing its uninstaller archive to `uninstaller`
+pub fn install<P: AsRef<Path>>(installer: OakRead, uninstaller: P, backend: & dyn RegistryBackend, target_root: Option<&Path>) -> bool {
+    if let Err(e) = installer.verify() {
+        println!("The archive is damaged: {}", e);
+        return true;
+    }
+
+    failure(_install(installer, Some(uninstaller), None, backend, target_root))
 }
 
-pub fn uninstall<P: AsRef<Path>>(uninstaller: P) -> bool {
+///Run a bare uninstaller archive
+pub fn uninstall(uninstaller: OakRead, backend: & dyn RegistryBackend, target_root: Option<&Path>) -> bool {
+    if let Err(e) = uninstaller.verify() {
+        println!("The archive is damaged: {}", e);
+        return true;
+    }
 
-    _install(uninstaller, None::<PathBuf>)
+    failure(_install(uninstaller, None::<PathBuf>, None, backend, target_root))
 }
 
-///List all the files, folders and commands in an oak repo
-pub fn list<P: AsRef<Path>>(repo: P) -> Result<()> {
+///Check an archive against its manifest and, if `key` is given, check that it can be trusted: installers must be signed
+///by `key`, and uninstallers must be the one recorded next to `path` by the installer that wrote them
+pub fn verify(archive: Window, signature: Option<&Signature>, key: Option<&VerifyingKey>, path: &Path) -> Result<()> {
+    let read = OakRead::open(archive.try_clone()?)?;
+
+    read.verify()?;
 
-    {
-        let archive = zip::ZipArchive::new(OpenOptions::new().read(true).open(repo.as_ref())?)?;
+    if let Some(key) = key {
+        match read.info()?.oak_type {
+            OakType::Installer => {signing::verify(archive, signature, key)?}
+            //Uninstallers aren't signed, so check that `path` is the uninstaller its installer recorded
+            OakType::Uninstaller => {signing::check_recorded(archive, path)?}
+        }
+    }
 
-        println!("Stored files:");
+    Ok(())
+}
 
-        for name in archive.file_names() {
-            if name != "_commands" {
-                println!("    {}", name);
-            }
+///Extract every archived file and folder into `folder`, each named after its entry
+pub fn extract(read: &OakRead, folder: &Path) -> Result<()> {
+    std::fs::create_dir_all(folder)?;
+
+    for name in read.entries() {
+        read.extract(&DiskFileSystem, name.as_str(), folder.join(name.as_str()))?;
+    }
+
+    Ok(())
+}
+
+///Undo the changes recorded in a journal left behind by an interrupted installation (made under `target_root`, if given), then delete the journal
+pub fn rollback<P: AsRef<Path>>(journal: P, backend: & dyn RegistryBackend, target_root: Option<&Path>) -> Result<()> {
+
+    let journal = Journal::open(journal)?;
+
+    let temp = TempDir::new()?;
+
+    crate::mlc::replay(journal.ops()?.as_slice(), &journal, &DiskFileSystem, backend, &temp, target_root)?;
+
+    journal.remove()
+}
+
+///If an interrupted installation left a journal behind, offer to roll it back. Returns true if we should carry on, from the start
+fn recover(journal: &Path, backend: & dyn RegistryBackend, target_root: Option<&Path>) -> Result<bool> {
+
+    if !journal.exists() {
+        return Ok(true);
+    }
+
+    println!("An interrupted installation was found at {:?}", journal);
+    println!("Enter 'r' to roll back its changes, 's' to roll them back and start this program again from the beginning, or anything else to quit");
+
+    let mut answer = String::new();
+
+    std::io::stdin().read_line(& mut answer)?;
+
+    match answer.trim() {
+        "r" => {
+            rollback(journal, backend, target_root)?;
+            Ok(false)
+        }
+        //Nothing in the journal says how far the script got, so the only safe way on is to start over
+        "s" => {
+            rollback(journal, backend, target_root)?;
+            Ok(true)
+        }
+        _ => {Ok(false)}
+    }
+}
+
+///Run an installer in plan mode, recording what it would do without changing anything
+pub fn plan<P: AsRef<Path>>(installer: P, backend: & dyn RegistryBackend, target_root: Option<&Path>) -> Result<Plan> {
+
+    let read = OakRead::new(installer)?;
+
+    let code = read.commands()?;
+
+    let temp = TempDir::new()?;
+
+    let inverses = Inverse::new();
+
+    let plan = Plan::new();
+
+    crate::mlc::run(code.as_str(), &read, None, Some(&inverses), &temp, Some(&plan), &DiskFileSystem, backend, target_root)?;
+
+    Ok(plan)
+}
+
+///Run an installer against an empty file system held in memory, returning the files and folders it would leave behind
+pub fn dry_run<P: AsRef<Path>>(installer: P, backend: & dyn RegistryBackend) -> Result<Vec<PathBuf>> {
+
+    let read = OakRead::new(installer)?;
+
+    let code = read.commands()?;
+
+    let temp = TempDir::new()?;
+
+    let fs = MemoryFileSystem::new();
+
+    //Scripts stage files under the temporary folder, so it has to exist in memory too
+    fs.create_dir_all(temp.path())?;
+
+    crate::mlc::run(code.as_str(), &read, None, None, &temp, None, &fs, backend, None)?;
+
+    Ok(fs.paths().into_iter().filter(|path| !path.starts_with(temp.path()) && !temp.path().starts_with(path)).collect())
+}
+
+///List the info, and all the files, folders and commands in an oak repo
+pub fn list(read: &OakRead) -> Result<()> {
+
+    let info = read.info()?;
+
+    println!("Info:");
+    println!("{}", serde_json::to_string_pretty(&info)?);
+
+    println!("Capabilities:");
+    print!("{}", info.capabilities);
+
+    if !read.modules().is_empty() {
+        println!("Modules:");
+
+        for module in read.modules().keys() {
+            println!("    {}", module);
         }
     }
 
-    let read = OakRead::new(repo.as_ref()).unwrap();
+    if !read.downloads().is_empty() {
+        println!("Prefetched downloads:");
 
-    println!("Commands:");
+        for (url, name) in read.downloads() {
+            println!("    {} ({})", url, name);
+        }
+    }
 
-    println!("{}", read.commands().unwrap());
+    if !read.payload().is_empty() {
+        println!("Payload:");
+        println!("{}", serde_json::to_string_pretty(read.payload())?);
+    }
+
+    println!("Stored files:");
+
+    for name in read.entries() {
+        match (read.index().get(&name), read.manifest().get(&name)) {
+            (Some(blob), Some(entry)) => {println!("    {} ({}, {} bytes)", name, blob, entry.size)}
+            (Some(blob), None) => {println!("    {} ({})", name, blob)}
+            (None, _) => {println!("    {}", name)}
+        }
+    }
+
+    match info.oak_type {
+        OakType::Installer => {
+            println!("Commands:");
+
+            println!("{}", read.commands().unwrap());
+        }
+        OakType::Uninstaller => {
+            println!("Inverses:");
+
+            for op in read.inverses()? {
+                println!("    {}", op);
+            }
+        }
+    }
 
 
     Ok(())
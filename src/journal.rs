@@ -0,0 +1,158 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use serde::{Serialize, Deserialize};
+use crate::error::Result;
+use crate::filesystem::FileSystem;
+use crate::oak::Payload;
+use crate::path_type::{reroot, InverseOp};
+
+///A single line of the journal log, recording an inverse and where it was inserted into the list of inverses
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    index: usize,
+    op: InverseOp,
+}
+
+///An on-disk log of every inverse recorded during an installation.
+///
+///Each inverse (and any backup it restores from) is flushed to disk before the step it undoes runs,
+///so if the installer is killed part way through, the next run can still roll back the changes made so far.
+pub struct Journal {
+    directory: PathBuf,
+    log: Mutex<File>,
+    ///The root that the installation is being made under, if any. Inverses record logical paths, so backups are taken from under it
+    target_root: Option<PathBuf>,
+}
+
+impl Journal {
+
+    ///Get the location of the journal used while creating the uninstaller at `uninstaller`
+    pub fn location<P: AsRef<Path>>(uninstaller: P) -> PathBuf {
+        let mut path = uninstaller.as_ref().as_os_str().to_os_string();
+        path.push(".journal");
+        PathBuf::from(path)
+    }
+
+    ///Create a new, empty journal in `directory` for an installation made under `target_root`
+    pub fn create<P: AsRef<Path>>(directory: P, target_root: Option<&Path>) -> Result<Self> {
+        let directory = directory.as_ref().to_path_buf();
+
+        std::fs::create_dir(&directory)?;
+        std::fs::create_dir(directory.join("payload"))?;
+
+        let log = OpenOptions::new().create_new(true).append(true).open(directory.join("log"))?;
+
+        Ok(Self {
+            directory,
+            log: Mutex::new(log),
+            target_root: target_root.map(Path::to_path_buf),
+        })
+    }
+
+    ///Open an existing journal left behind by an interrupted installation
+    pub fn open<P: AsRef<Path>>(directory: P) -> Result<Self> {
+        let directory = directory.as_ref().to_path_buf();
+
+        let log = OpenOptions::new().append(true).open(directory.join("log"))?;
+
+        Ok(Self {
+            directory,
+            log: Mutex::new(log),
+            target_root: None,
+        })
+    }
+
+    ///Append an inverse to the journal. If the inverse restores a backup, the backup is copied into the journal first
+    pub fn record(&self, index: usize, op: &InverseOp) -> Result<()> {
+
+        //Backups are always taken from the location that they restore to, before that location is changed
+        if let InverseOp::Data { name, destination } = op {
+            let backup = self.directory.join("payload").join(name);
+
+            let destination = reroot(destination, self.target_root.as_deref());
+
+            if destination.is_dir() {
+                let options = fs_extra::dir::CopyOptions { content_only: true, ..Default::default() };
+
+                fs_extra::dir::copy(&destination, &backup, &options)?;
+            } else {
+                std::fs::copy(&destination, &backup)?;
+            }
+
+            sync_all(&backup)?;
+        }
+
+        let mut line = serde_json::to_string(&Entry { index, op: op.clone() })?;
+        line.push('\n');
+
+        let mut guard = self.log.lock().unwrap();
+
+        guard.write_all(line.as_bytes())?;
+        guard.sync_all()?;
+
+        Ok(())
+    }
+
+    ///Rebuild the list of inverses from the journal, in the order they should be executed
+    pub fn ops(&self) -> Result<Vec<InverseOp>> {
+        let reader = BufReader::new(File::open(self.directory.join("log"))?);
+
+        let mut ops = Vec::new();
+
+        for line in reader.lines() {
+            //A torn final line means we were interrupted while writing it, and the step it undoes never ran
+            let entry: Entry = match line.ok().and_then(|line| serde_json::from_str(line.as_str()).ok()) {
+                Some(entry) => {entry}
+                None => {break}
+            };
+
+            ops.insert(entry.index, entry.op);
+        }
+
+        Ok(ops)
+    }
+
+    ///Delete the journal and all the backups it holds
+    pub fn remove(self) -> Result<()> {
+        drop(self.log);
+        std::fs::remove_dir_all(&self.directory)?;
+        Ok(())
+    }
+}
+
+impl Payload for Journal {
+    fn extract(&self, fs: & dyn FileSystem, name: &str, destination: &Path) -> Result<()> {
+        restore(fs, self.directory.join("payload").join(name).as_path(), destination)
+    }
+}
+
+///Copy a backup file or folder from the journal into `fs`
+fn restore(fs: & dyn FileSystem, backup: &Path, destination: &Path) -> Result<()> {
+    if backup.is_dir() {
+        fs.create_dir(destination)?;
+
+        for entry in std::fs::read_dir(backup)? {
+            let path = entry?.path();
+            restore(fs, path.as_path(), destination.join(path.file_name().unwrap()).as_path())?;
+        }
+    } else {
+        fs.write(destination, & mut File::open(backup)?)?;
+    }
+
+    Ok(())
+}
+
+///Flush a file, or every file in a folder, to disk
+fn sync_all(path: &Path) -> Result<()> {
+    if path.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            sync_all(entry?.path().as_path())?;
+        }
+    } else {
+        File::open(path)?.sync_all()?;
+    }
+
+    Ok(())
+}
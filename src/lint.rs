@@ -0,0 +1,202 @@
+use crate::error::{Error, Result};
+use crate::preprocess::{location, tokenize, Token};
+use crate::mlc::sandbox::{self, Capability};
+use crate::capabilities::{is_under, Capabilities};
+
+///Functions that run code which isn't checked when the installer is built
+const CODE_LOADERS: [&str; 4] = ["load", "loadstring", "dofile", "loadfile"];
+
+///The arguments each function writes to, counting from 0, which the runtime checks against the write roots. Functions are
+///found by name after `__`, `oak.lib.`, `oak.` or `os.`
+const WRITES: [(&str, &[usize]); 17] = [
+    ("delete", &[0]),
+    ("remove", &[0]),
+    ("move", &[0, 1]),
+    ("rename", &[0, 1]),
+    ("data", &[1]),
+    ("mkdir", &[0]),
+    ("copy", &[1]),
+    ("zip", &[0]),
+    ("unzip", &[1]),
+    ("download", &[1]),
+    ("edit", &[0]),
+    ("create_symlink", &[1]),
+    ("set_attributes", &[0]),
+    ("ensure_dir_all", &[0]),
+    ("install_dir_tree", &[1]),
+    ("replace_file_with_backup", &[1, 2]),
+    ("write_config_if_absent", &[0]),
+];
+
+///Check a script for mistakes that would leave its uninstaller incomplete, before it is packaged:
+///
+///- syntax errors
+///- calls that change the machine without recording inverses, such as `io.popen`, `os.exit` and `debug.*`, which the
+///  sandbox would remove anyway
+///- `load` and friends, which run code that can't be checked
+///- `pathtype.absolute` paths passed straight to a function that writes to them, which are outside the write roots, if
+///  `capabilities` declares them
+///
+///Every problem is reported, each with `name` and its line and column
+pub fn lint(source: &str, name: &str, capabilities: &Capabilities) -> Result<()> {
+    let mut findings = Vec::new();
+
+    if let Some(message) = syntax_error(source, name) {
+        findings.push(message);
+    }
+
+    let tokens = tokenize(source);
+
+    for (i, (offset, token)) in tokens.iter().enumerate() {
+        let first = match token {
+            Token::Name(first) => {first.as_str()}
+            _ => {continue}
+        };
+
+        //Fields and methods, such as `file:lines` or `t.load`, are someone else's
+        if i > 0 && matches!(tokens[i - 1].1, Token::Symbol('.') | Token::Symbol(':')) && !(i > 1 && tokens[i - 2].1 == Token::Symbol('.')) {
+            continue;
+        }
+
+        let field = match (tokens.get(i + 1), tokens.get(i + 2)) {
+            (Some((_, Token::Symbol('.'))), Some((_, Token::Name(field)))) => {Some(field.as_str())}
+            _ => {None}
+        };
+
+        let problem = match (first, field) {
+            ("debug", _) => {Some("`debug` can change the running installer, and is not available".to_string())}
+            ("os" | "io", None) => {Some(format!("`{}` can only be used to call its functions by name, so they can be checked", first))}
+            ("os", Some(function)) if !sandbox::allowed(format!("os.{}", function).as_str()) => {
+                Some(format!("`os.{}` changes the machine without being undone by the uninstaller", function))
+            }
+            ("io", Some(function)) if !sandbox::allowed(format!("io.{}", function).as_str()) => {
+                Some(format!("`io.{}` uses files or programs without going through `io.open`, so it isn't undone by the uninstaller", function))
+            }
+            (loader, _) if CODE_LOADERS.contains(&loader) => {
+                Some(format!("`{}` runs code that can't be checked when the installer is built", loader))
+            }
+            (name, _) if sandbox::capability(name) == Some(Capability::Forbidden) => {
+                Some(format!("`{}` is not available to installers", name))
+            }
+            ("pathtype", Some("absolute")) if capabilities.write_roots.is_some() && writes(&tokens, i) => {
+                let write_roots = capabilities.write_roots.as_ref().unwrap();
+
+                //Only literal paths can be checked here
+                let path = match (tokens.get(i + 3), tokens.get(i + 4)) {
+                    (Some((_, Token::Symbol('('))), Some((_, Token::String(Some(path))))) => {Some(path)}
+                    (Some((_, Token::String(Some(path)))), _) => {Some(path)}
+                    _ => {None}
+                };
+
+                path.filter(|path| !write_roots.iter().any(|root| is_under(path, root.to_string_lossy().as_ref())))
+                    .map(|path| format!("{:?} is outside the declared write roots", path))
+            }
+            _ => {None}
+        };
+
+        if let Some(problem) = problem {
+            let (line, column) = location(source, *offset);
+            findings.push(format!("{}:{}:{}: {}", name, line, column, problem));
+        }
+    }
+
+    if findings.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Lint(findings.join("\n")))
+    }
+}
+
+///Is the token at `i` in an argument that the call it is directly inside writes to. `io.open` only writes if its mode is
+///a literal other than `"r"` or `"rb"`
+fn writes(tokens: &[(usize, Token)], i: usize) -> bool {
+    let (callee, open, argument) = match enclosing_call(tokens, i) {
+        Some(call) => {call}
+        None => {return false}
+    };
+
+    if callee == "io.open" {
+        return argument == 0 && matches!(argument_start(tokens, open, 1), Some(Token::String(Some(mode))) if mode != "r" && mode != "rb");
+    }
+
+    let name = ["__", "oak.lib.", "oak.", "os."].iter().find_map(|prefix| callee.strip_prefix(prefix));
+
+    WRITES.iter().any(|(function, arguments)| name == Some(*function) && arguments.contains(&argument))
+}
+
+///The function called by the call whose arguments hold the token at `i`, along with the index of its `(` and which
+///argument holds the token, counting from 0
+fn enclosing_call(tokens: &[(usize, Token)], i: usize) -> Option<(String, usize, usize)> {
+    let mut depth = 0;
+    let mut argument = 0;
+
+    for open in (0..i).rev() {
+        match tokens[open].1 {
+            Token::Symbol(')' | ']' | '}') => {depth += 1}
+            Token::Symbol('(') if depth == 0 => {return callee(tokens, open).map(|callee| (callee, open, argument))}
+            Token::Symbol('[' | '{') if depth == 0 => {return None}
+            Token::Symbol('(' | '[' | '{') => {depth -= 1}
+            Token::Symbol(',') if depth == 0 => {argument += 1}
+            _ => {}
+        }
+    }
+
+    None
+}
+
+///The name, such as `oak.copy`, before the `(` at `open`
+fn callee(tokens: &[(usize, Token)], open: usize) -> Option<String> {
+    let mut j = open.checked_sub(1)?;
+
+    let mut name = match &tokens[j].1 {
+        Token::Name(name) => {name.clone()}
+        _ => {return None}
+    };
+
+    while j >= 2 {
+        match (&tokens[j - 1].1, &tokens[j - 2].1) {
+            (Token::Symbol(separator @ ('.' | ':')), Token::Name(part)) => {name = format!("{}{}{}", part, separator, name)}
+            _ => {break}
+        }
+
+        j -= 2;
+    }
+
+    Some(name)
+}
+
+///The first token of argument `n` of the call whose `(` is at `open`
+fn argument_start(tokens: &[(usize, Token)], open: usize, n: usize) -> Option<&Token> {
+    let mut depth = 0;
+    let mut argument = 0;
+
+    for (j, (_, token)) in tokens.iter().enumerate().skip(open + 1) {
+        if depth == 0 && argument == n && (j == open + 1 || tokens[j - 1].1 == Token::Symbol(',')) {
+            return Some(token);
+        }
+
+        match token {
+            Token::Symbol('(' | '[' | '{') => {depth += 1}
+            Token::Symbol(')' | ']' | '}') if depth == 0 => {return None}
+            Token::Symbol(')' | ']' | '}') => {depth -= 1}
+            Token::Symbol(',') if depth == 0 => {argument += 1}
+            _ => {}
+        }
+    }
+
+    None
+}
+
+///Compile the script without running it, giving the syntax error if there is one
+fn syntax_error(source: &str, name: &str) -> Option<String> {
+    let lua = rlua::Lua::new();
+
+    lua.context(|ctx| {
+        //A name starting with `=` is used as is in messages
+        match ctx.load(source).set_name(format!("={}", name).as_str()).and_then(|chunk| chunk.into_function()) {
+            Ok(_) => {None}
+            Err(rlua::Error::SyntaxError { message, .. }) => {Some(message)}
+            Err(e) => {Some(format!("{}: {}", name, e))}
+        }
+    })
+}
@@ -5,15 +5,19 @@ extern crate fs_extra;
 extern crate zip_extensions;
 extern crate clap;
 extern crate tempfile;
+#[cfg(windows)]
 extern crate registry;
 extern crate core;
 
-use std::path::{PathBuf};
-use clap::Arg;
+use std::path::{Path, PathBuf};
+use clap::{Arg, ArgMatches};
 use tempfile::TempDir;
 use oak::{OakRead, OakWrite};
 use crate::oak::{Info};
 use crate::source::Source;
+use crate::registry_backend::{EmulatedRegistry, RegistryBackend};
+#[cfg(windows)]
+use crate::registry_backend::WindowsRegistry;
 
 mod error;
 mod oak;
@@ -24,54 +28,301 @@ mod mlc;
 mod path_type;
 mod source;
 mod exe_extender;
+mod journal;
+mod plan;
+mod filesystem;
+mod registry_backend;
+mod signing;
+mod preprocess;
+mod lint;
+mod capabilities;
 
 
+///Exit code for success
+const SUCCESS: i32 = 0;
+
+///Exit code for an installer, uninstaller or check that failed
+const FAILURE: i32 = 1;
+
+///Exit code for bad arguments, which is also what clap uses
+const USAGE: i32 = 2;
+
 fn main() {
 
     let m = clap::Command::new(clap::crate_name!())
         .author(clap::crate_authors!())
         .version(clap::crate_version!())
         .about(clap::crate_description!())
-        .arg(Arg::new("source file")
-            .short('s')
-            .long("source")
-            .value_name("Source path")
+        .arg(Arg::new("registry")
+            .long("registry")
+            .value_name("Registry file")
+            .global(true)
+            .help("Use an emulated registry stored in this file instead of the Windows registry")
+        )
+        .arg(Arg::new("root")
+            .long("root")
+            .value_name("Root folder")
+            .global(true)
+            .help("Install everything under this folder instead of the real locations")
+        )
+        .subcommand(clap::Command::new("build")
+            .about("Create an installer from a source file")
+            .arg(Arg::new("source file")
+                .short('s')
+                .long("source")
+                .value_name("Source path")
+                .required(true)
+            )
+            .arg(Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_name("Installer path")
+                .default_value("install.exe")
+            )
+            .arg(Arg::new("stub")
+                .long("stub")
+                .value_name("Stub path")
+                .help("The project_oak executable to append the archive to, instead of this one")
+            )
+            .arg(Arg::new("sign")
+                .long("sign")
+                .value_name("Key file")
+                .help("Sign the installer with this ed25519 private key")
+            )
+            .arg(Arg::new("offline")
+                .long("offline")
+                .help("Fetch the urls the script downloads now, and store them in the installer")
+            )
+            .arg(Arg::new("plan")
+                .long("plan")
+                .help("Print what the installer would do instead of creating it")
+            )
+            .arg(Arg::new("json")
+                .long("json")
+                .requires("plan")
+                .help("Print the plan as JSON")
+            )
+            .arg(Arg::new("dry run")
+                .long("dry-run")
+                .conflicts_with("plan")
+                .help("Run the installer against an empty file system in memory, and print what it would leave behind")
+            )
+        )
+        .subcommand(clap::Command::new("run")
+            .about("Run a bare installer archive")
+            .arg(Arg::new("archive").value_name("Archive path").required(true))
+            .arg(Arg::new("uninstaller")
+                .short('u')
+                .long("uninstaller")
+                .value_name("Uninstaller path")
+                .required(true)
+                .help("Where to write the uninstaller archive")
+            )
+        )
+        .subcommand(clap::Command::new("uninstall")
+            .about("Run a bare uninstaller archive")
+            .arg(Arg::new("archive").value_name("Archive path").required(true))
+        )
+        .subcommand(clap::Command::new("inspect")
+            .about("List the info, files and commands or inverses of an archive or installer")
+            .arg(Arg::new("archive").value_name("Archive path").required(true))
+        )
+        .subcommand(clap::Command::new("extract")
+            .about("Extract the files and folders stored in an archive or installer")
+            .arg(Arg::new("archive").value_name("Archive path").required(true))
+            .arg(Arg::new("destination").value_name("Destination folder").required(true))
+        )
+        .subcommand(clap::Command::new("verify")
+            .about("Check the integrity, and optionally the signature, of an archive or installer")
+            .arg(Arg::new("archive").value_name("Archive path").required(true))
+            .arg(Arg::new("public key")
+                .long("public-key")
+                .value_name("Public key")
+                .help("Check that the installer was signed with this key, as printed by new-key")
+            )
+        )
+        .subcommand(clap::Command::new("new-key")
+            .about("Save a new ed25519 private key to a file, and print the public key to pin with OAK_PUBLIC_KEY")
+            .arg(Arg::new("key file").value_name("Key file").required(true))
         ).get_matches();
 
-        let (offset, length) = exe_extender::get_meta();
+        let target_root = m.value_of("root").map(PathBuf::from);
 
-        if offset == 0 {
-            //If the offset is zero, the exe contains no archive. This means it can only be used in 'create_installer' mode
+        let emulated = match m.value_of("registry").map(EmulatedRegistry::open).transpose() {
+            Ok(emulated) => {emulated}
+            Err(e) => {
+                println!("Couldn't open the registry file: {}", e);
+                std::process::exit(USAGE);
+            }
+        };
 
-            let source =  m.value_of("source file").unwrap();
+        #[cfg(windows)]
+        let default: & dyn RegistryBackend = &WindowsRegistry;
 
-            let tmp = TempDir::new().unwrap();
+        //Elsewhere there is no registry to fall back to, so use one held in memory
+        #[cfg(not(windows))]
+        let memory = EmulatedRegistry::new();
+        #[cfg(not(windows))]
+        let default: & dyn RegistryBackend = &memory;
 
-            let tmp_file = tmp.path().join("install");
+        let backend: & dyn RegistryBackend = match &emulated {
+            Some(registry) => {registry}
+            None => {default}
+        };
 
-            let complete = Source::load_from_path(PathBuf::from(source).as_path());
+        let code = match m.subcommand() {
+            Some(("build", sub)) => {exit_code(build(sub, backend, target_root.as_deref()))}
+            Some(("run", sub)) => {
+                match OakRead::new(sub.value_of("archive").unwrap()) {
+                    Ok(read) => {failure_code(hlc::install(read, sub.value_of("uninstaller").unwrap(), backend, target_root.as_deref()))}
+                    Err(e) => {exit_code::<()>(Err(e))}
+                }
+            }
+            Some(("uninstall", sub)) => {
+                match OakRead::new(sub.value_of("archive").unwrap()) {
+                    Ok(read) => {failure_code(hlc::uninstall(read, backend, target_root.as_deref()))}
+                    Err(e) => {exit_code::<()>(Err(e))}
+                }
+            }
+            Some(("inspect", sub)) => {
+                exit_code(open(sub).and_then(|read| hlc::list(&read)))
+            }
+            Some(("extract", sub)) => {
+                exit_code(open(sub).and_then(|read| hlc::extract(&read, Path::new(sub.value_of("destination").unwrap()))))
+            }
+            Some(("verify", sub)) => {
+                let path = Path::new(sub.value_of("archive").unwrap());
 
-            complete.create_installer(tmp_file.as_path());
+                let result = exe_extender::open_archive(path).and_then(|(archive, signature)| {
+                    let key = sub.value_of("public key").map(signing::public_key).transpose()?;
 
-            exe_extender::extend_exe(tmp_file.as_path(), PathBuf::from(".\\install.exe").as_path(), length);
+                    hlc::verify(archive, signature.as_ref(), key.as_ref(), path)
+                });
 
+                if result.is_ok() {
+                    println!("The archive is intact");
+                }
 
+                exit_code(result)
+            }
+            Some(("new-key", sub)) => {
+                exit_code(signing::generate_key(Path::new(sub.value_of("key file").unwrap())).map(|public| {
+                    println!("{}", signing::to_hex(public.as_bytes()));
+                }))
+            }
+            _ => {run_embedded(backend, target_root.as_deref())}
+        };
 
-        } else {
-            //If the offset is non-zero, we have an archive appended. We use this offset to obtain the archive and run it
+        std::process::exit(code);
+}
+
+///Run the archive appended to this exe, if there is one
+fn run_embedded(backend: & dyn RegistryBackend, target_root: Option<&Path>) -> i32 {
+
+    let trailer = match exe_extender::current_trailer() {
+        Ok(trailer) => {trailer}
+        Err(e) => {
+            println!("This installer is damaged and can't be run: {}", e);
+            return FAILURE;
+        }
+    };
+
+    match trailer {
+        Some(trailer) => {
+            //The exe has an archive appended, so run it in place
+
+            let (archive, sha256) = match exe_extender::get_archive(std::env::current_exe().unwrap().as_path(), &trailer) {
+                Ok(archive) => {archive}
+                Err(e) => {
+                    println!("This installer is damaged and can't be run: {}", e);
+                    return FAILURE;
+                }
+            };
+
+            failure_code(hlc::execute(archive, &sha256, trailer.signature.as_ref(), backend, target_root))
+        }
+        None => {
+            //A truncated installer loses its trailer, and looks like the plain builder
+            println!("No installer is attached to this exe, and no command was given (see --help). If this is an installer, it has been truncated");
+            USAGE
+        }
+    }
+}
 
-            let tmp = TempDir::new().unwrap();
+///Create an installer, or print its plan or what a dry run of it leaves behind
+fn build(m: &ArgMatches, backend: & dyn RegistryBackend, target_root: Option<&Path>) -> error::Result<()> {
 
-            let tmp_file = tmp.path().join("archive");
+    let tmp = TempDir::new()?;
 
-            exe_extender::get_archive(tmp_file.as_path(), length, offset);
+    let tmp_file = tmp.path().join("install");
 
-            hlc::execute(tmp_file.as_path());
+    let mut complete = Source::load_from_path(PathBuf::from(m.value_of("source file").unwrap()).as_path())?;
 
+    //Either the source or the command line can ask for an offline installer
+    complete.offline |= m.is_present("offline");
+
+    let key = m.value_of("sign").map(|key_file| signing::load_key(Path::new(key_file))).transpose()?;
+
+    let fetched = complete.create_installer(tmp_file.as_path())?;
+
+    //Keep a JSON plan the only thing written to stdout
+    if !m.is_present("json") {
+        for url in fetched {
+            println!("Fetched {}", url);
+        }
+    }
+
+    if m.is_present("plan") {
+        let plan = hlc::plan(tmp_file.as_path(), backend, target_root)?;
+
+        if m.is_present("json") {
+            println!("{}", plan.to_json()?);
+        } else {
+            print!("{}", plan);
         }
+    } else if m.is_present("dry run") {
+        for path in hlc::dry_run(tmp_file.as_path(), backend)? {
+            println!("{}", path.display());
+        }
+    } else {
+        let signature = match key {
+            Some(key) => {Some(signing::sign(std::fs::File::open(tmp_file.as_path())?, &key)?)}
+            None => {None}
+        };
+
+        let stub = match m.value_of("stub") {
+            Some(stub) => {PathBuf::from(stub)}
+            None => {std::env::current_exe()?}
+        };
 
+        exe_extender::extend_exe(stub.as_path(), tmp_file.as_path(), Path::new(m.value_of("output").unwrap()), signature.as_ref())?;
+    }
 
+    Ok(())
+}
 
+///Open the archive named by the `archive` argument, which can be a bare archive or an installer
+fn open(m: &ArgMatches) -> error::Result<OakRead> {
+    let (archive, _) = exe_extender::open_archive(Path::new(m.value_of("archive").unwrap()))?;
 
+    OakRead::open(archive)
+}
+
+fn exit_code<T>(result: error::Result<T>) -> i32 {
+    match result {
+        Ok(_) => {SUCCESS}
+        Err(e) => {
+            println!("Error: {}", e);
+            FAILURE
+        }
+    }
+}
 
+///The installer functions return true if they failed
+fn failure_code(failed: bool) -> i32 {
+    if failed {
+        FAILURE
+    } else {
+        SUCCESS
+    }
 }
@@ -14,24 +14,28 @@ Misc
 use std::collections::HashMap;
 use std::path::{Path};
 use std::time::SystemTime;
-use registry::Security;
 use rlua::{Context, Result, Value};
 use crate::mlc::registry_ex::{RootKey};
+use crate::filesystem::FileSystem;
+use crate::registry_backend::RegistryBackend;
 
-pub fn directory_contents(path: &Path) -> Result<HashMap<String, Vec<String>>> {
+///List the contents of the folder `path`, which is found at `rooted` on disk. The paths returned are under `path` rather than `rooted`
+pub fn directory_contents(fs: & dyn FileSystem, path: &Path, rooted: &Path) -> Result<HashMap<String, Vec<String>>> {
     let mut map = HashMap::new();
 
     let mut folders = Vec::new();
     let mut files = Vec::new();
     let mut other = Vec::new();
 
-    for path in std::fs::read_dir(path).unwrap().map(|x| x.unwrap().path()) {
-        if path.is_file() {
-            files.push(path.to_str().unwrap().to_string());
-        } else if path.is_dir() {
-            folders.push(path.to_str().unwrap().to_string());
+    for rooted in fs.read_dir(rooted)? {
+        let logical = path.join(rooted.file_name().unwrap());
+
+        if fs.is_file(&rooted) {
+            files.push(logical.to_str().unwrap().to_string());
+        } else if fs.is_dir(&rooted) {
+            folders.push(logical.to_str().unwrap().to_string());
         } else {
-            other.push(path.to_str().unwrap().to_string());
+            other.push(logical.to_str().unwrap().to_string());
         }
     }
 
@@ -42,10 +46,10 @@ pub fn directory_contents(path: &Path) -> Result<HashMap<String, Vec<String>>> {
     Ok(map)
 }
 
-pub fn file_type(path: &Path) -> Result<String> {
-    Ok(String::from(if path.is_file() {
+pub fn file_type(fs: & dyn FileSystem, path: &Path) -> Result<String> {
+    Ok(String::from(if fs.is_file(path) {
         "file"
-    } else if path.is_dir() {
+    } else if fs.is_dir(path) {
         "directory"
     } else if path.is_symlink() {
         "symlink"
@@ -54,8 +58,8 @@ pub fn file_type(path: &Path) -> Result<String> {
     }))
 }
 
-pub fn exists(path: &Path) -> Result<bool> {
-    Ok(path.exists())
+pub fn exists(fs: & dyn FileSystem, path: &Path) -> Result<bool> {
+    Ok(fs.exists(path))
 }
 
 ///Take a system time and convert it into a hashmap lua style
@@ -79,28 +83,12 @@ pub fn file_timestamps(path: &Path) -> Result<HashMap<String, Option<u64>>> {
     Ok(stamps)
 }
 
-pub fn get_registry_data<'l>(c: Context<'l>, root: &RootKey, key: String) -> Result<rlua::Table<'l>> {
-
-    let reg = registry::Hive::from(root).open(key, Security::Read).unwrap();
+pub fn get_registry_data<'l>(c: Context<'l>, backend: & dyn RegistryBackend, root: &RootKey, key: String) -> Result<rlua::Table<'l>> {
 
     let table = c.create_table().unwrap();
 
-    let mut subkeys = vec![];
-    let mut kv_pairs = HashMap::new();
-
-
-    for key in reg.keys() {
-
-        if let Ok(r) = key {
-            subkeys.push(r.to_string());
-        }
-    }
-
-    for value in reg.values() {
-        if let Ok(v) = value {
-            kv_pairs.insert(v.name().to_string_lossy(), crate::mlc::registry_ex::Data::from(v.data().clone()));
-        }
-    }
+    let subkeys = backend.subkeys(root, key.as_str())?;
+    let kv_pairs: HashMap<_, _> = backend.values(root, key.as_str())?.into_iter().collect();
 
     table.set(Value::String(c.create_string("subkeys")?), c.create_sequence_from(subkeys)?)?;
     table.set(Value::String(c.create_string("kv_pairs")?), c.create_table_from(kv_pairs)?)?;
@@ -109,6 +97,16 @@ pub fn get_registry_data<'l>(c: Context<'l>, root: &RootKey, key: String) -> Res
     Ok(table)
 }
 
+///The Win32 error code given for file attributes on other platforms
+#[cfg(not(windows))]
+pub const ERROR_NOT_SUPPORTED: u32 = 50;
+
+#[cfg(not(windows))]
+pub fn get_attributes(_path: &Path) -> std::result::Result<u32, u32> {
+    Err(ERROR_NOT_SUPPORTED)
+}
+
+#[cfg(windows)]
 pub fn get_attributes(path: &Path) -> std::result::Result<u32, u32> {
     use winapi::um::fileapi::GetFileAttributesA;
     use winapi::um::errhandlingapi::GetLastError;
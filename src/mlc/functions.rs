@@ -1,17 +1,24 @@
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::str::from_utf8_unchecked;
-use registry::Security;
+use std::time::Duration;
+use reqwest::header::{CONTENT_RANGE, RANGE};
+use reqwest::StatusCode;
+use sha2::{Digest, Sha256};
 use tempfile::TempDir;
-use crate::{error, OakRead, OakWrite};
+use crate::{error, OakWrite};
+use crate::oak::Payload;
 use crate::mlc::registry_ex::{Data, RootKey};
-use crate::path_type::{Inverse, PathType};
+use crate::path_type::{reroot, Inverse, InverseOp, PathType};
+use crate::plan::{Action, Plan};
 use crate::error::{Error, Result};
+use crate::filesystem::FileSystem;
+use crate::registry_backend::RegistryBackend;
 
-pub fn data(installer: & OakRead, inverses: Option<& Inverse>, name: & str, destination: &PathType, temp: & TempDir) -> Result<()>  {
+#[allow(clippy::too_many_arguments)]
+pub fn data(installer: & dyn Payload, inverses: Option<& Inverse>, plan: Option<& Plan>, fs: & dyn FileSystem, name: & str, destination: &PathType, temp: & TempDir, target_root: Option<& Path>) -> Result<()>  {
 
-    let destination_path = destination.to_absolute_path(temp);
-
-    installer.extract(name, &destination_path)?;
+    let destination_path = destination.to_absolute_path(temp, None);
 
     if !destination.is_temp() {
         if let Some(list) = inverses {
@@ -19,118 +26,130 @@ pub fn data(installer: & OakRead, inverses: Option<& Inverse>, name: & str, dest
             //list.insert(1, (String::from("delete"), vec![]));
 
 
-            list.insert(0, format!("__delete(pathtype.absolute({:?}))", destination_path));
+            list.insert(0, InverseOp::Delete { path: destination_path.clone() })?;
 
         }
     }
 
-    Ok(())
-}
+    if let Some(plan) = plan {
+        plan.record(Action::Data { name: name.to_string(), destination: destination_path.clone() }, inverses);
 
-pub fn _move(inverses: Option<& Inverse>, source: & PathType, destination: & PathType, temp: & TempDir) -> Result<()> {
+        if !destination.is_temp() {
+            return Ok(());
+        }
+    }
 
-    let d = destination;
+    installer.extract(fs, name, &destination.to_absolute_path(temp, target_root))?;
 
-    let destination = destination.to_absolute_path(temp);
+    Ok(())
+}
 
-    let source_path = source.to_absolute_path(temp);
+pub fn _move(inverses: Option<& Inverse>, plan: Option<& Plan>, fs: & dyn FileSystem, source: & PathType, destination: & PathType, temp: & TempDir, target_root: Option<& Path>) -> Result<()> {
 
-    if destination.exists() {
-        return Err(Error::AlreadyExists)
-    } else {
-        if source_path.is_dir() {
-            let mut options = fs_extra::dir::CopyOptions::default();
-            options.content_only = true;
+    let d = destination;
 
-            fs_extra::dir::move_dir(&source_path, &destination, &options)?;
+    let destination = destination.to_absolute_path(temp, None);
 
-        } else if source_path.is_file() {
-            let options = fs_extra::file::CopyOptions::default();
+    let source_path = source.to_absolute_path(temp, None);
 
-            fs_extra::file::move_file(&source_path, &destination, &options)?;
+    let destination_rooted = d.to_absolute_path(temp, target_root);
 
-        } else {
-            return Err(Error::DoesntExist);
-        }
+    let source_rooted = source.to_absolute_path(temp, target_root);
 
+    if fs.exists(&destination_rooted) {
+        return Err(Error::AlreadyExists)
+    } else if !fs.is_dir(&source_rooted) && !fs.is_file(&source_rooted) {
+        return Err(Error::DoesntExist);
+    } else {
         if !d.is_temp() {
             if let Some(list) = inverses {
 
 
                 if source.is_temp() {
 
-                    list.insert(0, format!("__delete(pathtype.absolute({:?}))", destination));
+                    list.insert(0, InverseOp::Delete { path: destination.clone() })?;
                 } else {
 
-                    let abs = source.to_absolute_path(temp);
+                    let abs = source.to_absolute_path(temp, None);
 
 
-                    list.insert(0, format!("__move(pathtype.absolute({:?}), pathtype.absolute({:?}))", destination, abs));
+                    list.insert(0, InverseOp::Move { source: destination.clone(), destination: abs })?;
                 }
 
             }
         }
+
+        if let Some(plan) = plan {
+            plan.record(Action::Move { source: source_path.clone(), destination: destination.clone() }, inverses);
+
+            if !source.is_temp() || !d.is_temp() {
+                return Ok(());
+            }
+        }
+
+        fs.rename(&source_rooted, &destination_rooted)?;
     }
     Ok(())
 }
 
-pub fn delete(mut uninstaller: Option<& OakWrite>, inverses: Option<&Inverse>, path: & PathType, temp: & TempDir) -> Result<()> {
+pub fn delete(mut uninstaller: Option<& OakWrite>, inverses: Option<&Inverse>, plan: Option<& Plan>, fs: & dyn FileSystem, p: & PathType, temp: & TempDir, target_root: Option<& Path>) -> Result<()> {
 
-    let path = path.to_absolute_path(temp);
+    let path = p.to_absolute_path(temp, None);
 
-    let name = if path.exists() {
+    let path_rooted = p.to_absolute_path(temp, target_root);
+
+    let name = if fs.exists(&path_rooted) {
         /*match uninstaller.as_mut() {
             None => { None }
             Some(archive) => { Some(archive.archive(&path)) }
         };*/
 
-        uninstaller.as_mut().map(|archive| archive.archive(&path))
+        uninstaller.as_mut().map(|archive| archive.archive(fs, &path_rooted)).transpose()?
     } else {
         return Err(Error::DoesntExist);
     };
 
-    if path.is_dir() {
-        std::fs::remove_dir_all(&path)?;
-    } else if path.is_file() || path.is_symlink() {
-        std::fs::remove_file(&path)?;
-    } else {
-        panic!("Path is not a directory or file")
-    };
+    //Temporary paths are gone by the time the inverses run, so there is nothing to restore
+    if !p.is_temp() {
+        if let Some(list) = inverses {
 
-    if let Some(list) = inverses {
+            //list.insert(0, (String::from("push"), vec![Operand::Path(PathType::Absolute(path.clone()))]));
+            //list.insert(1, (String::from("data"), vec![Operand::String(name.unwrap())]));
 
-        //list.insert(0, (String::from("push"), vec![Operand::Path(PathType::Absolute(path.clone()))]));
-        //list.insert(1, (String::from("data"), vec![Operand::String(name.unwrap())]));
 
+            //There is no uninstaller to back up to while planning, so the backup is left unnamed
+            list.insert(0, InverseOp::Data { name: name.unwrap_or_default(), destination: path.clone() })?;
 
-        list.insert(0, format!("__data({:?}, pathtype.absolute({:?}))", name.unwrap(), path))
+        }
+    }
+
+    if let Some(plan) = plan {
+        plan.record(Action::Delete { path: path.clone() }, inverses);
 
+        if !p.is_temp() {
+            return Ok(());
+        }
     }
 
+    fs.remove(&path_rooted)?;
+
 
     Ok(())
 }
 
-pub fn copy(inverses: Option<&  Inverse>, source: &PathType, destination: &PathType, temp: & TempDir) -> Result<()> {
+pub fn copy(inverses: Option<&  Inverse>, plan: Option<& Plan>, fs: & dyn FileSystem, source: &PathType, destination: &PathType, temp: & TempDir, target_root: Option<& Path>) -> Result<()> {
+
+    let source_path = source.to_absolute_path(temp, None);
+    let destination_path = destination.to_absolute_path(temp, None);
 
-    let source_path = source.to_absolute_path(temp);
-    let destination_path = destination.to_absolute_path(temp);
+    let source_rooted = source.to_absolute_path(temp, target_root);
+    let destination_rooted = destination.to_absolute_path(temp, target_root);
 
-    if destination_path.exists() {
+    if fs.exists(&destination_rooted) {
         return Err(Error::AlreadyExists);
+    } else if !fs.exists(&source_rooted) {
+        return Err(Error::DoesntExist);
     } else {
-        if source_path.is_file() {
-
-            std::fs::copy(&source_path, &destination_path)?;
-        } else if source_path.is_dir() {
-            let mut options = fs_extra::dir::CopyOptions::default();
-            options.content_only = true;
-
-            fs_extra::dir::copy(&source_path, &destination_path, &options)?;
-        } else {
-            panic!("Source is not a file or directory");
-        }
-
         if !destination.is_temp() {
 
             if let Some(list) = inverses {
@@ -139,12 +158,22 @@ pub fn copy(inverses: Option<&  Inverse>, source: &PathType, destination: &PathT
                 //list.insert(0, (String::from("push"), vec![Operand::Path(PathType::Absolute(destination_path.as_path().to_path_buf()))]));
                 //list.insert(1, (String::from("delete"), vec![]));
 
-                list.insert(0, format!("__delete(pathtype.absolute({:?}))", destination_path));
+                list.insert(0, InverseOp::Delete { path: destination_path.clone() })?;
 
             }
 
 
         }
+
+        if let Some(plan) = plan {
+            plan.record(Action::Copy { source: source_path.clone(), destination: destination_path.clone() }, inverses);
+
+            if !destination.is_temp() {
+                return Ok(());
+            }
+        }
+
+        fs.copy(&source_rooted, &destination_rooted)?;
     }
 
     Ok(())
@@ -155,7 +184,7 @@ pub fn copy(inverses: Option<&  Inverse>, source: &PathType, destination: &PathT
 
 /*pub fn create(inverses: Option<& Inverse>, path: PathType, temp: & TempDir) -> Result<()>  {
 
-    let abs_path = path.to_absolute_path(temp);
+    let abs_path = path.to_absolute_path(temp, None);
 
     std::fs::File::create(&abs_path)?;
 
@@ -165,35 +194,50 @@ pub fn copy(inverses: Option<&  Inverse>, source: &PathType, destination: &PathT
             //list.insert(0, (String::from("push"), vec![Operand::Path(archive)]));
             //list.insert(1, (String::from("delete"), vec![]));
 
-            list.insert(0, format!("__delete(pathtype.absolute({:?}))", abs_path));
+            list.insert(0, InverseOp::Delete { path: abs_path.clone() });
         }
     }
 
     Ok(())
 }*/
 
-pub fn create_symlink(inverses: Option<& Inverse>, original: &PathType, link: &PathType, temp: & TempDir) -> Result<()> {
+pub fn create_symlink(inverses: Option<& Inverse>, plan: Option<& Plan>, original: &PathType, link: &PathType, temp: & TempDir, target_root: Option<& Path>) -> Result<()> {
 
     //Neither path can be a tmp path as this doesnt make much sense
 
 
     if !original.is_temp() && !link.is_temp() {
 
-        let original = original.to_absolute_path(temp);
-        let link = link.to_absolute_path(temp);
+        let original_rooted = original.to_absolute_path(temp, target_root);
+        let link_rooted = link.to_absolute_path(temp, target_root);
+
+        let original = original.to_absolute_path(temp, None);
+        let link = link.to_absolute_path(temp, None);
+
+        if let Some(plan) = plan {
+            if original_rooted.exists() {
+                if let Some(list) = inverses {
+                    list.insert(0, InverseOp::Delete { path: link.clone() })?;
+                }
+
+                plan.record(Action::CreateSymlink { original: original.clone(), link: link.clone() }, inverses);
+            }
 
-        if original.is_file() {
-            std::os::windows::fs::symlink_file(original, &link)?;
+            return Ok(());
+        }
 
+        if original_rooted.is_file() {
             if let Some(list) = inverses {
-                list.insert(0, format!("__delete(pathtype.absolute({:?}))", link));
+                list.insert(0, InverseOp::Delete { path: link.clone() })?;
             }
-        } else if original.is_dir() {
-            std::os::windows::fs::symlink_dir(original, &link)?;
 
+            symlink_file(original_rooted.as_path(), link_rooted.as_path())?;
+        } else if original_rooted.is_dir() {
             if let Some(list) = inverses {
-                list.insert(0, format!("__delete(pathtype.absolute({:?}))", link));
+                list.insert(0, InverseOp::Delete { path: link.clone() })?;
             }
+
+            symlink_dir(original_rooted.as_path(), link_rooted.as_path())?;
         }
 
 
@@ -205,11 +249,30 @@ pub fn create_symlink(inverses: Option<& Inverse>, original: &PathType, link: &P
     Ok(())
 }
 
-pub fn mkdir(inverses: Option<& Inverse>, path: PathType, temp: & TempDir) -> Result<()>  {
+#[cfg(windows)]
+fn symlink_file(original: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(original, link)
+}
 
-    let abs_path = path.to_absolute_path(temp);
+#[cfg(windows)]
+fn symlink_dir(original: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_dir(original, link)
+}
 
-    std::fs::create_dir(&abs_path)?;
+//Other platforms don't distinguish between links to files and folders
+#[cfg(unix)]
+fn symlink_file(original: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(unix)]
+fn symlink_dir(original: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+pub fn mkdir(inverses: Option<& Inverse>, plan: Option<& Plan>, fs: & dyn FileSystem, path: PathType, temp: & TempDir, target_root: Option<& Path>) -> Result<()>  {
+
+    let abs_path = path.to_absolute_path(temp, None);
 
     if !path.is_temp() {
         if let Some(list) = inverses {
@@ -217,25 +280,56 @@ pub fn mkdir(inverses: Option<& Inverse>, path: PathType, temp: & TempDir) -> Re
             //list.insert(0, (String::from("push"), vec![Operand::Path(archive)]));
             //list.insert(1, (String::from("delete"), vec![]));
 
-            list.insert(0, format!("__delete(pathtype.absolute({:?}))", abs_path));
+            list.insert(0, InverseOp::Delete { path: abs_path.clone() })?;
         }
     }
 
+    if let Some(plan) = plan {
+        plan.record(Action::Mkdir { path: abs_path.clone() }, inverses);
+
+        if !path.is_temp() {
+            return Ok(());
+        }
+    }
+
+    fs.create_dir(&path.to_absolute_path(temp, target_root))?;
+
     Ok(())
 }
 
 
 
-pub fn set_attributes(inverses: Option<& Inverse>, path: &PathType, attributes: u32, temp: &TempDir) -> Result<()> {
+pub fn set_attributes(inverses: Option<& Inverse>, plan: Option<& Plan>, path: &PathType, attributes: u32, temp: &TempDir, target_root: Option<& Path>) -> Result<()> {
 
 
-    use winapi::um::fileapi::SetFileAttributesA;
+    let abs_path = path.to_absolute_path(temp, None);
+
+    let abs_rooted = path.to_absolute_path(temp, target_root);
+
+    let current_atts = crate::mlc::extra_functions::get_attributes(abs_rooted.as_path()).map_err(|err| error::Error::Win32API(format!("{}", err)))?;
+
+    if !path.is_temp() {
+        if let Some(list) = inverses {
+            list.insert(0, InverseOp::SetAttributes { path: abs_path.clone(), attributes: current_atts })?;
+        }
+    }
+
+    if let Some(plan) = plan {
+        plan.record(Action::SetAttributes { path: abs_path.clone(), attributes }, inverses);
+
+        if !path.is_temp() {
+            return Ok(());
+        }
+    }
 
-    let abs_path = path.to_absolute_path(temp);
+    write_attributes(abs_rooted.as_path(), attributes)
+}
 
-    let current_atts = crate::mlc::extra_functions::get_attributes(abs_path.as_path()).unwrap();
+#[cfg(windows)]
+fn write_attributes(path: &Path, attributes: u32) -> Result<()> {
+    use winapi::um::fileapi::SetFileAttributesA;
 
-    let abs_str = abs_path.to_str().unwrap().as_bytes();
+    let abs_str = path.to_str().unwrap().as_bytes();
 
     let success = unsafe {
         let pointer = abs_str.as_ptr() as *const i8;
@@ -246,28 +340,30 @@ pub fn set_attributes(inverses: Option<& Inverse>, path: &PathType, attributes:
     let err = unsafe { winapi::um::errhandlingapi::GetLastError() };
 
     if success != 0 {
-        if !path.is_temp() {
-            if let Some(list) = inverses {
-                list.insert(0, format!("__set_attributes(pathtype.absolute({:?}), {})", abs_path, current_atts));
-            }
-        }
-
         Ok(())
     } else {
 
         Err(error::Error::Win32API(format!("{}", err)))
     }
+}
 
-
-
+#[cfg(not(windows))]
+fn write_attributes(_path: &Path, _attributes: u32) -> Result<()> {
+    Err(error::Error::Win32API(format!("{}", crate::mlc::extra_functions::ERROR_NOT_SUPPORTED)))
 }
 
 
-pub fn zip(inverses: Option<& Inverse>, archive: &PathType, folder: &PathType, temp: & TempDir) -> Result<()>  {
+pub fn zip(inverses: Option<& Inverse>, plan: Option<& Plan>, fs: & dyn FileSystem, archive: &PathType, folder: &PathType, temp: & TempDir, target_root: Option<& Path>) -> Result<()>  {
 
-    let archive_path = archive.to_absolute_path(temp);
+    let archive_path = archive.to_absolute_path(temp, None);
+    let folder_path = folder.to_absolute_path(temp, None);
 
-    zip_extensions::write::zip_create_from_directory(&archive_path, &folder.to_absolute_path(temp))?;
+    let archive_rooted = archive.to_absolute_path(temp, target_root);
+    let folder_rooted = folder.to_absolute_path(temp, target_root);
+
+    if !fs.is_dir(&folder_rooted) {
+        return Err(Error::DoesntExist);
+    }
 
     if !archive.is_temp() {
 
@@ -276,20 +372,36 @@ pub fn zip(inverses: Option<& Inverse>, archive: &PathType, folder: &PathType, t
             //list.insert(0, (String::from("push"), vec![Operand::Path(archive)]));
             //list.insert(1, (String::from("delete"), vec![]));
 
-            list.insert(0, format!("__delete(pathtype.absolute({:?}))", archive_path));
+            list.insert(0, InverseOp::Delete { path: archive_path.clone() })?;
         }
     }
 
+    if let Some(plan) = plan {
+        plan.record(Action::Zip { archive: archive_path.clone(), folder: folder_path.clone() }, inverses);
+
+        if !archive.is_temp() {
+            return Ok(());
+        }
+    }
+
+    fs.zip(&archive_rooted, &folder_rooted)?;
+
     Ok(())
 }
 
-pub fn unzip(inverses: Option<& Inverse>, archive: &PathType, folder: &PathType, temp: & TempDir) -> Result<()>  {
+pub fn unzip(inverses: Option<& Inverse>, plan: Option<& Plan>, fs: & dyn FileSystem, archive: &PathType, folder: &PathType, temp: & TempDir, target_root: Option<& Path>) -> Result<()>  {
 
-    let folder_path = folder.to_absolute_path(temp);
+    let folder_path = folder.to_absolute_path(temp, None);
+    let archive_path = archive.to_absolute_path(temp, None);
 
-    //std::fs::create_dir(&folder.path(temp)).unwrap();
+    let folder_rooted = folder.to_absolute_path(temp, target_root);
+    let archive_rooted = archive.to_absolute_path(temp, target_root);
 
-    zip_extensions::read::zip_extract(&archive.to_absolute_path(temp), &folder_path)?;
+    if !fs.is_file(&archive_rooted) {
+        return Err(Error::DoesntExist);
+    }
+
+    //std::fs::create_dir(&folder.path(temp)).unwrap();
 
     if !archive.is_temp() {
         //Ok(Some(Step::Delete { path: folder.path(temp) }))
@@ -299,39 +411,91 @@ pub fn unzip(inverses: Option<& Inverse>, archive: &PathType, folder: &PathType,
             //list.insert(0, (String::from("push"), vec![Operand::Path(folder.clone())]));
             //list.insert(1, (String::from("delete"), vec![]));
 
-            list.insert(0, format!("__delete(pathtype.absolute({:?}))", folder_path));
+            list.insert(0, InverseOp::Delete { path: folder_path.clone() })?;
         }
 
 
     }
 
+    if let Some(plan) = plan {
+        plan.record(Action::Unzip { archive: archive_path.clone(), folder: folder_path.clone() }, inverses);
+
+        if !folder.is_temp() {
+            return Ok(());
+        }
+    }
+
+    fs.unzip(&archive_rooted, &folder_rooted)?;
+
     Ok(())
 }
 
 
-pub fn download(inverses: Option<& Inverse>, url: & str, destination: &PathType, temp: & TempDir) -> Result<String>  {
+///How many times a download is attempted before giving up
+const DOWNLOAD_ATTEMPTS: u32 = 4;
 
-    let response = reqwest::blocking::get(url)?;
+///How long to wait before the first retry of a download. Each retry waits twice as long as the last
+const DOWNLOAD_BACKOFF: Duration = Duration::from_millis(250);
 
-    let file_name = if destination.to_absolute_path(temp).is_dir() {
-        let fname = response
-            .url()
-            .path_segments()
-            .and_then(|segments| segments.last())
-            .and_then(|name| if name.is_empty() {None} else {Some(name)})
-            .unwrap_or("tmp.bin");
+///A url for [`download`] to fetch, and where to put it
+pub struct DownloadRequest<'a> {
+    pub url: &'a str,
 
-        destination.to_absolute_path(temp).join( fname)
-    } else if destination.to_absolute_path(temp).is_file() {
-        destination.to_absolute_path(temp)
-    } else {
-        panic!("Download destination must be a directory or file")
+    ///Either a folder to download into or the file to download to
+    pub destination: &'a PathType,
+
+    ///The SHA-256 the downloaded file must have, if known
+    pub sha256: Option<&'a str>,
+
+    ///The name the url is archived under in the installer, if it was fetched when the installer was built
+    pub prefetched: Option<&'a str>,
+
+    ///Given the bytes downloaded so far and the total, if known
+    pub progress: &'a dyn Fn(u64, Option<u64>),
+}
+
+///Download a url to the destination in `download`. The body is streamed to the file as is, retrying with backoff and resuming
+///with Range requests if the connection fails. If a SHA-256 is given, the step fails unless the downloaded file matches it.
+///An existing file at the destination is backed up to `uninstaller` and restored when the download is undone.
+///
+///If the url was fetched when the installer was built, it is extracted from `installer` instead
+#[allow(clippy::too_many_arguments)]
+pub fn download(installer: & dyn Payload, uninstaller: Option<& OakWrite>, inverses: Option<& Inverse>, plan: Option<& Plan>, fs: & dyn FileSystem, download: &DownloadRequest, temp: & TempDir, target_root: Option<& Path>) -> Result<String>  {
+
+    let DownloadRequest { url, destination, sha256, prefetched, progress } = *download;
+
+    let client = reqwest::blocking::Client::new();
+
+    //Nothing is downloaded while planning, or for prefetched urls, so the file name comes from the url as given rather than
+    //the one we end up at
+    let mut response = match (plan, prefetched) {
+        (None, None) => {Some(retry(|| request(&client, url, 0))?)}
+        _ => {None}
     };
 
+    let destination_rooted = destination.to_absolute_path(temp, target_root);
 
+    //Anything that isn't a folder is the file to download to, whether or not it exists yet
+    let file_name = if fs.is_dir(&destination_rooted) {
+        let fname = response
+            .as_ref()
+            .map(|response| response.url().clone())
+            .or_else(|| reqwest::Url::parse(url).ok())
+            .and_then(|url| url.path_segments().and_then(|mut segments| segments.next_back()).map(String::from))
+            .and_then(|name| if name.is_empty() {None} else {Some(name)})
+            .unwrap_or(String::from("tmp.bin"));
 
+        destination.to_absolute_path(temp, None).join( fname)
+    } else {
+        destination.to_absolute_path(temp, None)
+    };
 
-    let mut dest = std::fs::File::create(file_name.clone())?;
+    //Temporary paths are never rerooted
+    let file_rooted = if destination.is_temp() {
+        file_name.clone()
+    } else {
+        reroot(&file_name, target_root)
+    };
 
     if !destination.is_temp() {
 
@@ -340,98 +504,232 @@ pub fn download(inverses: Option<& Inverse>, url: & str, destination: &PathType,
             //list.insert(0, (String::from("push"), vec![Operand::Path(PathType::Absolute(file_name.clone()))]));
             //list.insert(1, (String::from("delete"), vec![]));
 
-            list.insert(0, format!("__delete(pathtype.absolute({:?}))", file_name));
+            if fs.is_file(&file_rooted) {
+                //Backup the file the download replaces. There is no uninstaller to back up to while planning, so the backup is left unnamed
+                let name = uninstaller.map(|archive| archive.archive(fs, &file_rooted)).transpose()?.unwrap_or_default();
+
+                list.insert(0, InverseOp::Data { name, destination: file_name.clone() })?;
+            } else {
+                list.insert(0, InverseOp::Delete { path: file_name.clone() })?;
+            }
+        }
+    }
+
+    if let Some(plan) = plan {
+        plan.record(Action::Download { url: url.to_string(), destination: file_name.clone() }, inverses);
+
+        if !destination.is_temp() {
+            return Ok(file_name.to_str().unwrap().to_string());
         }
     }
 
-    let content = response.text()?;
-    std::io::copy(&mut content.as_bytes(), &mut dest)?;
+    let actual = match prefetched {
+        Some(name) => {
+            installer.extract(fs, name, file_rooted.as_path())?;
+
+            let mut hasher = Sha256::new();
+            std::io::copy(& mut fs.open(file_rooted.as_path())?, & mut hasher)?;
+
+            format!("{:x}", hasher.finalize())
+        }
+        None => {
+            //Downloads are streamed to a file on the real disk so they can be resumed, then moved into `fs` if it isn't the disk
+            let local = fs.local_path(&file_rooted, temp)?;
+
+            let actual = stream(&client, url, response.take(), & mut std::fs::File::create(local.as_path())?, progress)?;
+
+            if local != file_rooted {
+                fs.write(&file_rooted, & mut std::fs::File::open(local.as_path())?)?;
+            }
+
+            actual
+        }
+    };
+
+    if let Some(expected) = sha256 {
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(Error::Download(format!("{}: expected SHA-256 {}, found {}", url, expected, actual)));
+        }
+    }
 
     Ok(file_name.to_str().unwrap().to_string())
 }
 
-pub fn edit(uninstaller: Option<& OakWrite>, inverses: Option<& Inverse>, s: &PathType, command: & str, temp: & TempDir) -> Result<()>  {
+///Download `url` to `file`, as [`download`] does, without recording anything. Gives the SHA-256 of the file
+pub fn fetch(url: & str, file: & mut std::fs::File, progress: & dyn Fn(u64, Option<u64>)) -> Result<String> {
+    stream(&reqwest::blocking::Client::new(), url, None, file, progress)
+}
+
+///Stream `url` into `file`, retrying and resuming until all of it has arrived. `response` is the first attempt, if it has
+///already been made. Gives the SHA-256 of everything written
+fn stream(client: & reqwest::blocking::Client, url: & str, mut response: Option<reqwest::blocking::Response>, file: & mut std::fs::File, progress: & dyn Fn(u64, Option<u64>)) -> Result<String> {
+    let mut written = 0;
+    let mut hasher = Sha256::new();
+
+    retry(|| {
+        let response = match response.take() {
+            Some(response) => {response}
+            None => {request(client, url, written)?}
+        };
+
+        receive(response, file, & mut written, & mut hasher, progress)
+    })?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+///Run `attempt` until it succeeds, waiting longer after each failure. Only network failures are retried, so requests the
+///server refused and errors writing the file fail straight away
+fn retry<T, F: FnMut() -> Result<T>>(mut attempt: F) -> Result<T> {
+    let mut wait = DOWNLOAD_BACKOFF;
+
+    for _ in 1..DOWNLOAD_ATTEMPTS {
+        match attempt() {
+            Err(Error::Reqwest(_)) | Err(Error::Connection(_)) => {
+                std::thread::sleep(wait);
+                wait *= 2;
+            }
+            result => {return result}
+        }
+    }
+
+    attempt()
+}
+
+///Request `url`, asking for everything after the first `from` bytes if there are any
+fn request(client: & reqwest::blocking::Client, url: & str, from: u64) -> Result<reqwest::blocking::Response> {
+    let mut request = client.get(url);
+
+    if from > 0 {
+        request = request.header(RANGE, format!("bytes={}-", from));
+    }
+
+    let response = request.send()?;
+
+    //Asking again won't change the server's mind
+    if response.status().is_client_error() {
+        return Err(Error::Download(format!("{}: {}", url, response.status())));
+    }
+
+    Ok(response.error_for_status()?)
+}
+
+///Stream the body of `response` into `file` after the `written` bytes already there, hashing it as it goes. If the server
+///sent the whole file rather than the rest of it, `file` is started again
+fn receive(mut response: reqwest::blocking::Response, file: & mut std::fs::File, written: & mut u64, hasher: & mut Sha256, progress: & dyn Fn(u64, Option<u64>)) -> Result<()> {
+    let start = response
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| range.strip_prefix("bytes "))
+        .and_then(|range| range.split('-').next())
+        .and_then(|start| start.parse::<u64>().ok());
+
+    if response.status() != StatusCode::PARTIAL_CONTENT || start != Some(*written) {
+        *written = 0;
+        *hasher = Sha256::new();
+
+        file.set_len(0)?;
+    }
+
+    file.seek(SeekFrom::Start(*written))?;
+
+    let total = response.content_length().map(|length| *written + length);
+
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        let read = response.read(& mut buffer).map_err(Error::Connection)?;
+
+        if read == 0 {
+            break;
+        }
+
+        file.write_all(&buffer[..read])?;
+        hasher.update(&buffer[..read]);
+
+        *written += read as u64;
+
+        progress(*written, total);
+    }
+
+    //The connection was closed early
+    if total.map(|total| *written < total).unwrap_or(false) {
+        return Err(Error::Connection(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)));
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn edit(uninstaller: Option<& OakWrite>, inverses: Option<& Inverse>, plan: Option<& Plan>, fs: & dyn FileSystem, s: &PathType, command: & str, temp: & TempDir, target_root: Option<& Path>) -> Result<()>  {
 
 
-    use std::io::Write;
+    use std::io::Read;
 
-    let source = s.to_absolute_path(temp);
+    let source = s.to_absolute_path(temp, None);
+
+    let source_rooted = s.to_absolute_path(temp, target_root);
 
 
     //Load `source`
-    let content = std::fs::read_to_string(source.as_path())?;
+    let mut content = String::new();
+    fs.open(source_rooted.as_path())?.read_to_string(& mut content)?;
 
 
     //Perform find and replace
     let res = sedregex::find_and_replace(content.as_str(), &[command])?;
 
 
-    match uninstaller {
-        None => {  }
-        Some(archive) => {
-
-            let name = archive.archive(&source);
+    let name = uninstaller.map(|archive| archive.archive(fs, &source_rooted)).transpose()?;
 
-            if !s.is_temp() {
+    if !s.is_temp() {
 
 
-                if let Some(list) = inverses {
-                    //list.insert(0, (String::from("push"), vec![Operand::Path(s.clone())]));
-                    //list.insert(1, (String::from("delete"), vec![]));
+        if let Some(list) = inverses {
+            //list.insert(0, (String::from("push"), vec![Operand::Path(s.clone())]));
+            //list.insert(1, (String::from("delete"), vec![]));
 
-                    list.insert(0, format!("__delete(pathtype.absolute({:?}))", source));
+            list.insert(0, InverseOp::Delete { path: source.clone() })?;
 
-                    //list.insert(2, (String::from("push"), vec![Operand::Path(s.clone())]));
-                    //list.insert(3, (String::from("data"), vec![Operand::String(name)]));
+            //list.insert(2, (String::from("push"), vec![Operand::Path(s.clone())]));
+            //list.insert(3, (String::from("data"), vec![Operand::String(name)]));
 
-                    list.insert(1, format!("__data({:?}, pathtype.absolute({:?}))", name, source))
+            list.insert(1, InverseOp::Data { name: name.unwrap_or_default(), destination: source.clone() })?;
 
-                }
+        }
 
-            }
+    }
 
+    if let Some(plan) = plan {
+        plan.record(Action::Edit { path: source.clone(), command: command.to_string() }, inverses);
 
+        if !s.is_temp() {
+            return Ok(());
         }
     }
 
     //Save back to `source`
-    let mut fh = std::fs::OpenOptions::new().write(true).open(source.as_path())?;
-
-    fh.write_all(res.as_ref().as_bytes())?;
+    fs.write(source_rooted.as_path(), & mut res.as_ref().as_bytes())?;
 
     Ok(())
 
 }
 
 
-pub fn write_reg_key(inverses: Option<& Inverse>, root: & RootKey, key: & str) -> Result<()>  {
-
-    let reg = registry::Hive::from(root); //.open(key, Security::AllAccess)?;
-
-
+pub fn write_reg_key(inverses: Option<& Inverse>, plan: Option<& Plan>, backend: & dyn RegistryBackend, root: & RootKey, key: & str) -> Result<()>  {
 
     //Look for the oldest ancestor that was newly created as part of this call.
     //For example, if a registry key looks like 'example\path\to\' before, and
     //'example\path\to\demonstrate\inverse' after, then you want to delete 'example\path\to\demonstrate'
     let common = {
-        let ancestors = Path::new(key).ancestors().collect::<Vec<_>>();
-
-        ancestors
-            .iter()
-            .rev()
-            .map(|x| {
-
-                let o= reg.open(x.to_str().unwrap(), Security::Read);
+        let parts = key.split('\\').collect::<Vec<_>>();
 
-                (x.clone(), o)
-            })
-            .find(|(_, x)| x.is_err())
-            .map(|(path, _)| path)
+        (1..=parts.len())
+            .map(|n| parts[..n].join("\\"))
+            .find(|ancestor| !backend.key_exists(root, ancestor))
     };
 
-    reg.create(key, Security::AllAccess)?;
-
-
     if let Some(p) = common {
         if let Some(list) = inverses {
             //list.insert(0, (String::from("push"), vec![Operand::String(String::from(p.to_str().unwrap()))]));
@@ -439,18 +737,25 @@ pub fn write_reg_key(inverses: Option<& Inverse>, root: & RootKey, key: & str) -
             //list.insert(2, (String::from("reg_delete_key"), vec![]));
 
 
-            list.insert(0, format!("__reg_delete_key(\"{:?}\", {:?})", root, p));
+            list.insert(0, InverseOp::RegDeleteKey { root: root.clone(), key: p })?;
 
         }
     }
 
+    if let Some(plan) = plan {
+        plan.record(Action::RegWriteKey { root: root.clone(), key: key.to_string() }, inverses);
+        return Ok(());
+    }
+
+    backend.create_key(root, key)?;
+
     Ok(())
 }
 
 
-pub fn write_reg_value(inverses: Option<& Inverse>, root: &RootKey, key: &str, value: &str, data: &registry::Data) -> Result<()>  {
+pub fn write_reg_value(inverses: Option<& Inverse>, plan: Option<& Plan>, backend: & dyn RegistryBackend, root: &RootKey, key: &str, value: &str, data: &Data) -> Result<()>  {
 
-    let reg = registry::Hive::from(root).open(key, Security::AllAccess)?;
+    let old_value = backend.value(root, key, value)?;
 
     //For inverses, there are two cases. If the value already exists (i.e. we are modifying it)
     //and if the value does not already exist (i.e. we are creating it). In the first case,
@@ -458,18 +763,7 @@ pub fn write_reg_value(inverses: Option<& Inverse>, root: &RootKey, key: &str, v
     //to delete the value.
 
     if let Some(list) = inverses {
-        if let Err(registry::value::Error::NotFound(_,_)) = reg.value(value) {
-
-            //list.insert(0, (String::from("push"), vec![Operand::String(value.clone())]));
-            //list.insert(1, (String::from("push"), vec![Operand::String(key.clone())]));
-            //list.insert(2, (String::from("push"), vec![Operand::String(root.clone())]));
-            //list.insert(3, (String::from("reg_delete_value"), vec![]));
-
-            list.insert(0, format!("__reg_delete_value(\"{:?}\", {:?}, {:?})", root, key, value));
-
-        } else {
-
-            let old_value = reg.value(value).unwrap();
+        if let Some(old_value) = old_value {
 
             //list.insert(0, (String::from("push"), vec![Operand::try_from(old_value).unwrap()]));
             //list.insert(1, (String::from("push"), vec![Operand::String(value.clone())]));
@@ -477,13 +771,25 @@ pub fn write_reg_value(inverses: Option<& Inverse>, root: &RootKey, key: &str, v
             //list.insert(3, (String::from("push"), vec![Operand::String(root.clone())]));
             //list.insert(4, (String::from("reg_write_value"), vec![]));
 
-            //todo!()
+            list.insert(0, InverseOp::RegWriteValue { root: root.clone(), key: key.to_string(), value: value.to_string(), data: old_value })?;
+
+        } else {
+
+            //list.insert(0, (String::from("push"), vec![Operand::String(value.clone())]));
+            //list.insert(1, (String::from("push"), vec![Operand::String(key.clone())]));
+            //list.insert(2, (String::from("push"), vec![Operand::String(root.clone())]));
+            //list.insert(3, (String::from("reg_delete_value"), vec![]));
 
-            list.insert(0, format!("__reg_write_value(\"{:?}\", {:?}, {:?}, {})", root, key, value, crate::mlc::data_to_code(&Data::from(old_value))));
+            list.insert(0, InverseOp::RegDeleteValue { root: root.clone(), key: key.to_string(), value: value.to_string() })?;
         }
     }
 
-    reg.set_value(value, &data)?;
+    if let Some(plan) = plan {
+        plan.record(Action::RegWriteValue { root: root.clone(), key: key.to_string(), value: value.to_string(), data: data.clone() }, inverses);
+        return Ok(());
+    }
+
+    backend.set_value(root, key, value, data)?;
 
 
     //Ok(Some(inverse))
@@ -491,13 +797,9 @@ pub fn write_reg_value(inverses: Option<& Inverse>, root: &RootKey, key: &str, v
     Ok(())
 }
 
-pub fn delete_reg_value(inverses: Option<& Inverse>, root: &RootKey, key: &str, value: &str) -> Result<()>  {
+pub fn delete_reg_value(inverses: Option<& Inverse>, plan: Option<& Plan>, backend: & dyn RegistryBackend, root: &RootKey, key: &str, value: &str) -> Result<()>  {
 
-    let reg = registry::Hive::from(root).open(key, Security::AllAccess)?;
-
-    let old_value = reg.value(value).unwrap();
-
-    reg.delete_value(value)?;
+    let old_value = backend.value(root, key, value)?.ok_or(Error::DoesntExist)?;
 
     if let Some(list) = inverses {
 
@@ -508,33 +810,38 @@ pub fn delete_reg_value(inverses: Option<& Inverse>, root: &RootKey, key: &str,
         //list.insert(4, (String::from("reg_write_value"), vec![]));
 
 
-        list.insert(0, format!("__reg_write_value(\"{:?}\", {:?}, {:?}, {})", root, key, value, crate::mlc::data_to_code(&Data::from(old_value))));
+        list.insert(0, InverseOp::RegWriteValue { root: root.clone(), key: key.to_string(), value: value.to_string(), data: old_value })?;
+
+    }
 
+    if let Some(plan) = plan {
+        plan.record(Action::RegDeleteValue { root: root.clone(), key: key.to_string(), value: value.to_string() }, inverses);
+        return Ok(());
     }
 
+    backend.delete_value(root, key, value)?;
+
 
     Ok(())
 
 }
 
 fn recursive_recover(
-    regkey: &registry::RegKey,
+    backend: & dyn RegistryBackend,
     rootkey: & RootKey,
+    name: & str,
     list: & Inverse,
-    index: & mut usize) {
-
-    let name = regkey.to_string();
-    let name = name.split_once("\\").unwrap().1;
+    index: & mut usize) -> Result<()> {
 
     //list.insert(*index, (String::from("push"), vec![Operand::String(name.to_string())]));
     //list.insert(*index + 1, (String::from("push"), vec![rootkey.clone()]));
     //list.insert(*index + 2, (String::from("reg_write_key"), vec![]));
 
-    list.insert(*index, format!("__reg_write_key(\"{:?}\", {:?})", rootkey, name));
+    list.insert(*index, InverseOp::RegWriteKey { root: rootkey.clone(), key: name.to_string() })?;
 
-    *index = *index + 1;
+    *index += 1;
 
-    for value in regkey.values().map(|x| x.unwrap()) {
+    for (value, data) in backend.values(rootkey, name)? {
 
         //list.insert(*index, (String::from("push"), vec![Operand::try_from(value.data().clone()).unwrap()]));
         //list.insert(*index+1, (String::from("push"), vec![Operand::String(value.name().to_string().unwrap())]));
@@ -542,29 +849,37 @@ fn recursive_recover(
         //list.insert(*index + 3, (String::from("push"), vec![rootkey.clone()]));
         //list.insert(*index + 4, (String::from("reg_write_value"), vec![]));
 
-        list.insert(*index, format!("__reg_write_value(\"{:?}\", {:?}, {:?}, {})", rootkey, name, value.name().to_string().unwrap(), crate::mlc::data_to_code(&Data::from(value.data().clone()))));
+        list.insert(*index, InverseOp::RegWriteValue { root: rootkey.clone(), key: name.to_string(), value, data })?;
 
-        *index = *index + 1;
+        *index += 1;
 
     }
 
-    for key in regkey.keys().map(|x| x.unwrap()) {
-        recursive_recover(&key.open(Security::Read).unwrap(), rootkey, list, index);
+    for key in backend.subkeys(rootkey, name)? {
+        recursive_recover(backend, rootkey, format!("{}\\{}", name, key).as_str(), list, index)?;
     }
+
+    Ok(())
 }
 
-pub fn delete_reg_key(inverses: Option<& Inverse>, root: &RootKey, key: &str) -> Result<()>  {
+pub fn delete_reg_key(inverses: Option<& Inverse>, plan: Option<& Plan>, backend: & dyn RegistryBackend, root: &RootKey, key: &str) -> Result<()>  {
 
-    let reg = registry::Hive::from(root).open(key, Security::AllAccess)?;
+    if !backend.key_exists(root, key) {
+        return Err(Error::DoesntExist);
+    }
 
 
     if let Some(list) = inverses {
         let mut index = 0;
-        recursive_recover(&reg, root, list, & mut index);
+        recursive_recover(backend, root, key, list, & mut index)?;
+    }
+
+    if let Some(plan) = plan {
+        plan.record(Action::RegDeleteKey { root: root.clone(), key: key.to_string() }, inverses);
+        return Ok(());
     }
 
-    reg.delete("", true)?; //Delete the contents of the key
-    reg.delete_self(false)?; //Delete the key itself
+    backend.delete_key(root, key)?;
 
 
 
@@ -574,15 +889,20 @@ pub fn delete_reg_key(inverses: Option<& Inverse>, root: &RootKey, key: &str) ->
 
 }
 
-pub fn file_open(mut uninstaller: Option<& OakWrite>, inverses: Option<& Inverse>, path: PathType, mode: String, temp: &TempDir) -> Result<()> {
+///Record the inverse of opening `path` with `mode`, and get the path that should actually be opened
+#[allow(clippy::too_many_arguments)]
+pub fn file_open(mut uninstaller: Option<& OakWrite>, inverses: Option<& Inverse>, plan: Option<& Plan>, fs: & dyn FileSystem, path: PathType, mode: String, temp: &TempDir, target_root: Option<& Path>) -> Result<PathBuf> {
+
+    let abs_path = path.to_absolute_path(temp, None);
+
+    let abs_rooted = path.to_absolute_path(temp, target_root);
 
     if !path.is_temp() {
-        let abs_path = path.to_absolute_path(temp);
 
-        let bytes = if mode.as_bytes()[mode.len() - 1] == 'b' as u8 {
+        let bytes = if mode.as_bytes()[mode.len() - 1] == b'b' {
             &mode.as_bytes()[..mode.len() - 1]
         } else {
-            &mode.as_bytes()
+            mode.as_bytes()
         };
 
 
@@ -591,18 +911,18 @@ pub fn file_open(mut uninstaller: Option<& OakWrite>, inverses: Option<& Inverse
             match from_utf8_unchecked(bytes) {
                 "r" | "r+" => {} //Do nothing
                 "w" | "a" | "w+" | "a+" => {
-                    if PathBuf::from(&abs_path).exists() {
+                    if fs.exists(&abs_rooted) {
                         //Backup the original file
-                        let name = uninstaller.as_mut().map(|archive| archive.archive(&abs_path)).unwrap();
+                        let name = uninstaller.as_mut().map(|archive| archive.archive(fs, &abs_rooted)).transpose()?.unwrap_or_default();
 
                         if let Some(list) = inverses {
-                            list.insert(0, format!("__data({:?}, pathtype.absolute({:?}))", name, &abs_path))
+                            list.insert(0, InverseOp::Data { name, destination: abs_path.clone() })?;
                         }
                     } else {
                         //
                         if let Some(list) = inverses {
 
-                            list.insert(0, format!("__delete(pathtype.absolute({:?}))", &abs_path));
+                            list.insert(0, InverseOp::Delete { path: abs_path.clone() })?;
                         }
                     }
                 }
@@ -611,7 +931,23 @@ pub fn file_open(mut uninstaller: Option<& OakWrite>, inverses: Option<& Inverse
                 _ => {}
             }
         }
+
+        if let Some(plan) = plan {
+            if bytes != b"r" {
+                plan.record(Action::FileOpen { path: abs_path.clone(), mode: mode.clone() }, inverses);
+
+                //Anything that might write is pointed at a scratch copy in the temporary folder instead
+                let (mut file, scratch) = tempfile::NamedTempFile::new_in(temp.path())?.keep().map_err(std::io::Error::from)?;
+
+                if fs.is_file(&abs_rooted) {
+                    std::io::copy(& mut fs.open(&abs_rooted)?, & mut file)?;
+                }
+
+                return Ok(scratch);
+            }
+        }
     }
 
-    Ok(())
+    //Lua can only open files on the real disk
+    fs.local_path(&abs_rooted, temp)
 }
@@ -1,6 +1,224 @@
+//This file contains some higher level functions, made up of functions from 'functions.rs' and 'extra_functions.rs'
 
+///The `oak.lib` helpers, run before every script.
+///
+///They are written in Lua using only the `oak` functions, so everything they change is recorded for the uninstaller by the
+///functions they call. Paths can be path types or absolute path strings, and temporary paths stay temporary
+pub const LIBRARY: &str = r#"
+local lib = {}
 
+local UNINSTALL = "Software\\Microsoft\\Windows\\CurrentVersion\\Uninstall\\"
 
-//This file contains some higher level functions, made up of functions from 'functions.rs' and 'extra_functions.rs'
+-- Folders created by ensure_dir_all, which don't exist yet when planning
+local created = {}
+
+-- Split `path` into the pathtype function that makes it and the path to give that function. Temporary paths stay
+-- temporary, so scratch files aren't recorded or checked against the capabilities, and anything else is absolute
+local function split(path)
+    if type(path) == "string" then
+        return pathtype.absolute, path
+    end
+
+    if path.ident == "t" then
+        return pathtype.temp, path.path
+    end
+
+    return pathtype.absolute, __get_abs_path(path)
+end
+
+local function exists(make, path)
+    return oak.exists(__get_abs_path(make(path)))
+end
+
+local function separator(path)
+    if string.find(path, "\\", 1, true) then
+        return "\\"
+    end
+
+    return "/"
+end
+
+-- Join `relative`, separated by `/`, onto `base` using the separator `base` uses
+local function join(base, relative)
+    local sep = separator(base)
+
+    relative = string.gsub(relative, "[/\\]", sep)
+
+    if string.sub(base, -1) == sep then
+        return base .. relative
+    end
+
+    return base .. sep .. relative
+end
+
+local function parent(path)
+    return string.match(path, "^(.*)[/\\][^/\\]+[/\\]?$")
+end
+
+local function sorted_keys(t)
+    local keys = {}
+
+    for key in pairs(t) do
+        table.insert(keys, key)
+    end
+
+    table.sort(keys)
+
+    return keys
+end
+
+-- The values of a registry key, or nil if it doesn't exist
+local function values(root, key)
+    local ok, data = pcall(oak.get_registry_data, root, key)
+
+    if ok then
+        return data.kv_pairs
+    end
+
+    return nil
+end
+
+local function ensure_dir_all(make, path)
+    -- Stop at the root or drive
+    if path == nil or path == "" or string.match(path, "^%a:$") then
+        return
+    end
+
+    local key = __get_abs_path(make(path))
+
+    if created[key] or oak.exists(key) then
+        return
+    end
+
+    ensure_dir_all(make, parent(path))
+
+    oak.mkdir(make(path))
+
+    created[key] = true
+end
+
+-- Create the folder `path`, and any of its parents that don't exist
+function lib.ensure_dir_all(path)
+    ensure_dir_all(split(path))
+end
+
+-- Install the archived files in `tree` under the folder `destination`, creating folders as needed. `tree` maps paths
+-- separated by `/` to archived names, as glob payload references and oak.payload entries with patterns do
+function lib.install_dir_tree(tree, destination)
+    local make, destination = split(destination)
+
+    ensure_dir_all(make, destination)
+
+    for _, path in ipairs(sorted_keys(tree)) do
+        local target = join(destination, path)
+
+        ensure_dir_all(make, parent(target))
+
+        oak.data(tree[path], make(target))
+    end
+end
+
+-- Install the archived file `name` to `destination`, first moving any file already there to `backup`, which is
+-- `destination` .. ".bak" if not given
+function lib.replace_file_with_backup(name, destination, backup)
+    local make, destination = split(destination)
+    local make_backup, backup = split(backup or make(destination .. ".bak"))
+
+    if exists(make, destination) then
+        if exists(make_backup, backup) then
+            oak.delete(make_backup(backup))
+        end
+
+        oak.move(make(destination), make_backup(backup))
+    end
+
+    oak.data(name, make(destination))
+end
+
+-- Write `contents` to the file `path` unless it already exists, so settings a user has changed are kept. Gives whether
+-- the file was written
+function lib.write_config_if_absent(path, contents)
+    local make, path = split(path)
+
+    if exists(make, path) then
+        return false
+    end
+
+    ensure_dir_all(make, parent(path))
+
+    local file = io.open(make(path), "w")
+    file:write(contents)
+    file:close()
+
+    return true
+end
+
+-- Is `name` installed, judging by its entry in Programs and Features under `root` (HKLM by default)
+function lib.is_installed(name, root)
+    return values(root or HKLM, UNINSTALL .. name) ~= nil
+end
+
+-- Add `name` to Programs and Features under `root` (HKLM by default). `fields` gives the entry's values, such as
+-- DisplayName, DisplayVersion and UninstallString
+function lib.add_uninstall_entry(name, fields, root)
+    root = root or HKLM
+
+    local key = UNINSTALL .. name
+
+    if values(root, key) == nil then
+        oak.reg_write_key(root, key)
+    end
+
+    for _, value in ipairs(sorted_keys(fields)) do
+        oak.reg_write_value(root, key, value, fields[value])
+    end
+end
+
+local function multi_string(root, key, value)
+    local data = values(root, key)
+    local lines = {}
+
+    if data ~= nil and type(data[value]) == "table" then
+        for _, line in ipairs(data[value]) do
+            table.insert(lines, line)
+        end
+    end
+
+    return lines
+end
+
+-- Add `line` to the end of the multi-string registry value `value`, unless it's already there. The key must exist
+function lib.reg_append_multi(root, key, value, line)
+    local lines = multi_string(root, key, value)
+
+    for _, existing in ipairs(lines) do
+        if existing == line then
+            return
+        end
+    end
+
+    table.insert(lines, line)
+
+    oak.reg_write_value(root, key, value, lines)
+end
+
+-- Remove every copy of `line` from the multi-string registry value `value`
+function lib.reg_remove_multi(root, key, value, line)
+    local lines = {}
+    local removed = false
+
+    for _, existing in ipairs(multi_string(root, key, value)) do
+        if existing == line then
+            removed = true
+        else
+            table.insert(lines, existing)
+        end
+    end
 
+    if removed then
+        oak.reg_write_value(root, key, value, lines)
+    end
+end
 
+oak.lib = lib
+"#;
@@ -1,36 +1,42 @@
-mod functions;
+pub mod functions;
 mod extra_functions;
 mod higher_functions;
-mod registry_ex;
+pub mod sandbox;
+pub mod registry_ex;
 
-use std::path::{PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::cell::Cell;
 use std::sync::Arc;
 use crate::{OakRead, OakWrite};
+use crate::oak::{Payload, PayloadEntry};
+use crate::plan::Plan;
+use crate::filesystem::FileSystem;
+use crate::registry_backend::RegistryBackend;
 
-use crate::path_type::{Inverse, PathType};
+use crate::path_type::{enclosed, reroot, Inverse, InverseOp, PathType};
 
 use rlua::{Context, FromLua, Lua, Table, ToLua, Value};
 use rlua::prelude::{LuaError};
 use crate::mlc::registry_ex::{Data, RootKey};
 
 use crate::error::{Error};
+use std::collections::BTreeMap;
 
 use rlua::Result;
 
-//Take the oak code and run it
-pub fn run(code: & str, install: & OakRead, uninstall: Option<& OakWrite>, inverses: Option<& Inverse>, temp: &tempfile::TempDir) -> Result<()> {
+//Take the oak code and run it. If `plan` is given, the code is run in plan mode and the machine is left untouched.
+//All file operations go through `fs`, and all registry operations go through `backend`.
+//If `target_root` is given, every absolute path is remapped under it, while inverses still record the logical paths
+#[allow(clippy::too_many_arguments)]
+pub fn run(code: & str, install: & OakRead, uninstall: Option<& OakWrite>, inverses: Option<& Inverse>, temp: &tempfile::TempDir, plan: Option<& Plan>, fs: & dyn FileSystem, backend: & dyn RegistryBackend, target_root: Option<& Path>) -> Result<()> {
 
     let lua = Lua::new();
 
 
-    let code = format!("
--- Delete the tmpname and execute functions
-os.tmpname = null
-io.tmpfile = null
-os.execute = null
-
+    //Sets up the oak functions. Everything it leaves that isn't vetted by the sandbox is removed before `code` runs
+    let prelude = format!("
 -- Redefine remove and rename
-os.remove = __remove
+os.remove = __delete
 os.rename = __rename
 
 -- Add the following functions
@@ -100,18 +106,14 @@ HKU  = \"HKU\"
 _expanded = null
 _qword = null
 
-____io_open = io.open
+local raw_open = io.open
+local file_open = __file_open
 
-function _open (filename, mode)
-    __file_open(filename, mode)
-    path = __get_abs_path(filename)
-    return ____io_open(path, mode)
+function io.open (filename, mode)
+    local path = file_open(filename, mode)
+    return raw_open(path, mode)
 end
 
-io.open = _open
-
---_open = null
-
 oak = {{}}
 oak.delete = __delete
 oak.move = __move
@@ -122,7 +124,7 @@ oak.copy = __copy
 oak.zip = __zip
 oak.unzip = __unzip
 oak.download = __download
-oak.edit = _edit
+oak.edit = __edit
 oak.reg_write_key = __reg_write_key
 oak.reg_delete_key = __reg_delete_key
 oak.reg_write_value = __reg_write_value
@@ -134,12 +136,67 @@ oak.exists = __exists
 oak.file_timestamps = __file_timestamps
 oak.get_registry_data = __get_registry_data
 oak.set_attributes = __set_attributes
-
+oak.payload = __payload
 
 {}
 
+-- Modules are only loaded from the archive, never from the machine being installed to
+local __loaded = {{}}
+local __modules = __modules
+local load = load
+
+function require (name)
+    if __loaded[name] == nil then
+        local module = name
+        local code = __modules[module]
+
+        if code == nil then
+            module = name .. \".init\"
+            code = __modules[module]
+        end
+
+        if code == nil then
+            error(\"module '\" .. name .. \"' is not in the archive\", 2)
+        end
+
+        local chunk = assert(load(code, \"=\" .. module))
+
+        __loaded[name] = chunk(name)
+
+        if __loaded[name] == nil then
+            __loaded[name] = true
+        end
+    end
+
+    return __loaded[name]
+end
+
+    ", higher_functions::LIBRARY);
+
+    //Everything the script changes must be declared in the capabilities of the installer
+    let capabilities = install.info().map_err(LuaError::from)?.capabilities;
+
+    //Only paths outside the temporary folder need declaring
+    let writable = |path: &PathType| -> Result<()> {
+        if path.is_temp() {
+            return Ok(());
+        }
+
+        Ok(capabilities.check_path(path.to_absolute_path(temp, None).as_path())?)
+    };
+
+    let registry = |root: &RootKey, key: &str| -> Result<()> {
+        Ok(capabilities.check_key(root, key)?)
+    };
 
-    ", code);
+    //Paths given to the query functions as plain strings are logical, unless they are in the temporary folder
+    let rooted = |path: PathBuf| {
+        if path.starts_with(temp.path()) && !path.components().any(|component| component == Component::ParentDir) {
+            path
+        } else {
+            reroot(&path, target_root)
+        }
+    };
 
     lua.context(|ctx| {
         ctx.scope(|scope| {
@@ -150,28 +207,38 @@ oak.set_attributes = __set_attributes
 
             globals.set("__delete",
                         scope.create_function(|_, path: PathType| {
-                            functions::delete( uninstall, inverses.clone(), &path, temp)?;
+                            writable(&path)?;
+
+                            functions::delete( uninstall, inverses, plan, fs, &path, temp, target_root)?;
                             Ok(())
                         }).unwrap()
             ).unwrap();
 
             globals.set("__move",
                         scope.create_function(|_, (source, destination): (PathType, PathType)| {
-                            functions::_move(inverses, &source, &destination, temp)?;
+                            writable(&source)?;
+                            writable(&destination)?;
+
+                            functions::_move(inverses, plan, fs, &source, &destination, temp, target_root)?;
                             Ok(())
                         }).unwrap()
             ).unwrap();
 
             globals.set("__rename",
                         scope.create_function(|_, (source, destination): (PathType, PathType)| {
-                            functions::_move(inverses, &source, &destination, temp)?;
+                            writable(&source)?;
+                            writable(&destination)?;
+
+                            functions::_move(inverses, plan, fs, &source, &destination, temp, target_root)?;
                             Ok(())
                         }).unwrap()
             ).unwrap();
 
             globals.set("__data",
                         scope.create_function(|_, (name, destination): (String, PathType)| {
-                            functions::data(install, inverses, &name, &destination, temp)?;
+                            writable(&destination)?;
+
+                            functions::data(install, inverses, plan, fs, &name, &destination, temp, target_root)?;
                             Ok(())
                         }).unwrap()
             ).unwrap();
@@ -185,143 +252,202 @@ oak.set_attributes = __set_attributes
 
             globals.set("__mkdir",
                         scope.create_function(|_, path: PathType| {
-                            functions::mkdir( inverses, path, temp)?;
+                            writable(&path)?;
+
+                            functions::mkdir( inverses, plan, fs, path, temp, target_root)?;
                             Ok(())
                         }).unwrap()
             ).unwrap();
 
             globals.set("__copy",
                         scope.create_function(|_, (source, destination): (PathType, PathType)| {
-                            functions::copy(inverses, &source, &destination, temp)?;
+                            writable(&destination)?;
+
+                            functions::copy(inverses, plan, fs, &source, &destination, temp, target_root)?;
                             Ok(())
                         }).unwrap()
             ).unwrap();
 
             globals.set("__zip",
                         scope.create_function(|_, (archive, folder): (PathType, PathType)| {
-                            functions::zip(inverses, &archive, &folder, temp)?;
+                            writable(&archive)?;
+
+                            functions::zip(inverses, plan, fs, &archive, &folder, temp, target_root)?;
                             Ok(())
                         }).unwrap()
             ).unwrap();
 
             globals.set("__unzip",
                         scope.create_function(|_, (archive, folder): (PathType, PathType)| {
-                            functions::unzip(inverses, &archive, &folder, temp)?;
+                            writable(&folder)?;
+
+                            functions::unzip(inverses, plan, fs, &archive, &folder, temp, target_root)?;
                             Ok(())
                         }).unwrap()
             ).unwrap();
 
             globals.set("__download",
-                        scope.create_function(|_, (url, destination): (String, PathType)| -> rlua::Result<String> {
-                            let f = functions::download(inverses, &url, &destination, temp)?;
+                        scope.create_function(|_, (url, destination, sha256): (String, PathType, Option<String>)| -> rlua::Result<String> {
+                            capabilities.check_url(&url)?;
+                            writable(&destination)?;
+
+                            //Report every tenth of the download, or every megabyte if its size isn't known
+                            let reported = Cell::new(None);
+
+                            let progress = |done: u64, total: Option<u64>| {
+                                let step = match total {
+                                    Some(total) if total > 0 => {done * 10 / total}
+                                    _ => {done / (1024 * 1024)}
+                                };
+
+                                if reported.replace(Some(step)) != Some(step) {
+                                    match total {
+                                        Some(total) => {println!("Downloading {}: {} of {} bytes", url, done, total)}
+                                        None => {println!("Downloading {}: {} bytes", url, done)}
+                                    }
+                                }
+                            };
+
+                            let prefetched = install.downloads().get(&url).map(String::as_str);
+
+                            let request = functions::DownloadRequest { url: &url, destination: &destination, sha256: sha256.as_deref(), prefetched, progress: &progress };
+
+                            let f = functions::download(install, uninstall, inverses, plan, fs, &request, temp, target_root)?;
                             Ok(f)
                         }).unwrap()
             ).unwrap();
 
             globals.set("__edit",
                         scope.create_function(|_, (path, reg): (PathType, String)| {
-                            functions::edit(uninstall, inverses, &path, &reg, temp)?;
+                            writable(&path)?;
+
+                            functions::edit(uninstall, inverses, plan, fs, &path, &reg, temp, target_root)?;
                             Ok(())
                         }).unwrap()
             ).unwrap();
 
             globals.set("__reg_write_key",
                         scope.create_function(|_, (root, key): (RootKey, String)| {
-                            functions::write_reg_key( inverses, &root, &key)?;
+                            registry(&root, &key)?;
+
+                            functions::write_reg_key( inverses, plan, backend, &root, &key)?;
                             Ok(())
                         }).unwrap()
             ).unwrap();
 
             globals.set("__reg_delete_key",
                         scope.create_function(|_, (root, key): (RootKey, String)| {
-                            functions::delete_reg_key( inverses, &root, &key)?;
+                            registry(&root, &key)?;
+
+                            functions::delete_reg_key( inverses, plan, backend, &root, &key)?;
                             Ok(())
                         }).unwrap()
             ).unwrap();
 
             globals.set("__reg_write_value",
                         scope.create_function(|_, (root, key, value, data): (RootKey, String, String, Data)| {
-                            functions::write_reg_value( inverses, &root, &key, &value, &registry::Data::from(&data))?;
+                            registry(&root, &key)?;
+
+                            functions::write_reg_value( inverses, plan, backend, &root, &key, &value, &data)?;
                             Ok(())
                         }).unwrap()
             ).unwrap();
 
             globals.set("__reg_delete_value",
                         scope.create_function(|_, (root, key, value): (RootKey, String, String)| {
-                            functions::delete_reg_value( inverses, &root, &key, &value)?;
+                            registry(&root, &key)?;
+
+                            functions::delete_reg_value( inverses, plan, backend, &root, &key, &value)?;
                             Ok(())
                         }).unwrap()
             ).unwrap();
 
             globals.set("__directory_contents",
                         scope.create_function(|_, path: String| {
-                            extra_functions::directory_contents(&PathBuf::from(path))
+                            extra_functions::directory_contents(fs, &PathBuf::from(&path), &rooted(PathBuf::from(&path)))
                         }).unwrap()
             ).unwrap();
 
             globals.set("__file_type",
                         scope.create_function(|_, path: String| {
-                            extra_functions::file_type(&PathBuf::from(path))
+                            extra_functions::file_type(fs, &rooted(PathBuf::from(path)))
                         }).unwrap()
             ).unwrap();
 
             globals.set("__exists",
                         scope.create_function(|_, path: String| {
-                            extra_functions::exists(&PathBuf::from(path))
+                            extra_functions::exists(fs, &rooted(PathBuf::from(path)))
                         }).unwrap()
             ).unwrap();
 
             globals.set("__file_timestamps",
                         scope.create_function(|_, path: String| {
-                            extra_functions::file_timestamps(&PathBuf::from(path))
+                            extra_functions::file_timestamps(&rooted(PathBuf::from(path)))
                         }).unwrap()
             ).unwrap();
 
             globals.set("__file_open",
                         scope.create_function(|_, (path, mode): (PathType, String)| {
-                            functions::file_open(uninstall, inverses, path, mode, temp)?;
-                            Ok(())
+                            //Only opening a file to read it doesn't need declaring
+                            if mode != "r" && mode != "rb" {
+                                writable(&path)?;
+                            }
+
+                            let path = functions::file_open(uninstall, inverses, plan, fs, path, mode, temp, target_root)?;
+                            Ok(path.to_str().unwrap().to_string())
                         }).unwrap()
             ).unwrap();
 
             globals.set("__get_registry_data",
                         scope.create_function(|c, (root, key): (String, String)| {
-                            extra_functions::get_registry_data(c, &RootKey::from(root.as_str()), key)
+                            extra_functions::get_registry_data(c, backend, &RootKey::from(root.as_str()), key)
                         }).unwrap()
             ).unwrap();
 
             globals.set("__get_abs_path",
                         scope.create_function(|_, path: PathType| {
-                            Ok(path.to_absolute_path(temp).to_str().unwrap().to_string())
+                            Ok(path.to_absolute_path(temp, None).to_str().unwrap().to_string())
                         }).unwrap()
             ).unwrap();
 
             globals.set("__create_symlink",
                         scope.create_function(|_, (original, link): (PathType, PathType)| {
-                            functions::create_symlink(inverses, &original, &link, temp)?;
+                            writable(&link)?;
+
+                            functions::create_symlink(inverses, plan, &original, &link, temp, target_root)?;
                             Ok(())
                         }).unwrap()
             ).unwrap();
 
             globals.set("__set_attributes",
                         scope.create_function(|_, (path, attr): (PathType, u32)| -> rlua::Result<()> {
-                            functions::set_attributes(inverses, &path, attr, temp)?;
+                            writable(&path)?;
+
+                            functions::set_attributes(inverses, plan, &path, attr, temp, target_root)?;
                             Ok(())
                         }).unwrap()
             ).unwrap();
 
+            globals.set("__payload", payload_table(ctx, install.payload())?).unwrap();
+
+            globals.set("__modules", ctx.create_table_from(install.modules().iter().map(|(k, v)| (k.as_str(), v.as_str())))?).unwrap();
+
+
+
 
 
 
 
+            ctx.load(prelude.as_str()).exec()?;
 
+            sandbox::apply(ctx)?;
 
-            match ctx.load(code.as_str()).exec() {
+            match ctx.load(code).exec() {
                 Ok(_) => {Ok(())}
                 Err(e) => {
 
                     if let rlua::Error::CallbackError { traceback, cause } = &e {
-                        println!("Callback error: {} \n\n{}", traceback, cause.to_string());
+                        println!("Callback error: {} \n\n{}", traceback, cause);
                     } else {
                         println!("Other error: {}", e);
                     }
@@ -363,6 +489,67 @@ oak.set_attributes = __set_attributes
 
 }
 
+///Execute a list of inverses recorded by [`run`], undoing the installation that produced them.
+///
+///Inverses are recorded before the step they undo, so the last inverse may belong to a step that never ran.
+///Inverses whose work is already done (deleting something that doesn't exist, for example) are skipped.
+///Inverses record logical paths, so an installation made under `target_root` must be replayed with the same root.
+pub fn replay(ops: &[InverseOp], payload: & dyn Payload, fs: & dyn FileSystem, backend: & dyn RegistryBackend, temp: &tempfile::TempDir, target_root: Option<& Path>) -> crate::error::Result<()> {
+
+    for op in ops {
+        match op {
+            InverseOp::Delete { path } => {
+                let rooted = reroot(path, target_root);
+
+                if fs.exists(&rooted) || rooted.is_symlink() {
+                    functions::delete(None, None, None, fs, &PathType::Absolute(path.clone()), temp, target_root)?;
+                }
+            }
+            InverseOp::Move { source, destination } => {
+                if fs.exists(&reroot(source, target_root)) {
+                    functions::_move(None, None, fs, &PathType::Absolute(source.clone()), &PathType::Absolute(destination.clone()), temp, target_root)?;
+                }
+            }
+            InverseOp::Data { name, destination } => {
+                //Restoring a backup always replaces whatever is there now
+                let rooted = reroot(destination, target_root);
+
+                if fs.exists(&rooted) || rooted.is_symlink() {
+                    functions::delete(None, None, None, fs, &PathType::Absolute(destination.clone()), temp, target_root)?;
+                }
+
+                functions::data(payload, None, None, fs, name, &PathType::Absolute(destination.clone()), temp, target_root)?;
+            }
+            InverseOp::RegWriteKey { root, key } => {
+                functions::write_reg_key(None, None, backend, root, key)?;
+            }
+            InverseOp::RegDeleteKey { root, key } => {
+                if backend.key_exists(root, key) {
+                    functions::delete_reg_key(None, None, backend, root, key)?;
+                }
+            }
+            InverseOp::RegWriteValue { root, key, value, data } => {
+                functions::write_reg_value(None, None, backend, root, key, value, data)?;
+            }
+            InverseOp::RegDeleteValue { root, key, value } => {
+                let exists = backend
+                    .value(root, key, value)
+                    .map(|data| data.is_some())
+                    .unwrap_or(false);
+
+                if exists {
+                    functions::delete_reg_value(None, None, backend, root, key, value)?;
+                }
+            }
+            InverseOp::SetAttributes { path, attributes } => {
+                functions::set_attributes(None, None, &PathType::Absolute(path.clone()), *attributes, temp, target_root)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
 impl<'l> FromLua<'l> for PathType {
     fn from_lua(lua_value: Value<'l>, lua: Context<'l>) -> rlua::Result<Self> {
         let table = Table::from_lua(lua_value, lua)?;
@@ -377,13 +564,19 @@ impl<'l> FromLua<'l> for PathType {
 
                 Ok(PathType::Special(PathBuf::from(special), PathBuf::from(path)))
             }
-             "t" => { Ok(PathType::Temporary(PathBuf::from(path))) },
+            "t" => {
+                //Temporary paths aren't undone or checked against the capabilities, so they must stay in the temporary folder.
+                //The folder isn't known here, so only check that the path stays within an empty base; the joined path is unused
+                enclosed(Path::new(""), path.as_str()).map_err(LuaError::from)?;
+
+                Ok(PathType::Temporary(PathBuf::from(path)))
+            },
             "a" => { Ok(PathType::Absolute(PathBuf::from(path))) },
             _ => {
                 Err(rlua::Error::FromLuaConversionError {
                     from: "Lua Table",
                     to: "PathType",
-                    message: Some(format!("Invalid PathType value. Please create a pathtype via the pathtype.temp or pathtype.absolute functions"))
+                    message: Some("Invalid PathType value. Please create a pathtype via the pathtype.temp or pathtype.absolute functions".to_string())
                 })
             }
         }
@@ -439,7 +632,7 @@ impl<'lua> FromLua<'lua> for Data {
                     }
                     Err(_) => {
 
-                        let pairs: Vec<_> = table.pairs::<Value, Value>().into_iter().map(|x| x.unwrap()).collect();
+                        let pairs: Vec<_> = table.pairs::<Value, Value>().map(|x| x.unwrap()).collect();
 
                         let is_multiline = move || -> rlua::Result<_> {
                             let mut multi = vec![None; pairs.len()];
@@ -459,7 +652,7 @@ impl<'lua> FromLua<'lua> for Data {
                                         from: "Vector of Strings",
                                         to: "Data::MultiString",
                                         message: Some("Bad index in multi string lua table".to_string()),
-                                    })?) = Some(s.to_str().map_err(|x| x)?.to_string());
+                                    })?) = Some(s.to_str()?.to_string());
 
                                 } else {
                                     return Ok(None);
@@ -575,24 +768,17 @@ impl<'lua> FromLua<'lua> for RootKey {
     }
 }
 
-pub fn data_to_code(data: &Data) -> String {
-    match data {
-        Data::None => {format!("null")}
-        Data::String(s) => {format!("{:?}",s)}
-        Data::ExpandString(s) => {format!("registry.expanded({:?})", s)}
-        Data::U32(n) => {format!("{}", n)}
-        Data::MultiString(m) => {
-            let mut s = String::from("{");
-
-            for line in m {
-                s.push_str(format!("{:?}, ", line).as_str());
-            }
-
-            s.push_str("}");
+///Convert the payload declared in the source into the `oak.payload` table, where each name maps to the name of an archived entry,
+///or to a table of them for names declared with include patterns
+fn payload_table<'lua>(ctx: Context<'lua>, payload: &BTreeMap<String, PayloadEntry>) -> Result<Table<'lua>> {
+    let table = ctx.create_table()?;
 
-            s
+    for (name, entry) in payload {
+        match entry {
+            PayloadEntry::Single(archived) => {table.set(name.as_str(), archived.as_str())?}
+            PayloadEntry::Tree(files) => {table.set(name.as_str(), ctx.create_table_from(files.iter().map(|(k, v)| (k.as_str(), v.as_str())))?)?}
         }
-        Data::U64(n) => {format!("registry.qword({:?})", n)}
-        _ => {panic!("Invalid data")}
     }
+
+    Ok(table)
 }
@@ -1,7 +1,9 @@
+use serde::{Serialize, Deserialize};
+
 
 
 //Creating our own Data and Rootkey implementations is required for serde
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Data {
     None,
     String(String),
@@ -26,6 +28,7 @@ impl From<&str> for Data {
     }
 }
 
+#[cfg(windows)]
 impl From<registry::Data> for Data {
     fn from(d: registry::Data) -> Self {
         match d {
@@ -45,6 +48,7 @@ impl From<registry::Data> for Data {
     }
 }
 
+#[cfg(windows)]
 impl From<&Data> for registry::Data {
     fn from(d: &Data) -> Self {
         match d {
@@ -52,19 +56,21 @@ impl From<&Data> for registry::Data {
             Data::String(z) => {registry::Data::String(utfx::U16CString::try_from(z).unwrap())}
             Data::ExpandString(z) => {registry::Data::ExpandString(utfx::U16CString::try_from(z).unwrap())}
             Data::Binary(z) => {registry::Data::Binary(z.clone())}
-            Data::U32(z) => {registry::Data::U32(z.clone())}
-            Data::U32BE(z) => {registry::Data::U32BE(z.clone())}
+            Data::U32(z) => {registry::Data::U32(*z)}
+            Data::U32BE(z) => {registry::Data::U32BE(*z)}
             Data::Link => {registry::Data::Link}
             Data::MultiString(z) => {registry::Data::MultiString(z.iter().map(|s| utfx::U16CString::try_from(s).unwrap()).collect())}
             Data::ResourceList => {registry::Data::ResourceList}
             Data::FullResourceDescriptor => {registry::Data::FullResourceDescriptor}
             Data::ResourceRequirementsList => {registry::Data::ResourceRequirementsList}
-            Data::U64(z) => {registry::Data::U64(z.clone()) }
+            Data::U64(z) => {registry::Data::U64(*z) }
         }
     }
 }
 
-#[derive(Clone, Debug)]
+//The names match the registry's own abbreviations, which scripts use too
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum RootKey {
     HKLM,
     HKCC,
@@ -87,6 +93,7 @@ impl From<&str> for RootKey {
     }
 }
 
+#[cfg(windows)]
 impl From<&RootKey> for registry::Hive {
     fn from(rk: &RootKey) -> Self {
         match rk {
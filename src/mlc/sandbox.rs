@@ -0,0 +1,277 @@
+//This file decides which globals scripts can see. Anything not listed in `POLICY` is removed before a script runs
+
+use rlua::{Context, Result, Table, Value};
+use Capability::{Forbidden, Inverse, ReadOnly};
+
+///What a function or value available to scripts can do to the machine
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Capability {
+    ///Doesn't change the machine
+    ReadOnly,
+
+    ///Changes the machine, recording inverses so the uninstaller undoes it
+    Inverse,
+
+    ///Could change the machine without recording inverses, or run unchecked code, so it is removed
+    Forbidden,
+}
+
+///Every global scripts can see. The fields of a table are vetted too if any of them are listed here (as `table.field`),
+///otherwise the whole table is vetted with it
+pub const POLICY: &[(&str, Capability)] = &[
+    ("_G", ReadOnly),
+    ("_VERSION", ReadOnly),
+    ("assert", ReadOnly),
+    ("collectgarbage", ReadOnly),
+    ("error", ReadOnly),
+    ("getmetatable", ReadOnly),
+    ("ipairs", ReadOnly),
+    ("next", ReadOnly),
+    ("pairs", ReadOnly),
+    ("pcall", ReadOnly),
+    ("print", ReadOnly),
+    ("rawequal", ReadOnly),
+    ("rawget", ReadOnly),
+    ("rawlen", ReadOnly),
+    ("rawset", ReadOnly),
+    ("select", ReadOnly),
+    ("setmetatable", ReadOnly),
+    ("tonumber", ReadOnly),
+    ("tostring", ReadOnly),
+    ("type", ReadOnly),
+    ("warn", ReadOnly),
+    ("xpcall", ReadOnly),
+
+    //Modules only come from the archive
+    ("require", ReadOnly),
+
+    ("load", Forbidden),
+    ("loadstring", Forbidden),
+    ("dofile", Forbidden),
+    ("loadfile", Forbidden),
+    ("debug", Forbidden),
+    ("package", Forbidden),
+
+    ("coroutine", ReadOnly),
+    ("coroutine.close", ReadOnly),
+    ("coroutine.create", ReadOnly),
+    ("coroutine.isyieldable", ReadOnly),
+    ("coroutine.resume", ReadOnly),
+    ("coroutine.running", ReadOnly),
+    ("coroutine.status", ReadOnly),
+    ("coroutine.wrap", ReadOnly),
+    ("coroutine.yield", ReadOnly),
+
+    ("math", ReadOnly),
+    ("math.abs", ReadOnly),
+    ("math.acos", ReadOnly),
+    ("math.asin", ReadOnly),
+    ("math.atan", ReadOnly),
+    ("math.ceil", ReadOnly),
+    ("math.cos", ReadOnly),
+    ("math.deg", ReadOnly),
+    ("math.exp", ReadOnly),
+    ("math.floor", ReadOnly),
+    ("math.fmod", ReadOnly),
+    ("math.huge", ReadOnly),
+    ("math.log", ReadOnly),
+    ("math.max", ReadOnly),
+    ("math.maxinteger", ReadOnly),
+    ("math.min", ReadOnly),
+    ("math.mininteger", ReadOnly),
+    ("math.modf", ReadOnly),
+    ("math.pi", ReadOnly),
+    ("math.rad", ReadOnly),
+    ("math.random", ReadOnly),
+    ("math.randomseed", ReadOnly),
+    ("math.sin", ReadOnly),
+    ("math.sqrt", ReadOnly),
+    ("math.tan", ReadOnly),
+    ("math.tointeger", ReadOnly),
+    ("math.type", ReadOnly),
+    ("math.ult", ReadOnly),
+
+    ("string", ReadOnly),
+    ("string.byte", ReadOnly),
+    ("string.char", ReadOnly),
+    ("string.dump", Forbidden),
+    ("string.find", ReadOnly),
+    ("string.format", ReadOnly),
+    ("string.gmatch", ReadOnly),
+    ("string.gsub", ReadOnly),
+    ("string.len", ReadOnly),
+    ("string.lower", ReadOnly),
+    ("string.match", ReadOnly),
+    ("string.pack", ReadOnly),
+    ("string.packsize", ReadOnly),
+    ("string.rep", ReadOnly),
+    ("string.reverse", ReadOnly),
+    ("string.sub", ReadOnly),
+    ("string.unpack", ReadOnly),
+    ("string.upper", ReadOnly),
+
+    ("table", ReadOnly),
+    ("table.concat", ReadOnly),
+    ("table.insert", ReadOnly),
+    ("table.move", ReadOnly),
+    ("table.pack", ReadOnly),
+    ("table.remove", ReadOnly),
+    ("table.sort", ReadOnly),
+    ("table.unpack", ReadOnly),
+
+    ("utf8", ReadOnly),
+    ("utf8.char", ReadOnly),
+    ("utf8.charpattern", ReadOnly),
+    ("utf8.codepoint", ReadOnly),
+    ("utf8.codes", ReadOnly),
+    ("utf8.len", ReadOnly),
+    ("utf8.offset", ReadOnly),
+
+    ("io", ReadOnly),
+    ("io.open", Inverse),
+    ("io.close", ReadOnly),
+    ("io.flush", ReadOnly),
+    ("io.read", ReadOnly),
+    ("io.write", ReadOnly),
+    ("io.type", ReadOnly),
+    ("io.stdin", ReadOnly),
+    ("io.stdout", ReadOnly),
+    ("io.stderr", ReadOnly),
+    ("io.input", Forbidden),
+    ("io.lines", Forbidden),
+    ("io.output", Forbidden),
+    ("io.popen", Forbidden),
+    ("io.tmpfile", Forbidden),
+
+    ("os", ReadOnly),
+    ("os.clock", ReadOnly),
+    ("os.date", ReadOnly),
+    ("os.difftime", ReadOnly),
+    ("os.getenv", ReadOnly),
+    ("os.time", ReadOnly),
+    ("os.copy", Inverse),
+    ("os.move", Inverse),
+    ("os.remove", Inverse),
+    ("os.rename", Inverse),
+    ("os.execute", Forbidden),
+    ("os.exit", Forbidden),
+    ("os.setlocale", Forbidden),
+    ("os.tmpname", Forbidden),
+
+    ("pathtype", ReadOnly),
+    ("registry", ReadOnly),
+    ("HKLM", ReadOnly),
+    ("HKCC", ReadOnly),
+    ("HKCR", ReadOnly),
+    ("HKCU", ReadOnly),
+    ("HKU", ReadOnly),
+
+    ("oak", Inverse),
+    ("oak.copy", Inverse),
+    ("oak.data", Inverse),
+    ("oak.delete", Inverse),
+    ("oak.download", Inverse),
+    ("oak.edit", Inverse),
+    ("oak.lib", Inverse),
+    ("oak.mkdir", Inverse),
+    ("oak.move", Inverse),
+    ("oak.reg_delete_key", Inverse),
+    ("oak.reg_delete_value", Inverse),
+    ("oak.reg_write_key", Inverse),
+    ("oak.reg_write_value", Inverse),
+    ("oak.rename", Inverse),
+    ("oak.set_attributes", Inverse),
+    ("oak.unzip", Inverse),
+    ("oak.zip", Inverse),
+    ("oak.directory_contents", ReadOnly),
+    ("oak.exists", ReadOnly),
+    ("oak.file_timestamps", ReadOnly),
+    ("oak.file_type", ReadOnly),
+    ("oak.get_registry_data", ReadOnly),
+    ("oak.payload", ReadOnly),
+
+    ("__copy", Inverse),
+    ("__create_symlink", Inverse),
+    ("__data", Inverse),
+    ("__delete", Inverse),
+    ("__download", Inverse),
+    ("__edit", Inverse),
+    ("__file_open", Inverse),
+    ("__mkdir", Inverse),
+    ("__move", Inverse),
+    ("__reg_delete_key", Inverse),
+    ("__reg_delete_value", Inverse),
+    ("__reg_write_key", Inverse),
+    ("__reg_write_value", Inverse),
+    ("__rename", Inverse),
+    ("__set_attributes", Inverse),
+    ("__unzip", Inverse),
+    ("__zip", Inverse),
+    ("__directory_contents", ReadOnly),
+    ("__exists", ReadOnly),
+    ("__file_timestamps", ReadOnly),
+    ("__file_type", ReadOnly),
+    ("__get_abs_path", ReadOnly),
+    ("__get_registry_data", ReadOnly),
+
+    //Only used to set up `oak.payload` and `require`
+    ("__modules", Forbidden),
+    ("__payload", Forbidden),
+];
+
+///Get the capability of a global, or a field of one as `table.field`, or `None` if it hasn't been vetted
+pub fn capability(name: &str) -> Option<Capability> {
+    POLICY.iter().find(|(vetted, _)| *vetted == name).map(|(_, capability)| *capability)
+}
+
+///Can scripts use a global, or a field of one as `table.field`
+pub fn allowed(name: &str) -> bool {
+    matches!(capability(name), Some(ReadOnly) | Some(Inverse))
+}
+
+///Are the fields of the global `name` vetted one by one
+pub fn is_library(name: &str) -> bool {
+    POLICY.iter().any(|(vetted, _)| vetted.len() > name.len() && vetted.starts_with(name) && vetted[name.len()..].starts_with('.'))
+}
+
+///Remove every global, and every field of a library, that isn't allowed
+pub fn apply(ctx: Context) -> Result<()> {
+    let globals = ctx.globals();
+
+    for (key, value) in entries(&globals)? {
+        let name = match &key {
+            Value::String(name) => {name.to_str()?.to_string()}
+            _ => {
+                globals.raw_set(key, Value::Nil)?;
+                continue;
+            }
+        };
+
+        if !allowed(name.as_str()) {
+            globals.raw_set(key, Value::Nil)?;
+            continue;
+        }
+
+        if let Value::Table(library) = value {
+            if is_library(name.as_str()) {
+                for (field, _) in entries(&library)? {
+                    let vetted = match &field {
+                        Value::String(field) => {allowed(format!("{}.{}", name, field.to_str()?).as_str())}
+                        _ => {false}
+                    };
+
+                    if !vetted {
+                        library.raw_set(field, Value::Nil)?;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+///Get the entries of a table, so it can be changed while they are gone through
+fn entries<'lua>(table: &Table<'lua>) -> Result<Vec<(Value<'lua>, Value<'lua>)>> {
+    table.clone().pairs::<Value, Value>().collect()
+}
@@ -3,21 +3,28 @@ use zip::{ZipArchive, ZipWriter};
 use std::path::{Path, PathBuf};
 use std::fs::OpenOptions;
 use zip::write::FileOptions;
-use crate::error::{Result};
-use zip_extensions::{ZipWriterExtensions};
+use zip::CompressionMethod;
+use crate::error::{Error, Result};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::ops::{DerefMut};
 use std::sync::Mutex;
+use std::collections::BTreeMap;
+use std::fs::File;
+use sha2::{Digest, Sha256};
 use serde::{Serialize, Deserialize};
+use crate::path_type::InverseOp;
+use crate::filesystem::{self, FileSystem, ReadSeek};
+use crate::exe_extender::Window;
+use crate::capabilities::Capabilities;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub enum OakType {
     Installer,
     Uninstaller,
 }
 
 ///Describes the location that the uninstaller should be located
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub enum UninstallLocation {
     ///A user specified absolute path
     Path(PathBuf),
@@ -35,7 +42,7 @@ pub enum UninstallLocation {
 }
 
 ///Extra information about an installer/uninstaller packaged in the archive
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Info {
     ///Is the archive an installer or an uninstaller
     pub oak_type: OakType,
@@ -48,6 +55,10 @@ pub struct Info {
 
     ///If set to true, the installer will fail if it isn't elavated
     pub elevated: bool,
+
+    ///What the installer may change. Building fails if the script names something outside it, and so does installing
+    #[serde(default)]
+    pub capabilities: Capabilities,
 }
 
 impl Default for Info {
@@ -57,6 +68,7 @@ impl Default for Info {
             u_location: UninstallLocation::Path(PathBuf::from("Sample path")),
             reboot: false,
             elevated: false,
+            capabilities: Capabilities::default(),
         }
     }
 }
@@ -85,20 +97,189 @@ impl Info {
 
 }
 
+///The hash and size of an archived entry, recorded in the `_manifest` entry of the archive
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ManifestEntry {
+    pub sha256: String,
+    pub size: u64,
+}
+
+impl ManifestEntry {
+    ///Hash everything in `reader`
+    fn of<R: Read>(reader: & mut R) -> Result<Self> {
+        let mut hasher = Sha256::new();
+        let size = std::io::copy(reader, & mut hasher)?;
+
+        Ok(Self {
+            sha256: format!("{:x}", hasher.finalize()),
+            size,
+        })
+    }
+}
+
+///A named part of the payload declared in the `<files>` section of a source, recorded in the `_payload` entry of the archive
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(untagged)]
+pub enum PayloadEntry {
+    ///A single file or folder, by the name it is archived under
+    Single(String),
+
+    ///The files matched by include patterns, mapping each path (relative to the folder searched, separated by `/`) to the
+    ///name it is archived under
+    Tree(BTreeMap<String, String>),
+}
+
+///A source of archived entries that inverses can restore from
+pub trait Payload {
+    ///Extract the entry `name` to `destination` in `fs`
+    fn extract(&self, fs: & dyn FileSystem, name: &str, destination: &Path) -> Result<()>;
+}
+
 ///A struct used to read an oak archive
 pub struct OakRead {
-    archive: Mutex<ZipArchive<std::fs::File>>,
+    archive: Mutex<ZipArchive<Window>>,
+    ///The archive's part of the file, for reading uncompressed blobs in place
+    window: Window,
+    ///Maps the name of each archived file or folder to the blob holding its contents
+    index: BTreeMap<String, String>,
+    ///The expected hash and size of each archived file or folder
+    manifest: BTreeMap<String, ManifestEntry>,
+    ///The payload declared in the source, by name
+    payload: BTreeMap<String, PayloadEntry>,
+    ///The code of each Lua module scripts can `require`, by module name
+    modules: BTreeMap<String, String>,
+    ///The name each url fetched when the installer was built is archived under
+    downloads: BTreeMap<String, String>,
 }
 
 impl OakRead {
 
     ///Create a new reader from an existing oak archive
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open(Window::whole(OpenOptions::new().read(true).open(path)?)?)
+    }
+
+    ///Create a new reader from an oak archive in part of a file, such as the exe it is appended to
+    pub fn open(window: Window) -> Result<Self> {
+        let mut archive = ZipArchive::new(window.try_clone()?)?;
+
+        //Archives written before deduplication have no index, and store each entry under its own name
+        let index = match archive.by_name("_index") {
+            Ok(file) => {serde_json::from_reader(file)?}
+            Err(_) => {BTreeMap::new()}
+        };
+
+        //Likewise, archives written before the manifest can't be verified
+        let manifest = match archive.by_name("_manifest") {
+            Ok(file) => {serde_json::from_reader(file)?}
+            Err(_) => {BTreeMap::new()}
+        };
+
+        //Sources without a `<files>` section have no declared payload
+        let payload = match archive.by_name("_payload") {
+            Ok(file) => {serde_json::from_reader(file)?}
+            Err(_) => {BTreeMap::new()}
+        };
+
+        //Likewise for sources without modules
+        let modules = match archive.by_name("_modules") {
+            Ok(file) => {serde_json::from_reader(file)?}
+            Err(_) => {BTreeMap::new()}
+        };
+
+        //And for online installers
+        let downloads = match archive.by_name("_downloads") {
+            Ok(file) => {serde_json::from_reader(file)?}
+            Err(_) => {BTreeMap::new()}
+        };
+
         Ok(Self {
-            archive: Mutex::new(ZipArchive::new(OpenOptions::new().read(true).open(path).unwrap())?),
+            archive: Mutex::new(archive),
+            window,
+            index,
+            manifest,
+            payload,
+            modules,
+            downloads,
         })
     }
 
+    ///Get the names of all the archived files and folders
+    pub fn entries(& self) -> Vec<String> {
+        if !self.index.is_empty() {
+            return self.index.keys().cloned().collect();
+        }
+
+        //Archives written before deduplication store each entry under its own name, next to the entries used by the archive itself
+        let guard = self.archive.lock().unwrap();
+
+        guard.file_names()
+            .filter(|name| !["_commands", "_inverses", "_info", "_index", "_manifest", "_payload", "_modules", "_downloads"].contains(name))
+            .map(String::from)
+            .collect()
+    }
+
+    ///Get the expected hash and size of every archived file and folder
+    pub fn manifest(& self) -> &BTreeMap<String, ManifestEntry> {
+        &self.manifest
+    }
+
+    ///Check every archived file and folder against the manifest, so a truncated or corrupted archive is found before anything is changed
+    pub fn verify(& self) -> Result<()> {
+        let mut guard = self.archive.lock().unwrap();
+
+        for name in self.manifest.keys() {
+            let mut blob = guard.by_name(self.blob(name))?;
+
+            //A corrupted entry may fail to decompress at all
+            let actual = ManifestEntry::of(& mut blob).map_err(|e| corrupted(name, e))?;
+
+            self.check(name, &actual)?;
+        }
+
+        Ok(())
+    }
+
+    ///The name of the blob holding the entry `name`
+    fn blob<'a>(& 'a self, name: &'a str) -> &'a str {
+        self.index.get(name).map(String::as_str).unwrap_or(name)
+    }
+
+    ///Check the hash and size of the entry `name` against the manifest
+    fn check(& self, name: &str, actual: &ManifestEntry) -> Result<()> {
+        if let Some(expected) = self.manifest.get(name) {
+            if expected.size != actual.size {
+                return Err(Error::Corrupted(format!("{}: expected {} bytes, found {}", name, expected.size, actual.size)));
+            }
+
+            if expected.sha256 != actual.sha256 {
+                return Err(Error::Corrupted(format!("{}: expected SHA-256 {}, found {}", name, expected.sha256, actual.sha256)));
+            }
+        }
+
+        Ok(())
+    }
+
+    ///Get the payload declared in the source, which scripts see as `oak.payload`
+    pub fn payload(& self) -> &BTreeMap<String, PayloadEntry> {
+        &self.payload
+    }
+
+    ///Get the code of the Lua modules scripts can `require`, by module name
+    pub fn modules(& self) -> &BTreeMap<String, String> {
+        &self.modules
+    }
+
+    ///Get the name each url fetched when the installer was built is archived under, by url
+    pub fn downloads(& self) -> &BTreeMap<String, String> {
+        &self.downloads
+    }
+
+    ///Get the names of the archived files and folders, and the blobs that hold them
+    pub fn index(& self) -> &BTreeMap<String, String> {
+        &self.index
+    }
+
     ///Get the list of commands stored in the archive
     pub fn commands(& self) -> Result<String> {
         //bincode::deserialize_from(self.archive.by_name("_command").unwrap()).unwrap()
@@ -108,6 +289,13 @@ impl OakRead {
         Ok(res)
     }
 
+    ///Get the list of inverses stored in an uninstaller
+    pub fn inverses(& self) -> Result<Vec<InverseOp>> {
+        let mut guard = self.archive.lock().unwrap();
+        let inverses = serde_json::from_reader(guard.by_name("_inverses")?)?;
+        Ok(inverses)
+    }
+
     ///Get the information in the _info section of the archive
     pub fn info(& self) -> Result<Info> {
 
@@ -117,96 +305,173 @@ impl OakRead {
 
     }
 
-    ///Extract the specified file `name` to `destination`
-    pub fn extract<P: AsRef<Path>>(& self, name: &str, destination: P) -> Result<()> {
-
-        let mut guard = self.archive.lock().unwrap();
-
-        let mut afile = guard.by_name(name)?;
+    ///Extract the specified file `name` to `destination` in `fs`
+    pub fn extract<P: AsRef<Path>>(& self, fs: & dyn FileSystem, name: &str, destination: P) -> Result<()> {
 
-        if name.as_bytes()[1] == 'd' as u8 {
+        let destination = destination.as_ref();
 
-            let mut temp = tempfile::tempfile().unwrap();
+        let mut guard = self.archive.lock().unwrap();
 
-            std::io::copy(& mut afile, & mut temp)?;
+        let mut afile = guard.by_name(self.blob(name))?;
 
-            temp.seek(SeekFrom::Start(0)).unwrap();
+        if name.starts_with("_d") {
+            //Folders are zips, which are read out of order, so they are checked before anything is extracted. Stored ones
+            //are read in place, and compressed ones (from older archives) are copied out first
+            let mut folder: Box<dyn ReadSeek> = if afile.compression() == CompressionMethod::Stored {
+                Box::new(self.window.slice(afile.data_start(), afile.compressed_size())?)
+            } else {
+                let mut temp = tempfile::tempfile()?;
+                std::io::copy(& mut afile, & mut temp).map_err(|e| corrupted(name, e))?;
+                Box::new(temp)
+            };
 
-            let mut archive = zip::ZipArchive::new(temp).unwrap();
+            folder.seek(SeekFrom::Start(0))?;
+            self.check(name, &ManifestEntry::of(& mut folder).map_err(|e| corrupted(name, e))?)?;
+            folder.seek(SeekFrom::Start(0))?;
 
-            std::fs::create_dir(destination.as_ref())?;
+            fs.create_dir(destination)?;
 
-            archive.extract(destination)?;
+            //Entries are checked as they are extracted, so a crafted archive can't write outside the destination
+            filesystem::extract_zip(fs, folder, destination)?;
 
             Ok(())
         } else {
+            //Files are checked as they are written
+            let mut reader = Hashing::new(& mut afile);
+
             //Create constraint has been removed for the inverse of the Edit step, which restores a file from an oak archive
             //and replace the original file
-            let mut dfile = OpenOptions::new().write(true).create(true).open(destination)?;
-            std::io::copy(& mut afile, & mut dfile)?;
+            let written = fs.write(destination, & mut reader);
 
-            Ok(())
+            let checked = match (written, reader.finish()) {
+                (_, Err(e)) => {Err(corrupted(name, e))}
+                (Err(e), Ok(_)) => {return Err(e)}
+                (Ok(()), Ok(actual)) => {self.check(name, &actual)}
+            };
+
+            //Nothing corrupted is left at the destination
+            if checked.is_err() {
+                fs.remove(destination)?;
+            }
+
+            checked
+        }
+
+
+    }
+}
+
+impl Payload for OakRead {
+    fn extract(&self, fs: & dyn FileSystem, name: &str, destination: &Path) -> Result<()> {
+        OakRead::extract(self, fs, name, destination)
+    }
+}
+
+fn corrupted<E: std::fmt::Display>(name: &str, e: E) -> Error {
+    Error::Corrupted(format!("{}: {}", name, e))
+}
+
+///Hashes everything read through it, so an entry can be checked against the manifest as it is used. Errors reading the
+///entry are kept, so they can be told apart from errors using what was read
+struct Hashing<R: Read> {
+    reader: R,
+    hasher: Sha256,
+    size: u64,
+    error: Option<String>,
+}
+
+impl<R: Read> Hashing<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            hasher: Sha256::new(),
+            size: 0,
+            error: None,
+        }
+    }
+
+    ///The hash and size of everything read, or why reading failed
+    fn finish(self) -> std::result::Result<ManifestEntry, String> {
+        match self.error {
+            Some(error) => {Err(error)}
+            None => {
+                Ok(ManifestEntry {
+                    sha256: format!("{:x}", self.hasher.finalize()),
+                    size: self.size,
+                })
+            }
         }
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(& mut self, buf: & mut [u8]) -> std::io::Result<usize> {
+        let read = self.reader.read(buf).inspect_err(|e| self.error = Some(e.to_string()))?;
 
+        self.hasher.update(&buf[..read]);
+        self.size += read as u64;
 
+        Ok(read)
     }
 }
 
-///A struct used to write to an oak archive
+///A struct used to write to an oak archive.
+///
+///Archived files and folders are stored as blobs named after the SHA-256 of their contents, so identical contents are only stored once.
+///The `_index` entry, written when the `OakWrite` is dropped, maps the name given to each archived file or folder to its blob.
+///The `_manifest` entry, written alongside it, records the hash and size of each so they can be verified on extraction
 pub struct OakWrite {
-    data: Mutex<(ZipWriter<std::fs::File>, u32)>,
+    data: Mutex<WriteState>,
 }
 
+///The archive being written, how many files and folders have been archived, and the index and manifest entries for them
+type WriteState = (ZipWriter<std::fs::File>, u32, BTreeMap<String, String>, BTreeMap<String, ManifestEntry>);
+
 impl OakWrite {
     ///Create a new oak archive and return an `OakWrite` object
     pub fn new<P: AsRef<Path>>(path: P) -> Self {
         Self {
-            data: Mutex::new((ZipWriter::new(OpenOptions::new().create_new(true).write(true).open(path.as_ref()).unwrap()), 0)),
+            data: Mutex::new((ZipWriter::new(OpenOptions::new().create_new(true).write(true).open(path.as_ref()).unwrap()), 0, BTreeMap::new(), BTreeMap::new())),
         }
     }
 
 
 
-    ///Archive a file or folder into the archive
-    pub fn archive<P: AsRef<Path>>(& self, path: P) -> String {
+    ///Archive a file or folder in `fs` into the archive, giving the name it is archived under
+    pub fn archive<P: AsRef<Path>>(& self, fs: & dyn FileSystem, path: P) -> Result<String> {
 
         let mut guard = self.data.lock().unwrap();
 
-        let (archive, count) = guard.deref_mut();
+        let (archive, count, index, manifest) = guard.deref_mut();
 
-        if path.as_ref().is_dir() {
+        if fs.is_dir(path.as_ref()) {
             //self.archive.add_directory(path.as_ref()., FileOptions::default());
 
-            let mut temp = tempfile::tempfile().unwrap();
+            let temp = tempfile::tempfile()?;
 
             //let mut temp = std::fs::OpenOptions::new().read(true).write(true).create(true).open("E:\\Software Projects\\IntelliJ\\project_oak\\tmp\\create.zip").unwrap();
 
-            {
-                let mut writer = ZipWriter::new(&temp);
-
-                writer.create_from_directory(&PathBuf::from(path.as_ref())).unwrap();
-
-            }
+            filesystem::write_zip(fs, path.as_ref(), &temp)?;
 
             let identifier = format!("_d_{}", count);
-            archive.start_file(identifier.clone(), FileOptions::default()).unwrap();
 
-            temp.seek(SeekFrom::Start(0)).unwrap();
+            store(archive, index, manifest, identifier.as_str(), temp, CompressionMethod::Stored)?;
+            *count += 1;
 
-            std::io::copy(& mut temp, archive).unwrap();
-            *count = *count + 1;
 
-
-            identifier
-        } else if path.as_ref().is_file() {
+            Ok(identifier)
+        } else if fs.is_file(path.as_ref()) {
             let identifier =format!("_{}", count);
-            archive.start_file(identifier.clone(), FileOptions::default()).unwrap();
-            let mut file  = OpenOptions::new().read(true).open(path.as_ref()).unwrap();
-            std::io::copy(& mut file, archive).unwrap();
-            *count = *count + 1;
-            identifier
+
+            let mut temp = tempfile::tempfile()?;
+            let mut file  = fs.open(path.as_ref())?;
+            std::io::copy(& mut file, & mut temp)?;
+
+            store(archive, index, manifest, identifier.as_str(), temp, CompressionMethod::Deflated)?;
+            *count += 1;
+            Ok(identifier)
         } else {
-            panic!("{:?} is not a file or folder", path.as_ref());
+            Err(Error::DoesntExist)
         }
 
 
@@ -217,7 +482,7 @@ impl OakWrite {
     pub fn info(& self, info: &Info) {
         let mut guard = self.data.lock().unwrap();
 
-        let (archive, _) = guard.deref_mut();
+        let (archive, _, _, _) = guard.deref_mut();
 
 
         archive.start_file("_info", FileOptions::default()).unwrap();
@@ -225,17 +490,58 @@ impl OakWrite {
 
     }
 
+    ///Write the payload declared in the source to the _payload section of the archive
+    pub fn payload(& self, payload: &BTreeMap<String, PayloadEntry>) {
+        let mut guard = self.data.lock().unwrap();
+
+        let (archive, _, _, _) = guard.deref_mut();
+
+        archive.start_file("_payload", FileOptions::default()).unwrap();
+        serde_json::to_writer(archive, payload).unwrap()
+    }
+
+    ///Write the code of the Lua modules scripts can `require` to the _modules section of the archive
+    pub fn modules(& self, modules: &BTreeMap<String, String>) {
+        let mut guard = self.data.lock().unwrap();
+
+        let (archive, _, _, _) = guard.deref_mut();
+
+        archive.start_file("_modules", FileOptions::default()).unwrap();
+        serde_json::to_writer(archive, modules).unwrap()
+    }
+
+    ///Write the names the urls fetched while building are archived under to the _downloads section of the archive
+    pub fn downloads(& self, downloads: &BTreeMap<String, String>) {
+        let mut guard = self.data.lock().unwrap();
+
+        let (archive, _, _, _) = guard.deref_mut();
+
+        archive.start_file("_downloads", FileOptions::default()).unwrap();
+        serde_json::to_writer(archive, downloads).unwrap()
+    }
+
     ///Write the commands list to the archive
     pub fn commands(& self, commands: & str) {
 
         let mut guard = self.data.lock().unwrap();
 
-        let (archive, _) = guard.deref_mut();
+        let (archive, _, _, _) = guard.deref_mut();
 
-        archive.start_file(format!("_commands"), FileOptions::default()).unwrap();
+        archive.start_file("_commands", FileOptions::default()).unwrap();
         archive.write_all(commands.as_bytes()).unwrap();
     }
 
+    ///Write the list of inverses to the archive
+    pub fn inverses(& self, inverses: &[InverseOp]) {
+
+        let mut guard = self.data.lock().unwrap();
+
+        let (archive, _, _, _) = guard.deref_mut();
+
+        archive.start_file("_inverses", FileOptions::default()).unwrap();
+        serde_json::to_writer(archive, inverses).unwrap()
+    }
+
     /*
     ///Finish the archive. This is called on `drop`
     pub fn finish(& mut self) -> Result<()> {
@@ -245,3 +551,41 @@ impl OakWrite {
     */
 
 }
+
+impl Drop for OakWrite {
+    fn drop(&mut self) {
+        //Errors can't be reported from here, and the archive is unusable without its index anyway
+        if let Ok((archive, _, index, manifest)) = self.data.get_mut() {
+            if archive.start_file("_index", FileOptions::default()).is_ok() {
+                let _ = serde_json::to_writer(& mut *archive, index);
+            }
+
+            if archive.start_file("_manifest", FileOptions::default()).is_ok() {
+                let _ = serde_json::to_writer(archive, manifest);
+            }
+        }
+    }
+}
+
+///Record `contents` in the index and manifest under `name`, and store it as a blob unless identical contents are already stored.
+///
+///Folders are zips already, and are stored uncompressed so they can be extracted in place
+fn store(archive: & mut ZipWriter<File>, index: & mut BTreeMap<String, String>, manifest: & mut BTreeMap<String, ManifestEntry>, name: &str, mut contents: File, compression: CompressionMethod) -> Result<()> {
+    contents.seek(SeekFrom::Start(0))?;
+
+    let entry = ManifestEntry::of(& mut contents)?;
+
+    let blob = format!("_b_{}", entry.sha256);
+
+    if !index.values().any(|stored| stored == &blob) {
+        archive.start_file(blob.clone(), FileOptions::default().compression_method(compression))?;
+
+        contents.seek(SeekFrom::Start(0))?;
+        std::io::copy(& mut contents, archive)?;
+    }
+
+    index.insert(name.to_string(), blob);
+    manifest.insert(name.to_string(), entry);
+
+    Ok(())
+}
@@ -1,6 +1,11 @@
-use std::path::PathBuf;
+use std::fmt::{Display, Formatter};
+use std::path::{Component, Path, PathBuf, Prefix};
 use std::sync::Mutex;
+use serde::{Serialize, Deserialize};
 use tempfile::TempDir;
+use crate::error::{Error, Result};
+use crate::journal::Journal;
+use crate::mlc::registry_ex::{Data, RootKey};
 
 
 #[derive(PartialEq, Eq, Clone, Debug)]
@@ -11,17 +16,20 @@ pub enum PathType {
 }
 
 impl PathType {
-    pub fn to_absolute_path(&self, temp: &TempDir) -> PathBuf {
+    ///Get the path on disk. If `root` is given, absolute and special paths are remapped under it (temporary paths never are).
+    ///Pass `None` as the root to get the logical path, which is what inverses record
+    pub fn to_absolute_path(&self, temp: &TempDir, root: Option<&Path>) -> PathBuf {
         match self {
             PathType::Absolute(path) => {
-                path.clone()
+                reroot(path, root)
             }
             PathType::Temporary(path) => {
-                temp.path().join(path)
+                //Checked by `enclosed` when created, but it can never leave the temporary folder either way
+                reroot(path, Some(temp.path()))
             }
             PathType::Special(special, path) => {
                 let special = PathBuf::from(std::env::var(special.to_str().unwrap()).unwrap());
-                special.join(&path)
+                reroot(special.join(path).as_path(), root)
             }
         }
 
@@ -37,26 +45,218 @@ impl PathType {
     }
 }
 
+///Remap the logical path `path` under `root`, if one is given. Drive letters and network shares become folders under `root`
+///(so `C:\Program Files` becomes `<root>\C\Program Files`), and `..` can never climb out of `root`
+pub fn reroot(path: &Path, root: Option<&Path>) -> PathBuf {
+    let root = match root {
+        None => {return path.to_path_buf()}
+        Some(root) => {root}
+    };
 
-pub struct Inverse(Mutex<Vec<String>>);
+    let mut parts: Vec<PathBuf> = Vec::new();
+
+    for component in path.components() {
+        match component {
+            Component::Prefix(prefix) => {
+                match prefix.kind() {
+                    Prefix::Disk(drive) | Prefix::VerbatimDisk(drive) => {parts.push(PathBuf::from((drive as char).to_string()))}
+                    Prefix::UNC(server, share) | Prefix::VerbatimUNC(server, share) => {
+                        parts.push(PathBuf::from("UNC"));
+                        parts.push(PathBuf::from(server));
+                        parts.push(PathBuf::from(share));
+                    }
+                    Prefix::Verbatim(name) | Prefix::DeviceNS(name) => {parts.push(PathBuf::from(name))}
+                }
+            }
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => {parts.pop();}
+            Component::Normal(part) => {parts.push(PathBuf::from(part))}
+        }
+    }
+
+    let mut rerooted = root.to_path_buf();
+
+    for part in parts {
+        rerooted.push(part);
+    }
+
+    rerooted
+}
+
+///Join the relative path `name` onto `base`, failing with [`Error::PathTraversal`] if it is absolute, names a drive or
+///stream, or climbs out of `base` with `..`. `\\` and `/` are both separators, as archives and scripts made on Windows may use either
+pub fn enclosed(base: &Path, name: &str) -> Result<PathBuf> {
+    let escapes = || Error::PathTraversal(format!("{:?} escapes {:?}", name, base));
+
+    if name.starts_with(['/', '\\']) {
+        return Err(escapes());
+    }
+
+    let mut parts: Vec<&str> = Vec::new();
+
+    for component in name.split(['/', '\\']) {
+        match component {
+            "" | "." => {}
+            ".." => {parts.pop().ok_or_else(escapes)?;}
+            _ if component.contains(':') => {return Err(escapes())}
+            _ => {parts.push(component)}
+        }
+    }
+
+    let mut path = base.to_path_buf();
+
+    for part in parts {
+        path.push(part);
+    }
+
+    Ok(path)
+}
+
+///Paths are stored as plain strings where possible, and as `OsString`s otherwise, so non UTF-8 paths survive the round trip
+pub mod os_path {
+    use std::ffi::OsString;
+    use std::path::{Path, PathBuf};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stored {
+        Str(String),
+        Os(OsString),
+    }
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        match path.to_str() {
+            Some(s) => {serializer.serialize_str(s)}
+            None => {path.as_os_str().serialize(serializer)}
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        match Stored::deserialize(deserializer)? {
+            Stored::Str(s) => {Ok(PathBuf::from(s))}
+            Stored::Os(s) => {Ok(PathBuf::from(s))}
+        }
+    }
+}
+
+///A single step of an uninstaller. Each one undoes a step performed by the installer
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum InverseOp {
+    ///Delete the file or folder at `path`
+    Delete {
+        #[serde(with = "os_path")]
+        path: PathBuf,
+    },
+
+    ///Move a file or folder from `source` to `destination`
+    Move {
+        #[serde(with = "os_path")]
+        source: PathBuf,
+        #[serde(with = "os_path")]
+        destination: PathBuf,
+    },
+
+    ///Extract the archived entry `name` to `destination`
+    Data {
+        name: String,
+        #[serde(with = "os_path")]
+        destination: PathBuf,
+    },
+
+    ///Create the registry key `key`
+    RegWriteKey {
+        root: RootKey,
+        key: String,
+    },
+
+    ///Delete the registry key `key` and everything under it
+    RegDeleteKey {
+        root: RootKey,
+        key: String,
+    },
+
+    ///Set the registry value `value` of `key` to `data`
+    RegWriteValue {
+        root: RootKey,
+        key: String,
+        value: String,
+        data: Data,
+    },
+
+    ///Delete the registry value `value` of `key`
+    RegDeleteValue {
+        root: RootKey,
+        key: String,
+        value: String,
+    },
+
+    ///Set the file attributes of `path`
+    SetAttributes {
+        #[serde(with = "os_path")]
+        path: PathBuf,
+        attributes: u32,
+    },
+}
+
+
+impl Display for InverseOp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InverseOp::Delete { path } => {write!(f, "Delete {:?}", path)}
+            InverseOp::Move { source, destination } => {write!(f, "Move {:?} to {:?}", source, destination)}
+            InverseOp::Data { name, destination } => {write!(f, "Restore {} to {:?}", name, destination)}
+            InverseOp::RegWriteKey { root, key } => {write!(f, "Create registry key {:?}\\{}", root, key)}
+            InverseOp::RegDeleteKey { root, key } => {write!(f, "Delete registry key {:?}\\{}", root, key)}
+            InverseOp::RegWriteValue { root, key, value, data } => {write!(f, "Set registry value {:?}\\{}\\{} to {:?}", root, key, value, data)}
+            InverseOp::RegDeleteValue { root, key, value } => {write!(f, "Delete registry value {:?}\\{}\\{}", root, key, value)}
+            InverseOp::SetAttributes { path, attributes } => {write!(f, "Set the attributes of {:?} to {}", path, attributes)}
+        }
+    }
+}
+
+
+pub struct Inverse {
+    ops: Mutex<Vec<InverseOp>>,
+
+    ///If present, every inverse is written to the journal before it is added to the list
+    journal: Option<Journal>,
+}
 
 impl Inverse {
 
     pub fn new() -> Self {
-        Self(Mutex::new(Vec::new()))
+        Self {
+            ops: Mutex::new(Vec::new()),
+            journal: None,
+        }
     }
 
-    pub fn insert(&self, index: usize, line: String) {
-        let mut guard = self.0.lock().unwrap();
-        guard.insert(index, line);
+    ///Create a list of inverses that is also written to `journal`
+    pub fn with_journal(journal: Journal) -> Self {
+        Self {
+            ops: Mutex::new(Vec::new()),
+            journal: Some(journal),
+        }
     }
 
-    pub fn combine(&self) -> String {
-        let guard = self.0.lock().unwrap();
+    ///Record an inverse. This must be called before the step it undoes changes anything, so the journal is never behind the machine
+    pub fn insert(&self, index: usize, op: InverseOp) -> Result<()> {
+        if let Some(journal) = &self.journal {
+            journal.record(index, &op)?;
+        }
+
+        let mut guard = self.ops.lock().unwrap();
+        guard.insert(index, op);
 
-        guard.iter().fold(String::new(), |mut source, line| { source.push_str(line); source.push_str("\n"); source })
+        Ok(())
     }
 
-}
+    ///Get a copy of the recorded inverses, in the order they should be executed
+    pub fn ops(&self) -> Vec<InverseOp> {
+        let guard = self.ops.lock().unwrap();
 
+        guard.clone()
+    }
 
+}
@@ -0,0 +1,192 @@
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use serde::Serialize;
+use crate::error::Result;
+use crate::mlc::registry_ex::{Data, RootKey};
+use crate::path_type::{os_path, Inverse, InverseOp};
+
+///A step that an installer would perform
+#[derive(Serialize, Clone, Debug)]
+pub enum Action {
+    Data {
+        name: String,
+        #[serde(with = "os_path")]
+        destination: PathBuf,
+    },
+    Move {
+        #[serde(with = "os_path")]
+        source: PathBuf,
+        #[serde(with = "os_path")]
+        destination: PathBuf,
+    },
+    Delete {
+        #[serde(with = "os_path")]
+        path: PathBuf,
+    },
+    Copy {
+        #[serde(with = "os_path")]
+        source: PathBuf,
+        #[serde(with = "os_path")]
+        destination: PathBuf,
+    },
+    Mkdir {
+        #[serde(with = "os_path")]
+        path: PathBuf,
+    },
+    CreateSymlink {
+        #[serde(with = "os_path")]
+        original: PathBuf,
+        #[serde(with = "os_path")]
+        link: PathBuf,
+    },
+    SetAttributes {
+        #[serde(with = "os_path")]
+        path: PathBuf,
+        attributes: u32,
+    },
+    Zip {
+        #[serde(with = "os_path")]
+        archive: PathBuf,
+        #[serde(with = "os_path")]
+        folder: PathBuf,
+    },
+    Unzip {
+        #[serde(with = "os_path")]
+        archive: PathBuf,
+        #[serde(with = "os_path")]
+        folder: PathBuf,
+    },
+    Download {
+        url: String,
+        #[serde(with = "os_path")]
+        destination: PathBuf,
+    },
+    Edit {
+        #[serde(with = "os_path")]
+        path: PathBuf,
+        command: String,
+    },
+    FileOpen {
+        #[serde(with = "os_path")]
+        path: PathBuf,
+        mode: String,
+    },
+    RegWriteKey {
+        root: RootKey,
+        key: String,
+    },
+    RegDeleteKey {
+        root: RootKey,
+        key: String,
+    },
+    RegWriteValue {
+        root: RootKey,
+        key: String,
+        value: String,
+        data: Data,
+    },
+    RegDeleteValue {
+        root: RootKey,
+        key: String,
+        value: String,
+    },
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Data { name, destination } => {write!(f, "Extract {} to {:?}", name, destination)}
+            Action::Move { source, destination } => {write!(f, "Move {:?} to {:?}", source, destination)}
+            Action::Delete { path } => {write!(f, "Delete {:?}", path)}
+            Action::Copy { source, destination } => {write!(f, "Copy {:?} to {:?}", source, destination)}
+            Action::Mkdir { path } => {write!(f, "Create folder {:?}", path)}
+            Action::CreateSymlink { original, link } => {write!(f, "Create link {:?} to {:?}", link, original)}
+            Action::SetAttributes { path, attributes } => {write!(f, "Set the attributes of {:?} to {}", path, attributes)}
+            Action::Zip { archive, folder } => {write!(f, "Zip {:?} into {:?}", folder, archive)}
+            Action::Unzip { archive, folder } => {write!(f, "Unzip {:?} into {:?}", archive, folder)}
+            Action::Download { url, destination } => {write!(f, "Download {} to {:?}", url, destination)}
+            Action::Edit { path, command } => {write!(f, "Edit {:?} with {:?}", path, command)}
+            Action::FileOpen { path, mode } => {write!(f, "Open {:?} with mode {:?}", path, mode)}
+            Action::RegWriteKey { root, key } => {write!(f, "Create registry key {:?}\\{}", root, key)}
+            Action::RegDeleteKey { root, key } => {write!(f, "Delete registry key {:?}\\{}", root, key)}
+            Action::RegWriteValue { root, key, value, data } => {write!(f, "Set registry value {:?}\\{}\\{} to {:?}", root, key, value, data)}
+            Action::RegDeleteValue { root, key, value } => {write!(f, "Delete registry value {:?}\\{}\\{}", root, key, value)}
+        }
+    }
+}
+
+///A planned step, and the inverses that would undo it
+#[derive(Serialize, Clone, Debug)]
+pub struct Step {
+    pub action: Action,
+    pub inverses: Vec<InverseOp>,
+}
+
+///The steps an installer would take, recorded by running it in plan mode.
+///
+///In plan mode each function checks its preconditions and records what it would do instead of doing it.
+///Steps that only touch temporary paths still run, so that later steps see their results.
+///Preconditions are checked against the machine as it is now, so a step that depends on an earlier
+///(skipped) step may fail while planning.
+pub struct Plan {
+    ///The steps so far, and how many of the recorded inverses have already been given to a step
+    steps: Mutex<(Vec<Step>, usize)>,
+}
+
+impl Plan {
+
+    pub fn new() -> Self {
+        Self {
+            steps: Mutex::new((Vec::new(), 0)),
+        }
+    }
+
+    ///Record a step, along with the inverses recorded for it. Functions insert their inverses at the front
+    ///of the list, so a step's inverses are the ones at the front that no earlier step has claimed
+    pub fn record(&self, action: Action, inverses: Option<&Inverse>) {
+        let mut guard = self.steps.lock().unwrap();
+
+        let (steps, claimed) = &mut *guard;
+
+        let inverses = match inverses {
+            None => {Vec::new()}
+            Some(list) => {
+                let ops = list.ops();
+                let new = ops.len() - *claimed;
+
+                *claimed = ops.len();
+
+                ops[..new].to_vec()
+            }
+        };
+
+        steps.push(Step { action, inverses });
+    }
+
+    ///Get a copy of the planned steps, in the order they would run
+    pub fn steps(&self) -> Vec<Step> {
+        let guard = self.steps.lock().unwrap();
+
+        guard.0.clone()
+    }
+
+    ///Get the plan as JSON
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(&self.steps())?)
+    }
+}
+
+impl Display for Plan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, step) in self.steps().iter().enumerate() {
+            writeln!(f, "{}. {}", i + 1, step.action)?;
+
+            for inverse in &step.inverses {
+                writeln!(f, "    undo: {}", inverse)?;
+            }
+        }
+
+        Ok(())
+    }
+}
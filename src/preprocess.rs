@@ -0,0 +1,301 @@
+use std::path::{Path, PathBuf};
+use crate::error::{Error, Result};
+
+///Find the payload references in a script and replace each of them with Lua code.
+///
+///A payload reference is a Lua string literal wrapped in `$`, such as `$"C:\\data\\file.txt"$`, and is only recognised where
+///Lua code is expected, so a `$` inside a string or comment is left alone. `$$` is replaced with a literal `$`.
+///
+///`replace` is given the path of each reference and returns the Lua code to put in its place, or a message explaining why
+///it can't. Malformed references and failed replacements are reported with `name` and the line and column of the reference.
+pub fn expand<F: FnMut(&str) -> std::result::Result<String, String>>(source: &str, name: &str, mut replace: F) -> Result<String> {
+
+    let mut tokens = Tokens { source, position: 0 };
+
+    let mut expanded = String::with_capacity(source.len());
+
+    //Start of the source that has been read but not yet copied to `expanded`
+    let mut copied = 0;
+
+    while let Some(c) = tokens.peek() {
+        let start = tokens.position;
+
+        match c {
+            '-' if tokens.starts_with("--") => {
+                tokens.advance(2);
+
+                match tokens.long_bracket() {
+                    Some(level) => {tokens.skip_long(level)}
+                    None => {tokens.skip_line()}
+                }
+            }
+            '[' if tokens.long_bracket().is_some() => {
+                let level = tokens.long_bracket().unwrap();
+                tokens.skip_long(level);
+            }
+            '"' | '\'' => {
+                tokens.advance(1);
+
+                //An unfinished string is a Lua syntax error, which is left for Lua to report
+                let _ = tokens.string(c);
+            }
+            '$' => {
+                expanded.push_str(&source[copied..start]);
+
+                tokens.advance(1);
+
+                if tokens.peek() == Some('$') {
+                    tokens.advance(1);
+                    expanded.push('$');
+                } else {
+                    let path = tokens.reference().map_err(|message| error(source, name, start, message.as_str()))?;
+
+                    expanded.push_str(replace(path.as_str()).map_err(|message| error(source, name, start, message.as_str()))?.as_str());
+                }
+
+                copied = tokens.position;
+            }
+            _ => {tokens.advance(c.len_utf8())}
+        }
+    }
+
+    expanded.push_str(&source[copied..]);
+
+    Ok(expanded)
+}
+
+///Characters that make a payload reference a glob pattern
+const PATTERN_CHARACTERS: [char; 3] = ['*', '?', '['];
+
+///Is the path of a payload reference a glob pattern, rather than a single file or folder
+pub fn is_pattern(path: &str) -> bool {
+    path.contains(PATTERN_CHARACTERS)
+}
+
+///Get the folder a pattern searches in: everything before the first part that contains a pattern character
+pub fn pattern_base(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|component| !component.as_os_str().to_string_lossy().contains(PATTERN_CHARACTERS))
+        .collect()
+}
+
+///Write `s` as a Lua string literal
+pub fn lua_string(s: &str) -> String {
+    let mut literal = String::with_capacity(s.len() + 2);
+
+    literal.push('"');
+
+    for c in s.chars() {
+        match c {
+            '\\' => {literal.push_str("\\\\")}
+            '"' => {literal.push_str("\\\"")}
+            '\n' => {literal.push_str("\\n")}
+            '\r' => {literal.push_str("\\r")}
+            '\0' => {literal.push_str("\\0")}
+            _ => {literal.push(c)}
+        }
+    }
+
+    literal.push('"');
+
+    literal
+}
+
+///A piece of Lua code, as far as checking it needs
+#[derive(Debug, PartialEq)]
+pub enum Token {
+    Name(String),
+    ///The value of a string, or `None` if it has escapes that aren't decoded
+    String(Option<String>),
+    Number,
+    Symbol(char),
+}
+
+///Split Lua code into tokens, each with its byte offset, skipping whitespace and comments
+pub fn tokenize(source: &str) -> Vec<(usize, Token)> {
+    let mut tokens = Tokens { source, position: 0 };
+
+    let mut list = Vec::new();
+
+    while let Some(c) = tokens.peek() {
+        let start = tokens.position;
+
+        match c {
+            '-' if tokens.starts_with("--") => {
+                tokens.advance(2);
+
+                match tokens.long_bracket() {
+                    Some(level) => {tokens.skip_long(level)}
+                    None => {tokens.skip_line()}
+                }
+            }
+            '[' if tokens.long_bracket().is_some() => {
+                let level = tokens.long_bracket().unwrap();
+                tokens.skip_long(level);
+
+                //Strip the brackets, and the newline Lua skips after the opening one
+                let end = tokens.position.saturating_sub(level + 2).max(start + level + 2);
+                let value = &source[start + level + 2..end];
+
+                list.push((start, Token::String(Some(value.strip_prefix('\n').unwrap_or(value).to_string()))));
+            }
+            '"' | '\'' => {
+                tokens.advance(1);
+                list.push((start, Token::String(tokens.string(c).ok())));
+            }
+            _ if c.is_ascii_alphabetic() || c == '_' => {
+                let name = tokens.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+                list.push((start, Token::Name(name)));
+            }
+            _ if c.is_ascii_digit() => {
+                tokens.take_while(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_');
+                list.push((start, Token::Number));
+            }
+            _ if c.is_whitespace() => {tokens.advance(c.len_utf8())}
+            _ => {
+                tokens.advance(c.len_utf8());
+                list.push((start, Token::Symbol(c)));
+            }
+        }
+    }
+
+    list
+}
+
+///Get the line and column of the byte `offset` in `source`, both counting from 1
+pub fn location(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+
+    let line = before.matches('\n').count() + 1;
+    let column = before[before.rfind('\n').map(|i| i + 1).unwrap_or(0)..].chars().count() + 1;
+
+    (line, column)
+}
+
+fn error(source: &str, name: &str, offset: usize, message: &str) -> Error {
+    let (line, column) = location(source, offset);
+
+    Error::Preprocess(format!("{}:{}:{}: {}", name, line, column, message))
+}
+
+struct Tokens<'a> {
+    source: &'a str,
+    position: usize,
+}
+
+impl Tokens<'_> {
+    fn rest(&self) -> &str {
+        &self.source[self.position..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        self.rest().starts_with(s)
+    }
+
+    fn advance(& mut self, bytes: usize) {
+        self.position = std::cmp::min(self.position + bytes, self.source.len());
+    }
+
+    ///If a long bracket (`[[`, `[=[`, ...) starts here, get its level
+    fn long_bracket(&self) -> Option<usize> {
+        let rest = self.rest().strip_prefix('[')?;
+        let level = rest.len() - rest.trim_start_matches('=').len();
+
+        if rest[level..].starts_with('[') {
+            Some(level)
+        } else {
+            None
+        }
+    }
+
+    ///Skip a long string or comment, including its brackets
+    fn skip_long(& mut self, level: usize) {
+        self.advance(level + 2);
+
+        let close = format!("]{}]", "=".repeat(level));
+
+        match self.rest().find(close.as_str()) {
+            Some(end) => {self.advance(end + close.len())}
+            None => {self.position = self.source.len()}
+        }
+    }
+
+    ///Read characters while `f` holds
+    fn take_while<F: Fn(char) -> bool>(& mut self, f: F) -> String {
+        let taken: String = self.rest().chars().take_while(|c| f(*c)).collect();
+        self.advance(taken.len());
+        taken
+    }
+
+    fn skip_line(& mut self) {
+        match self.rest().find('\n') {
+            Some(end) => {self.advance(end)}
+            None => {self.position = self.source.len()}
+        }
+    }
+
+    ///Read the rest of a short string after its opening `quote`, decoding its escapes.
+    ///
+    ///The whole string is read even if it has an escape that isn't supported, so the code after it is still found
+    fn string(& mut self, quote: char) -> std::result::Result<String, String> {
+        let mut value = String::new();
+        let mut unsupported = None;
+
+        while let Some(c) = self.peek() {
+            self.advance(c.len_utf8());
+
+            match c {
+                '\\' => {
+                    let escaped = self.peek().ok_or("unfinished escape sequence")?;
+                    self.advance(escaped.len_utf8());
+
+                    match escaped {
+                        'n' => {value.push('\n')}
+                        't' => {value.push('\t')}
+                        '\\' | '"' | '\'' => {value.push(escaped)}
+                        _ => {unsupported = unsupported.or(Some(format!("unsupported escape sequence \\{}", escaped)))}
+                    }
+                }
+                '\n' => {return Err("unfinished string".to_string());}
+                _ if c == quote => {
+                    return match unsupported {
+                        Some(message) => {Err(message)}
+                        None => {Ok(value)}
+                    };
+                }
+                _ => {value.push(c)}
+            }
+        }
+
+        Err("unfinished string".to_string())
+    }
+
+    ///Read a payload reference after its opening `$`
+    fn reference(& mut self) -> std::result::Result<String, String> {
+        let quote = match self.peek() {
+            Some(quote @ ('"' | '\'')) => {quote}
+            _ => {return Err("expected a quoted path after `$` (use `$$` for a literal `$`)".to_string());}
+        };
+
+        self.advance(1);
+
+        let path = self.string(quote).map_err(|message| format!("{} in payload reference", message))?;
+
+        if self.peek() != Some('$') {
+            return Err("expected `$` to close the payload reference".to_string());
+        }
+
+        self.advance(1);
+
+        if path.is_empty() {
+            return Err("empty payload reference".to_string());
+        }
+
+        Ok(path)
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use serde::{Serialize, Deserialize};
+use crate::error::{Error, Result};
+use crate::mlc::registry_ex::{Data, RootKey};
+
+///The registry operations used by the installer functions.
+///
+///Keys are given relative to their root key, with `\` separating each part (e.g. `SOFTWARE\example`).
+pub trait RegistryBackend {
+    fn key_exists(&self, root: &RootKey, key: &str) -> bool;
+
+    ///Create a key, along with any missing ancestors
+    fn create_key(&self, root: &RootKey, key: &str) -> Result<()>;
+
+    ///Delete a key and everything under it
+    fn delete_key(&self, root: &RootKey, key: &str) -> Result<()>;
+
+    ///List the names of the keys directly under a key
+    fn subkeys(&self, root: &RootKey, key: &str) -> Result<Vec<String>>;
+
+    ///List the names and data of the values of a key
+    fn values(&self, root: &RootKey, key: &str) -> Result<Vec<(String, Data)>>;
+
+    ///Get a value of a key, or `None` if the key has no such value. The key itself must exist
+    fn value(&self, root: &RootKey, key: &str, value: &str) -> Result<Option<Data>>;
+
+    ///Create or replace a value of an existing key
+    fn set_value(&self, root: &RootKey, key: &str, value: &str, data: &Data) -> Result<()>;
+
+    fn delete_value(&self, root: &RootKey, key: &str, value: &str) -> Result<()>;
+}
+
+///The real Windows registry
+#[cfg(windows)]
+pub struct WindowsRegistry;
+
+#[cfg(windows)]
+impl RegistryBackend for WindowsRegistry {
+    fn key_exists(&self, root: &RootKey, key: &str) -> bool {
+        registry::Hive::from(root).open(key, registry::Security::Read).is_ok()
+    }
+
+    fn create_key(&self, root: &RootKey, key: &str) -> Result<()> {
+        registry::Hive::from(root).create(key, registry::Security::AllAccess)?;
+        Ok(())
+    }
+
+    fn delete_key(&self, root: &RootKey, key: &str) -> Result<()> {
+        let reg = registry::Hive::from(root).open(key, registry::Security::AllAccess)?;
+
+        reg.delete("", true)?; //Delete the contents of the key
+        reg.delete_self(false)?; //Delete the key itself
+
+        Ok(())
+    }
+
+    fn subkeys(&self, root: &RootKey, key: &str) -> Result<Vec<String>> {
+        let reg = registry::Hive::from(root).open(key, registry::Security::Read)?;
+
+        let mut names = Vec::new();
+
+        for subkey in reg.keys() {
+            names.push(subkey?.to_string());
+        }
+
+        Ok(names)
+    }
+
+    fn values(&self, root: &RootKey, key: &str) -> Result<Vec<(String, Data)>> {
+        let reg = registry::Hive::from(root).open(key, registry::Security::Read)?;
+
+        let mut values = Vec::new();
+
+        for value in reg.values() {
+            let value = value?;
+            values.push((value.name().to_string_lossy(), Data::from(value.data().clone())));
+        }
+
+        Ok(values)
+    }
+
+    fn value(&self, root: &RootKey, key: &str, value: &str) -> Result<Option<Data>> {
+        let reg = registry::Hive::from(root).open(key, registry::Security::Read)?;
+
+        match reg.value(value) {
+            Ok(data) => {Ok(Some(Data::from(data)))}
+            Err(registry::value::Error::NotFound(_, _)) => {Ok(None)}
+            Err(e) => {Err(Error::from(e))}
+        }
+    }
+
+    fn set_value(&self, root: &RootKey, key: &str, value: &str, data: &Data) -> Result<()> {
+        let reg = registry::Hive::from(root).open(key, registry::Security::AllAccess)?;
+        reg.set_value(value, &registry::Data::from(data))?;
+        Ok(())
+    }
+
+    fn delete_value(&self, root: &RootKey, key: &str, value: &str) -> Result<()> {
+        let reg = registry::Hive::from(root).open(key, registry::Security::AllAccess)?;
+        reg.delete_value(value)?;
+        Ok(())
+    }
+}
+
+///A key of the emulated registry
+#[derive(Serialize, Deserialize, Default)]
+struct Key {
+    #[serde(default)]
+    values: BTreeMap<String, Data>,
+    #[serde(default)]
+    keys: BTreeMap<String, Key>,
+}
+
+///Registry names are case insensitive
+fn same_name(a: &str, b: &str) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
+
+///Find the actual name of an entry in `map`, ignoring case
+fn find_name<T>(map: &BTreeMap<String, T>, name: &str) -> Option<String> {
+    map.keys().find(|n| same_name(n, name)).cloned()
+}
+
+fn parts(key: &str) -> impl Iterator<Item = &str> {
+    key.split('\\').filter(|part| !part.is_empty())
+}
+
+impl Key {
+    fn find(&self, key: &str) -> Option<&Key> {
+        let mut current = self;
+
+        for part in parts(key) {
+            current = current.keys.get(&find_name(&current.keys, part)?)?;
+        }
+
+        Some(current)
+    }
+
+    fn find_mut(&mut self, key: &str) -> Option<&mut Key> {
+        let mut current = self;
+
+        for part in parts(key) {
+            let name = find_name(&current.keys, part)?;
+            current = current.keys.get_mut(&name)?;
+        }
+
+        Some(current)
+    }
+}
+
+///A portable registry emulated in memory, and optionally persisted to a JSON file after every change.
+///
+///This lets the registry functions (and their inverses) run on machines without a Windows registry,
+///and lets installers target a virtual hive instead of the real one.
+pub struct EmulatedRegistry {
+    file: Option<PathBuf>,
+    hives: Mutex<BTreeMap<RootKey, Key>>,
+}
+
+impl EmulatedRegistry {
+
+    ///Create an empty registry that is only held in memory
+    #[cfg(any(test, not(windows)))]
+    pub fn new() -> Self {
+        Self {
+            file: None,
+            hives: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    ///Open the registry persisted at `file`, or create an empty one there if it doesn't exist
+    pub fn open<P: AsRef<Path>>(file: P) -> Result<Self> {
+        let hives = if file.as_ref().exists() {
+            serde_json::from_reader(std::fs::File::open(file.as_ref())?)?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(Self {
+            file: Some(file.as_ref().to_path_buf()),
+            hives: Mutex::new(hives),
+        })
+    }
+
+    ///Make a change to a hive, then persist the registry if it has a file
+    fn modify<T, F: FnOnce(&mut Key) -> Result<T>>(&self, root: &RootKey, f: F) -> Result<T> {
+        let mut guard = self.hives.lock().unwrap();
+
+        let res = f(guard.entry(root.clone()).or_default())?;
+
+        if let Some(file) = &self.file {
+            std::fs::write(file, serde_json::to_string_pretty(&*guard)?)?;
+        }
+
+        Ok(res)
+    }
+
+    ///Read from a key
+    fn read<T, F: FnOnce(&Key) -> T>(&self, root: &RootKey, key: &str, f: F) -> Result<T> {
+        let guard = self.hives.lock().unwrap();
+
+        let empty = Key::default();
+
+        let hive = guard.get(root).unwrap_or(&empty);
+
+        Ok(f(hive.find(key).ok_or(Error::DoesntExist)?))
+    }
+}
+
+impl RegistryBackend for EmulatedRegistry {
+    fn key_exists(&self, root: &RootKey, key: &str) -> bool {
+        self.read(root, key, |_| ()).is_ok()
+    }
+
+    fn create_key(&self, root: &RootKey, key: &str) -> Result<()> {
+        self.modify(root, |hive| {
+            let mut current = hive;
+
+            for part in parts(key) {
+                let name = find_name(&current.keys, part).unwrap_or(part.to_string());
+                current = current.keys.entry(name).or_default();
+            }
+
+            Ok(())
+        })
+    }
+
+    fn delete_key(&self, root: &RootKey, key: &str) -> Result<()> {
+        let (parent, name) = key.trim_end_matches('\\').rsplit_once('\\').unwrap_or(("", key));
+
+        self.modify(root, |hive| {
+            let parent = hive.find_mut(parent).ok_or(Error::DoesntExist)?;
+            let name = find_name(&parent.keys, name).ok_or(Error::DoesntExist)?;
+
+            parent.keys.remove(&name);
+
+            Ok(())
+        })
+    }
+
+    fn subkeys(&self, root: &RootKey, key: &str) -> Result<Vec<String>> {
+        self.read(root, key, |k| k.keys.keys().cloned().collect())
+    }
+
+    fn values(&self, root: &RootKey, key: &str) -> Result<Vec<(String, Data)>> {
+        self.read(root, key, |k| k.values.iter().map(|(name, data)| (name.clone(), data.clone())).collect())
+    }
+
+    fn value(&self, root: &RootKey, key: &str, value: &str) -> Result<Option<Data>> {
+        self.read(root, key, |k| find_name(&k.values, value).map(|name| k.values[&name].clone()))
+    }
+
+    fn set_value(&self, root: &RootKey, key: &str, value: &str, data: &Data) -> Result<()> {
+        self.modify(root, |hive| {
+            let k = hive.find_mut(key).ok_or(Error::DoesntExist)?;
+
+            //Keep the existing name if the value is being replaced
+            let name = find_name(&k.values, value).unwrap_or(value.to_string());
+            k.values.insert(name, data.clone());
+
+            Ok(())
+        })
+    }
+
+    fn delete_value(&self, root: &RootKey, key: &str, value: &str) -> Result<()> {
+        self.modify(root, |hive| {
+            let k = hive.find_mut(key).ok_or(Error::DoesntExist)?;
+            let name = find_name(&k.values, value).ok_or(Error::DoesntExist)?;
+
+            k.values.remove(&name);
+
+            Ok(())
+        })
+    }
+}
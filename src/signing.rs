@@ -0,0 +1,145 @@
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use crate::error::{Error, Result};
+
+//A malformed pinned key fails the build of the stub, rather than every install
+const _: () = assert!(match option_env!("OAK_PUBLIC_KEY") {
+    Some(hex) => {is_hex_key(hex)}
+    None => {true}
+}, "OAK_PUBLIC_KEY must be 64 hex digits");
+
+///The public key installers must be signed with.
+///
+///It is pinned when the stub is built, by setting the `OAK_PUBLIC_KEY` environment variable to its hex encoding.
+///Stubs built without it run unsigned installers.
+pub fn pinned_key() -> Result<Option<VerifyingKey>> {
+    option_env!("OAK_PUBLIC_KEY").map(public_key).transpose()
+}
+
+///Generate a new private key, save it to `path`, and return its public key
+pub fn generate_key(path: &Path) -> Result<VerifyingKey> {
+    let key = SigningKey::generate(& mut OsRng);
+
+    //Never overwrite an existing key
+    OpenOptions::new().write(true).create_new(true).open(path)?.write_all(&key.to_bytes())?;
+
+    Ok(key.verifying_key())
+}
+
+///Load a private key saved by [`generate_key`]
+pub fn load_key(path: &Path) -> Result<SigningKey> {
+    let bytes: [u8; 32] = std::fs::read(path)?
+        .try_into()
+        .map_err(|_| Error::Signature(format!("{:?} is not an ed25519 private key", path)))?;
+
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+///Sign the archive read from `archive`
+pub fn sign<R: Read>(archive: R, key: &SigningKey) -> Result<Signature> {
+    Ok(key.sign(&digest(archive)?))
+}
+
+///Check that the archive read from `archive` was signed by `key`
+pub fn verify<R: Read>(archive: R, signature: Option<&Signature>, key: &VerifyingKey) -> Result<()> {
+    verify_digest(&digest(archive)?, signature, key)
+}
+
+///Check that the archive with the SHA-256 `sha256` was signed by `key`
+pub fn verify_digest(sha256: &[u8; 32], signature: Option<&Signature>, key: &VerifyingKey) -> Result<()> {
+    let signature = signature.ok_or(Error::Signature("the archive isn't signed".to_string()))?;
+
+    key.verify(sha256, signature)
+        .map_err(|_| Error::Signature("the archive has been tampered with, or was signed with a different key".to_string()))
+}
+
+///Get the location of the SHA-256 an installer records for the uninstaller it wrote to `uninstaller`
+pub fn digest_location<P: AsRef<Path>>(uninstaller: P) -> PathBuf {
+    let mut path = uninstaller.as_ref().as_os_str().to_os_string();
+    path.push(".sha256");
+    PathBuf::from(path)
+}
+
+///Record the SHA-256 of the uninstaller archive read from `archive`, next to the uninstaller at `uninstaller`
+pub fn record<R: Read>(archive: R, uninstaller: &Path) -> Result<()> {
+    std::fs::write(digest_location(uninstaller), to_hex(&digest(archive)?))?;
+
+    Ok(())
+}
+
+///Check that the archive read from `archive` is the one recorded for the uninstaller at `uninstaller`
+pub fn check_recorded<R: Read>(archive: R, uninstaller: &Path) -> Result<()> {
+    check_recorded_digest(&digest(archive)?, uninstaller)
+}
+
+///Check that the archive with the SHA-256 `sha256` is the one recorded for the uninstaller at `uninstaller`
+pub fn check_recorded_digest(sha256: &[u8; 32], uninstaller: &Path) -> Result<()> {
+    let recorded = std::fs::read_to_string(digest_location(uninstaller))
+        .map_err(|_| Error::Signature("no installer recorded this uninstaller".to_string()))?;
+
+    if from_hex(recorded.as_str()) != Some(*sha256) {
+        return Err(Error::Signature("the uninstaller isn't the one its installer wrote".to_string()));
+    }
+
+    Ok(())
+}
+
+///Parse a public key printed by `new-key`
+pub fn public_key(hex: &str) -> Result<VerifyingKey> {
+    from_hex(hex)
+        .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+        .ok_or(Error::Signature(format!("{} is not an ed25519 public key", hex)))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let hex = hex.trim();
+
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut bytes = [0u8; N];
+
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+
+    Some(bytes)
+}
+
+const fn is_hex_key(hex: &str) -> bool {
+    let bytes = hex.as_bytes();
+
+    if bytes.len() != 64 {
+        return false;
+    }
+
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if !bytes[i].is_ascii_hexdigit() {
+            return false;
+        }
+
+        i += 1;
+    }
+
+    true
+}
+
+///Archives can be large, so their SHA-256 is signed rather than the archive itself
+fn digest<R: Read>(mut archive: R) -> Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+
+    std::io::copy(& mut archive, & mut hasher)?;
+
+    Ok(hasher.finalize().into())
+}
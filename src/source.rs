@@ -1,29 +1,111 @@
 use std::fs::OpenOptions;
-use std::path::{Path};
+use std::path::{Path, PathBuf};
 use serde::{Serialize, Deserialize};
 use crate::{hlc, Info};
+use crate::error::{Error, Result};
 
 ///Struct containing all the necessary information to create an installer
 #[derive(Serialize, Deserialize)]
 pub struct Source {
     ///Lua code to add to the installer
-    code: String,
+    #[serde(default)]
+    pub(crate) code: String,
+
+    ///A Lua file to load the code from instead, relative to the source file
+    #[serde(default)]
+    pub(crate) script: Option<PathBuf>,
 
     ///Extra data to include in the archive
-    info: crate::oak::Info,
+    #[serde(default)]
+    pub(crate) info: crate::oak::Info,
+
+    ///A folder of Lua modules the code can `require`, relative to the source file
+    #[serde(default)]
+    pub(crate) modules: Option<PathBuf>,
+
+    ///Files to include in the archive, which the code finds by name in `oak.payload`
+    #[serde(default)]
+    pub(crate) files: Files,
+
+    ///Fetch the urls the code downloads when building, so the installer works without a network
+    #[serde(default)]
+    pub(crate) offline: bool,
+
+    ///The file the code was loaded from, used to locate errors in the code
+    #[serde(skip)]
+    pub(crate) path: PathBuf,
+
+    ///The folder relative payload paths are resolved against, which is the one holding the source file
+    #[serde(skip)]
+    pub(crate) base: PathBuf,
+}
+
+///The `<files>` section of a source
+#[derive(Serialize, Deserialize, Default)]
+pub struct Files {
+    #[serde(default)]
+    pub file: Vec<PayloadFile>,
+}
+
+///A file or folder to include in the archive, such as `<file name="app" path="build/app.exe"/>`.
+///
+///If any `<include>` or `<exclude>` patterns are given, `path` is a folder and only the files in it matching an include
+///pattern (or any file, if there are none) and no exclude pattern are included. Patterns are relative to `path`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PayloadFile {
+    ///The name the code uses to find the file in `oak.payload`
+    pub name: String,
+
+    ///The path of the file or folder on the build machine
+    pub path: PathBuf,
+
+    #[serde(default)]
+    pub include: Vec<String>,
+
+    #[serde(default)]
+    pub exclude: Vec<String>,
 }
 
 impl Source {
 
-    ///Take a source struct and create an installer
-    pub fn create_installer(&self,  path: &Path) {
-        hlc::create_installer(self.code.as_str(), path, &self.info).unwrap()
+    ///Take a source struct and create an installer, giving the urls fetched for it
+    pub fn create_installer(&self,  path: &Path) -> Result<Vec<String>> {
+        hlc::create_installer(self, path)
     }
 
-    pub fn load_from_path(path: &Path) -> Self {
-        let file = OpenOptions::new().read(true).open(path).unwrap();
+    ///Load a source file. Files ending in `.toml` are TOML, `.json` are JSON, and anything else is XML
+    pub fn load_from_path(path: &Path) -> Result<Self> {
+        let invalid = |e: &dyn std::fmt::Display| Error::Source(format!("{:?}: {}", path, e));
+
+        let extension = path.extension().map(|e| e.to_string_lossy().to_ascii_lowercase());
+
+        let mut source: Self = match extension.as_deref() {
+            Some("toml") => {toml::from_str(std::fs::read_to_string(path)?.as_str()).map_err(|e| invalid(&e))?}
+            Some("json") => {serde_json::from_reader(OpenOptions::new().read(true).open(path)?).map_err(|e| invalid(&e))?}
+            _ => {serde_xml_rs::from_reader(OpenOptions::new().read(true).open(path)?).map_err(|e| invalid(&e))?}
+        };
+
+        source.base = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        source.path = path.to_path_buf();
+
+        if let Some(script) = source.script.as_ref() {
+            if !source.code.trim().is_empty() {
+                return Err(invalid(&"has both code and a script"));
+            }
+
+            let script = source.base.join(script);
+
+            source.code = std::fs::read_to_string(script.as_path()).map_err(|e| Error::Source(format!("{:?}: {}", script, e)))?;
+            source.path = script;
+        }
+
+        source.modules = source.modules.map(|modules| source.base.join(modules));
+
+        for file in source.files.file.iter_mut() {
+            file.path = source.base.join(file.path.as_path());
+        }
 
-        serde_xml_rs::from_reader(file).unwrap()
+        Ok(source)
     }
 
 }
@@ -32,7 +114,13 @@ impl Default for Source {
     fn default() -> Self {
         Self {
             code: "".to_string(),
-            info: Info::default()
+            script: None,
+            modules: None,
+            info: Info::default(),
+            files: Files::default(),
+            offline: false,
+            path: PathBuf::new(),
+            base: PathBuf::new(),
         }
     }
 }
@@ -1,17 +1,37 @@
 
+#[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
     use std::io::Write;
     use std::path::{Path, PathBuf};
-    use registry::{Hive, Security};
     use tempfile::TempDir;
     use crate::hlc;
-    use crate::oak::{Info, UninstallLocation};
+    use crate::oak::{Info, OakRead, UninstallLocation};
+    use crate::mlc::registry_ex::{Data, RootKey};
+    use crate::registry_backend::{EmulatedRegistry, RegistryBackend};
+    use crate::source::Source;
+
+    ///Create an installer from `code`, as though it were a source holding nothing else
+    fn create_installer(code: &str, installer_path: &Path, info: &Info) -> crate::error::Result<Vec<String>> {
+        Source { code: code.to_string(), info: info.clone(), path: PathBuf::from("script"), ..Source::default() }.create_installer(installer_path)
+    }
+
+    fn generic_test<S, I, U>(source_function: S, installer_validator: I, uninstaller_validator: U)
+    where
+        S: FnMut(&Path) -> String,
+        I: FnMut(&Path),
+        U: FnMut(&Path),
 
-    fn generic_test<S, I, U>(mut source_function: S, mut installer_validator: I, mut uninstaller_validator: U)
+    {
+        generic_test_with(&EmulatedRegistry::new(), source_function, installer_validator, uninstaller_validator)
+    }
+
+    ///Like [`generic_test`], with the installer and uninstaller both making their registry changes in `registry`
+    fn generic_test_with<S, I, U>(registry: &EmulatedRegistry, mut source_function: S, mut installer_validator: I, mut uninstaller_validator: U)
     where
         S: FnMut(&Path) -> String,
-        I: FnMut(&Path) -> (),
-        U: FnMut(&Path) -> (),
+        I: FnMut(&Path),
+        U: FnMut(&Path),
 
     {
         let working = TempDir::new().unwrap();
@@ -30,19 +50,146 @@ mod tests {
 
         let uninstaller_path = working_path.join("uninstaller");
 
-        hlc::create_installer(&std::fs::read_to_string(source_path.as_path()).unwrap(), installer_path.as_path(), Info::default().set_uninstaller_location(UninstallLocation::Path(uninstaller_path.clone()))).unwrap();
+        create_installer(&std::fs::read_to_string(source_path.as_path()).unwrap(), installer_path.as_path(), Info::default().set_uninstaller_location(UninstallLocation::Path(uninstaller_path.clone()))).unwrap();
 
 
-        if !hlc::install(installer_path.as_path(), uninstaller_path.as_path()) {
+        assert!(!hlc::install(OakRead::new(installer_path.as_path()).unwrap(), uninstaller_path.as_path(), registry, None), "The installer failed");
 
-            //Closure to perform installer tests
-            installer_validator(working_path);
+        //Closure to perform installer tests
+        installer_validator(working_path);
 
-            hlc::uninstall(uninstaller_path.as_path());
+        assert!(!hlc::uninstall(OakRead::new(uninstaller_path.as_path()).unwrap(), registry, None), "The uninstaller failed");
 
-            //Closure to perform uninstaller tests
-            uninstaller_validator(working_path);
+        //Closure to perform uninstaller tests
+        uninstaller_validator(working_path);
+    }
+
+    ///Run an installer that is expected to fail, then check with `rollback_validator` that its changes were undone
+    fn failing_test<S, R>(mut source_function: S, mut rollback_validator: R)
+    where
+        S: FnMut(&Path) -> String,
+        R: FnMut(&Path),
+
+    {
+        let working = TempDir::new().unwrap();
+        let working_path = working.path();
+
+        let installer_path = working_path.join("installer");
+        let uninstaller_path = working_path.join("uninstaller");
+
+        create_installer(source_function(working_path).as_str(), installer_path.as_path(), Info::default().set_uninstaller_location(UninstallLocation::Path(uninstaller_path.clone()))).unwrap();
+
+        assert!(hlc::install(OakRead::new(installer_path.as_path()).unwrap(), uninstaller_path.as_path(), &EmulatedRegistry::new(), None), "The installer should have failed");
+
+        //A failed installer leaves no uninstaller behind
+        assert!(!uninstaller_path.exists());
+
+        rollback_validator(working_path);
+    }
+
+    ///What running a script with [`run_script`] did: the inverses it recorded, and the uninstaller holding the backups
+    ///they restore
+    struct Ran {
+        ops: Vec<crate::path_type::InverseOp>,
+        uninstaller: OakRead,
+        _working: TempDir,
+    }
+
+    impl Ran {
+        ///Replay the inverses through `fs` and `backend`, under `target_root` if given
+        fn undo(&self, fs: & dyn crate::filesystem::FileSystem, backend: & dyn crate::registry_backend::RegistryBackend, target_root: Option<&Path>) {
+            let temp = TempDir::new().unwrap();
+
+            crate::mlc::replay(self.ops.as_slice(), &self.uninstaller, fs, backend, &temp, target_root).unwrap();
+        }
+    }
+
+    ///Run `code` from an installer with nothing archived, through `fs` and `backend` and under `target_root` if given,
+    ///recording its inverses in `inverses`
+    fn run_script(code: &str, inverses: crate::path_type::Inverse, fs: & dyn crate::filesystem::FileSystem, backend: & dyn crate::registry_backend::RegistryBackend, target_root: Option<&Path>) -> Ran {
+        let working = TempDir::new().unwrap();
+
+        let installer_path = working.path().join("installer");
+        let uninstaller_path = working.path().join("uninstaller");
+
+        create_installer("", installer_path.as_path(), &Info::default()).unwrap();
+
+        {
+            let read = OakRead::new(installer_path.as_path()).unwrap();
+            let write = crate::oak::OakWrite::new(uninstaller_path.as_path());
+            let temp = TempDir::new().unwrap();
+
+            crate::mlc::run(code, &read, Some(&write), Some(&inverses), &temp, None, fs, backend, target_root).unwrap();
+        }
+
+        Ran { ops: inverses.ops(), uninstaller: OakRead::new(uninstaller_path.as_path()).unwrap(), _working: working }
+    }
+
+    ///The path and range of each request [`serve`] was sent
+    type Requests = std::sync::Arc<std::sync::Mutex<Vec<(String, Option<usize>)>>>;
+
+    ///Serve `body` over HTTP on loopback, giving the address and a log of the path and range of each request. `/flaky.bin`
+    ///drops the connection half way unless the rest is asked for with a range, `/data.bin` is sent in one go, and anything
+    ///else is not found
+    fn serve(body: Vec<u8>) -> (std::net::SocketAddr, Requests) {
+        use std::io::{BufRead, BufReader};
+        use std::net::TcpListener;
+        use std::sync::{Arc, Mutex};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        {
+            let requests = requests.clone();
+
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+
+                    let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+
+                    let path = lines.next().unwrap().unwrap().split(' ').nth(1).unwrap().to_string();
+
+                    let mut range = None;
+
+                    for line in lines {
+                        let line = line.unwrap();
+
+                        if line.is_empty() {
+                            break;
+                        }
+
+                        if let Some(from) = line.to_lowercase().strip_prefix("range: bytes=") {
+                            range = Some(from.trim_end_matches('-').parse::<usize>().unwrap());
+                        }
+                    }
+
+                    requests.lock().unwrap().push((path.clone(), range));
+
+                    match (path.as_str(), range) {
+                        ("/flaky.bin", None) => {
+                            write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).unwrap();
+                            stream.write_all(&body[..body.len() / 2]).unwrap();
+                        }
+                        ("/flaky.bin", Some(from)) => {
+                            write!(stream, "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", from, body.len() - 1, body.len(), body.len() - from).unwrap();
+                            stream.write_all(&body[from..]).unwrap();
+                        }
+                        ("/data.bin", _) => {
+                            write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).unwrap();
+                            stream.write_all(&body).unwrap();
+                        }
+                        _ => {
+                            write!(stream, "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+                        }
+                    }
+                }
+            });
         }
+
+        (address, requests)
     }
 
     #[test]
@@ -82,15 +229,15 @@ mod tests {
 
         let uninstaller_path = working_path.join("uninstaller");
 
-        hlc::create_installer(&std::fs::read_to_string(source_path.as_path()).unwrap(), installer_path.as_path(), Info::default().set_uninstaller_location(UninstallLocation::Path(uninstaller_path.clone()))).unwrap();
+        create_installer(&std::fs::read_to_string(source_path.as_path()).unwrap(), installer_path.as_path(), Info::default().set_uninstaller_location(UninstallLocation::Path(uninstaller_path.clone()))).unwrap();
 
-        if !hlc::install(installer_path.as_path(), uninstaller_path.as_path()) {
+        if !hlc::install(OakRead::new(installer_path.as_path()).unwrap(), uninstaller_path.as_path(), &EmulatedRegistry::new(), None) {
 
             //Closure to perform installer tests
 
             assert!(!working_path.join("sample").exists());
 
-            hlc::uninstall(uninstaller_path.as_path());
+            hlc::uninstall(OakRead::new(uninstaller_path.as_path()).unwrap(), &EmulatedRegistry::new(), None);
 
             //Closure to perform uninstaller tests
             assert!(working_path.join("sample").exists());
@@ -396,6 +543,8 @@ lazy_static=\"0.1.0\"
 num=\"0.1.0\"
 clap=\"1\"";
 
+        let (address, _) = serve(file_data.as_bytes().to_vec());
+
         generic_test(|working_path| {
 
             let file = working_path.join("download");
@@ -405,9 +554,9 @@ clap=\"1\"";
             format!("
 
 
-    __download(\"https://raw.githubusercontent.com/ray33ee/chembal/68b9402ff8c00e7fc041a9f95164ba0003c87d7a/Cargo.toml\", pathtype.absolute({:?}))
+    __download(\"http://{}/data.bin\", pathtype.absolute({:?}))
 
-", file)
+", address, file)
         }, |working_path|{
 
             assert!(working_path.join("download").exists());
@@ -415,7 +564,8 @@ clap=\"1\"";
             assert_eq!(std::fs::read_to_string(working_path.join("download")).unwrap().as_str(), file_data);
 
         }, |working_path|{
-            assert!(!working_path.join("download").exists());
+            //The empty file the download replaced is restored
+            assert_eq!(std::fs::read_to_string(working_path.join("download")).unwrap(), "");
         });
 
     }
@@ -457,23 +607,23 @@ clap=\"1\"";
     #[test]
     fn instruction_reg_write_key() {
 
+        let registry = EmulatedRegistry::new();
 
+        generic_test_with(&registry, |_working_path| {
 
-        generic_test(|_working_path| {
-
-            format!("
+            String::from("
 
     __reg_write_key(\"hklm\", \"SOFTWARE\\\\key_test\")
 
     ")
         }, |_working_path|{
 
-            Hive::LocalMachine.open("SOFTWARE\\key_test", Security::Read).unwrap();
+            assert!(registry.key_exists(&RootKey::HKLM, "SOFTWARE\\key_test"));
 
 
         }, |_working_path|{
 
-            Hive::LocalMachine.open("SOFTWARE\\key_test", Security::Read).err().unwrap();
+            assert!(!registry.key_exists(&RootKey::HKLM, "SOFTWARE\\key_test"));
 
         });
 
@@ -482,47 +632,27 @@ clap=\"1\"";
     #[test]
     fn instruction_reg_write_val() {
 
+        let registry = EmulatedRegistry::new();
 
-        let rkey = Hive::LocalMachine.open("SOFTWARE", Security::AllAccess).unwrap();
+        registry.create_key(&RootKey::HKLM, "SOFTWARE\\val_test").unwrap();
 
-        rkey.create("val_test", Security::AllAccess).unwrap();
+        generic_test_with(&registry, |_working_path| {
 
-        generic_test(|_working_path| {
 
-
-            format!("
+            String::from("
 
 __reg_write_value(\"hklm\", \"SOFTWARE\\\\val_test\", \"val_name\", 100)
 
 ")
         }, |_working_path|{
 
-            let rkey = Hive::LocalMachine.open("SOFTWARE\\val_test", Security::AllAccess).unwrap();
+            let val = registry.value(&RootKey::HKLM, "SOFTWARE\\val_test", "val_name").unwrap();
 
-            let val = rkey.value("val_name").unwrap();
-
-            if let registry::Data::U32(n) = val {
-                assert_eq!(n, 100);
-            } else {
-                panic!("Data not a number");
-            }
+            assert!(matches!(val, Some(Data::U32(100))), "Data not 100");
 
         }, |_working_path|{
 
-            let rkey = Hive::LocalMachine.open("SOFTWARE\\val_test", Security::AllAccess).unwrap();
-
-
-            match rkey.value("val_name") {
-                Ok(_) => {
-                    rkey.delete_self(false).unwrap();
-
-                    panic!("Uninstall failed. val_name value still exists")
-                }
-                Err(_) => {
-                    rkey.delete_self(false).unwrap();
-                }
-            }
-
+            assert!(registry.value(&RootKey::HKLM, "SOFTWARE\\val_test", "val_name").unwrap().is_none(), "Uninstall failed. val_name value still exists");
 
         });
 
@@ -531,50 +661,31 @@ __reg_write_value(\"hklm\", \"SOFTWARE\\\\val_test\", \"val_name\", 100)
     #[test]
     fn instruction_reg_write_val2() {
 
+        let registry = EmulatedRegistry::new();
 
-        let rkey = Hive::LocalMachine.open("SOFTWARE", Security::AllAccess).unwrap();
-
-        let bkey = rkey.create("val_test2", Security::AllAccess).unwrap();
+        registry.create_key(&RootKey::HKLM, "SOFTWARE\\val_test2").unwrap();
+        registry.set_value(&RootKey::HKLM, "SOFTWARE\\val_test2", "val_name", &Data::U32(400)).unwrap();
 
-        bkey.set_value("val_name", &registry::Data::U32(400)).unwrap();
+        generic_test_with(&registry, |_working_path| {
 
-        generic_test(|_working_path| {
 
-
-            format!("
+            String::from("
     __reg_write_value(\"hklm\", \"SOFTWARE\\\\val_test2\", \"val_name\", 100)
 
 ")
         }, |_working_path|{
 
-            let rkey = Hive::LocalMachine.open("SOFTWARE\\val_test2", Security::AllAccess).unwrap();
+            let val = registry.value(&RootKey::HKLM, "SOFTWARE\\val_test2", "val_name").unwrap();
 
-            let val = rkey.value("val_name").unwrap();
-
-            if let registry::Data::U32(n) = val {
-                assert_eq!(n, 100);
-            } else {
-                panic!("Data not a number");
-            }
+            assert!(matches!(val, Some(Data::U32(100))), "Data not 100");
 
         }, |_working_path|{
 
-            let rkey = Hive::LocalMachine.open("SOFTWARE\\val_test2", Security::AllAccess).unwrap();
-
-
-            let val = rkey.value("val_name").unwrap();
-
-            if let registry::Data::U32(n) = val {
-                assert_eq!(n, 400);
-            } else {
-                panic!("Data not a number");
-            }
+            let val = registry.value(&RootKey::HKLM, "SOFTWARE\\val_test2", "val_name").unwrap();
 
+            assert!(matches!(val, Some(Data::U32(400))), "Data not restored to 400");
 
         });
-
-        bkey.delete("", true).unwrap();
-        bkey.delete_self(false).unwrap();
     }
 
 
@@ -582,47 +693,33 @@ __reg_write_value(\"hklm\", \"SOFTWARE\\\\val_test\", \"val_name\", 100)
     #[test]
     fn instruction_reg_delete_val() {
 
+        let registry = EmulatedRegistry::new();
 
-        let rkey = Hive::LocalMachine.open("SOFTWARE", Security::AllAccess).unwrap();
-
-        let bkey = rkey.create("val_test_delete", Security::AllAccess).unwrap();
+        registry.create_key(&RootKey::HKLM, "SOFTWARE\\val_test_delete").unwrap();
+        registry.set_value(&RootKey::HKLM, "SOFTWARE\\val_test_delete", "f", &Data::U32(400)).unwrap();
 
-        bkey.set_value("f", &registry::Data::U32(400)).unwrap();
+        generic_test_with(&registry, |_working_path| {
 
-        generic_test(|_working_path| {
 
-
-            format!("
+            String::from("
 
 
     __reg_delete_value(HKLM, \"SOFTWARE\\\\val_test_delete\", \"f\")
 
 ")
         }, |_working_path|{
-            let rkey = Hive::LocalMachine.open("SOFTWARE\\val_test_delete", Security::AllAccess).unwrap();
 
-            assert!(rkey.value("f").is_err())
+            assert!(registry.value(&RootKey::HKLM, "SOFTWARE\\val_test_delete", "f").unwrap().is_none());
 
 
         }, |_working_path|{
 
+            let val = registry.value(&RootKey::HKLM, "SOFTWARE\\val_test_delete", "f").unwrap();
 
-            let rkey = Hive::LocalMachine.open("SOFTWARE\\val_test_delete", Security::AllAccess).unwrap();
-
-            let val = rkey.value("f").unwrap();
-
-            if let registry::Data::U32(n) = val {
-                assert_eq!(n, 400);
-            } else {
-                panic!("Data not a number");
-            }
-
+            assert!(matches!(val, Some(Data::U32(400))), "Data not restored to 400");
 
         });
 
-        bkey.delete("", true).unwrap();
-        bkey.delete_self(false).unwrap();
-
     }
 
 
@@ -633,42 +730,29 @@ __reg_write_value(\"hklm\", \"SOFTWARE\\\\val_test\", \"val_name\", 100)
     #[test]
     fn instruction_reg_delete_key() {
 
+        let registry = EmulatedRegistry::new();
 
-        let rkey = Hive::LocalMachine.open("SOFTWARE", Security::AllAccess).unwrap();
-
-        let rkey = rkey.create("instruction_reg_delete_key", Security::AllAccess).unwrap();
+        registry.create_key(&RootKey::HKLM, "SOFTWARE\\instruction_reg_delete_key\\inner").unwrap();
+        registry.set_value(&RootKey::HKLM, "SOFTWARE\\instruction_reg_delete_key\\inner", "val", &Data::U32(500)).unwrap();
 
-        let rkey = rkey.create("inner", Security::AllAccess).unwrap();
+        generic_test_with(&registry, |_working_path| {
 
-        rkey.set_value("val", &registry::Data::U32(500)).unwrap();
-
-        generic_test(|_working_path| {
-
-            format!("
+            String::from("
 
     __reg_delete_key(\"hklm\", \"SOFTWARE\\\\instruction_reg_delete_key\")
 
 ")
         }, |_working_path|{
 
-            Hive::LocalMachine.open("SOFTWARE\\instruction_reg_delete_key\\inner", Security::Read).err().unwrap();
+            assert!(!registry.key_exists(&RootKey::HKLM, "SOFTWARE\\instruction_reg_delete_key\\inner"));
 
         }, |_working_path|{
 
-            let key = Hive::LocalMachine.open("SOFTWARE\\instruction_reg_delete_key\\inner", Security::Read).unwrap();
-
-            if let registry::Data::U32(500) = key.value("val").unwrap() {
+            let val = registry.value(&RootKey::HKLM, "SOFTWARE\\instruction_reg_delete_key\\inner", "val").unwrap();
 
-            } else {
-                panic!("Invalid registry data");
-            }
+            assert!(matches!(val, Some(Data::U32(500))), "Invalid registry data");
 
         });
-
-        let r = Hive::LocalMachine.open("SOFTWARE\\instruction_reg_delete_key", Security::AllAccess).unwrap();
-
-        r.delete("", true).unwrap();
-        r.delete_self(false).unwrap();
     }
 
 
@@ -677,7 +761,7 @@ __reg_write_value(\"hklm\", \"SOFTWARE\\\\val_test\", \"val_name\", 100)
 
 
 
-        generic_test(|working_path| {
+        failing_test(|working_path| {
 
             let sample_path = working_path.join("sample");
             let copy_path = working_path.join("copy");
@@ -702,10 +786,6 @@ __reg_write_value(\"hklm\", \"SOFTWARE\\\\val_test\", \"val_name\", 100)
             assert!(!working_path.join("copy").exists());
             assert!(!working_path.join("third").exists());
 
-        }, |_working_path|{
-
-
-
         });
 
     }
@@ -715,7 +795,7 @@ __reg_write_value(\"hklm\", \"SOFTWARE\\\\val_test\", \"val_name\", 100)
 
 
 
-        generic_test(|working_path| {
+        failing_test(|working_path| {
 
             let sample_path = working_path.join("sample");
             let copy_path = working_path.join("copy");
@@ -740,10 +820,6 @@ __reg_write_value(\"hklm\", \"SOFTWARE\\\\val_test\", \"val_name\", 100)
             assert!(!working_path.join("copy").exists());
             assert!(!working_path.join("third").exists());
 
-        }, |_working_path|{
-
-
-
         });
 
     }
@@ -754,7 +830,7 @@ __reg_write_value(\"hklm\", \"SOFTWARE\\\\val_test\", \"val_name\", 100)
 
 
 
-        generic_test(|working_path| {
+        failing_test(|working_path| {
 
             let sample_path = working_path.join("sample");
             let copy_path = working_path.join("copy");
@@ -777,10 +853,6 @@ __reg_write_value(\"hklm\", \"SOFTWARE\\\\val_test\", \"val_name\", 100)
             assert!(!working_path.join("copy").exists());
             assert!(!working_path.join("third").exists());
 
-        }, |_working_path|{
-
-
-
         });
 
     }
@@ -950,6 +1022,7 @@ __reg_write_value(\"hklm\", \"SOFTWARE\\\\val_test\", \"val_name\", 100)
     }
 
     #[test]
+    #[cfg(windows)]
     fn special_path_test() {
 
         let file_path = PathBuf::from(std::env::var("APPDATA").unwrap()).join("file");
@@ -957,7 +1030,7 @@ __reg_write_value(\"hklm\", \"SOFTWARE\\\\val_test\", \"val_name\", 100)
         generic_test(|_| {
 
 
-            format!("
+            String::from("
 
     io.open(pathtype.special(pathtype.AppData, \"file\"), \"w\")
 
@@ -1043,5 +1116,1374 @@ __reg_write_value(\"hklm\", \"SOFTWARE\\\\val_test\", \"val_name\", 100)
 
     }*/
 
+    #[test]
+    fn inverse_round_trip_test() {
+        use std::ffi::OsString;
+        use crate::filesystem::{DiskFileSystem, FileSystem, MemoryFileSystem};
+        use crate::oak::OakWrite;
+        use crate::path_type::InverseOp;
+
+        //A file name that isn't valid UTF-8 on either platform, which must still survive a round trip
+        #[cfg(windows)]
+        let odd_name = {
+            use std::os::windows::ffi::OsStringExt;
+            OsString::from_wide(&[0x61, 0xD800, 0x62])
+        };
+
+        #[cfg(not(windows))]
+        let odd_name = {
+            use std::os::unix::ffi::OsStringExt;
+            OsString::from_vec(vec![0x61, 0xff, 0x62])
+        };
+
+        let working = TempDir::new().unwrap();
+        let working_path = working.path();
+
+        let sample = working_path.join("sample");
+        std::fs::write(sample.as_path(), "backup").unwrap();
+
+        let archive_path = working_path.join("archive");
+
+        let name = {
+            let write = OakWrite::new(archive_path.as_path());
+            write.archive(&DiskFileSystem, sample.as_path()).unwrap()
+        };
+
+        let app = PathBuf::from("\\app");
+        let odd = app.join(odd_name);
+
+        let ops = vec![
+            InverseOp::Delete { path: odd.clone() },
+            InverseOp::Move { source: app.join("moved"), destination: app.join("original") },
+            InverseOp::Data { name: name.clone(), destination: app.join("restored") },
+            InverseOp::RegWriteKey { root: RootKey::HKCU, key: String::from("SOFTWARE\\\"quoted\"") },
+            InverseOp::RegDeleteKey { root: RootKey::HKCU, key: String::from("SOFTWARE\\gone") },
+            InverseOp::RegWriteValue { root: RootKey::HKCU, key: String::from("SOFTWARE\\\"quoted\""), value: String::from("v"), data: Data::MultiString(vec![String::from("a"), String::from("b")]) },
+            InverseOp::RegDeleteValue { root: RootKey::HKCU, key: String::from("SOFTWARE\\kept"), value: String::from("gone") },
+            InverseOp::SetAttributes { path: odd.clone(), attributes: 0x20 },
+        ];
+
+        let json = serde_json::to_string(&ops).unwrap();
+
+        let decoded: Vec<InverseOp> = serde_json::from_str(json.as_str()).unwrap();
+
+        assert_eq!(serde_json::to_string(&decoded).unwrap(), json);
+
+        match (&decoded[0], &decoded[7]) {
+            (InverseOp::Delete { path }, InverseOp::SetAttributes { path: attributed, attributes }) => {
+                assert_eq!(path, &odd);
+                assert_eq!(attributed, &odd);
+                assert_eq!(*attributes, 0x20);
+            }
+            _ => {panic!("Expected the delete and set attributes inverses to keep their place")}
+        }
+
+        //Replay the decoded inverses, except setting attributes which needs the real disk on Windows
+        let fs = MemoryFileSystem::new();
+        let registry = EmulatedRegistry::new();
+
+        fs.create_dir_all(app.as_path()).unwrap();
+        fs.write(odd.as_path(), & mut "odd".as_bytes()).unwrap();
+        fs.write(app.join("moved").as_path(), & mut "moved".as_bytes()).unwrap();
+
+        registry.create_key(&RootKey::HKCU, "SOFTWARE\\gone").unwrap();
+        registry.create_key(&RootKey::HKCU, "SOFTWARE\\kept").unwrap();
+        registry.set_value(&RootKey::HKCU, "SOFTWARE\\kept", "gone", &Data::U32(1)).unwrap();
+
+        let read = OakRead::new(archive_path.as_path()).unwrap();
+        let temp = TempDir::new().unwrap();
+
+        crate::mlc::replay(&decoded[..7], &read, &fs, &registry, &temp, None).unwrap();
+
+        assert!(!fs.exists(odd.as_path()));
+        assert!(!fs.exists(app.join("moved").as_path()));
+        assert_eq!(fs.contents(app.join("original").as_path()).unwrap(), b"moved".to_vec());
+        assert_eq!(fs.contents(app.join("restored").as_path()).unwrap(), b"backup".to_vec());
+
+        assert!(!registry.key_exists(&RootKey::HKCU, "SOFTWARE\\gone"));
+        assert!(matches!(registry.value(&RootKey::HKCU, "SOFTWARE\\\"quoted\"", "v").unwrap(), Some(Data::MultiString(lines)) if lines == vec!["a", "b"]));
+        assert!(registry.value(&RootKey::HKCU, "SOFTWARE\\kept", "gone").unwrap().is_none());
+    }
+
+    #[test]
+    fn journal_rollback() {
+
+        use crate::journal::Journal;
+        use crate::path_type::Inverse;
+        use crate::filesystem::DiskFileSystem;
+
+        let file_data = "this is some
+
+
+        random data to load into the file.";
+
+        let working = TempDir::new().unwrap();
+        let working_path = working.path();
+
+        let sample_path = working_path.join("sample");
+        let copy_path = working_path.join("copy");
+
+        std::fs::write(sample_path.as_path(), file_data).unwrap();
+
+        let journal_path = Journal::location(working_path.join("uninstaller"));
+
+        let inverses = Inverse::with_journal(Journal::create(journal_path.as_path(), None).unwrap());
+
+        //Simulate an installer that is killed after these steps, leaving only the journal to undo them
+        run_script(format!("
+    __copy(pathtype.absolute({:?}), pathtype.absolute({:?}))
+    __delete(pathtype.absolute({:?}))
+", sample_path, copy_path, sample_path).as_str(), inverses, &DiskFileSystem, &EmulatedRegistry::new(), None);
+
+        assert!(!sample_path.exists());
+        assert!(copy_path.exists());
+
+        hlc::rollback(journal_path.as_path(), &EmulatedRegistry::new(), None).unwrap();
+
+        assert!(!copy_path.exists());
+        assert!(!journal_path.exists());
+
+        assert_eq!(std::fs::read_to_string(sample_path.as_path()).unwrap().as_str(), file_data);
+    }
+
+    #[test]
+    fn plan_test() {
+
+        use crate::plan::Action;
+        use crate::path_type::InverseOp;
+
+        let working = TempDir::new().unwrap();
+        let working_path = working.path();
+
+        let sample_path = working_path.join("sample");
+        let copy_path = working_path.join("copy");
+        let dir_path = working_path.join("dir");
+
+        std::fs::File::create(sample_path.as_path()).unwrap();
+
+        let installer_path = working_path.join("installer");
+
+        create_installer(format!("
+    __copy(pathtype.absolute({:?}), pathtype.absolute({:?}))
+    __mkdir(pathtype.absolute({:?}))
+    __delete(pathtype.absolute({:?}))
+", sample_path, copy_path, dir_path, sample_path).as_str(), installer_path.as_path(), &Info::default()).unwrap();
+
+        let plan = hlc::plan(installer_path.as_path(), &EmulatedRegistry::new(), None).unwrap();
+
+        //Nothing should have changed
+        assert!(sample_path.exists());
+        assert!(!copy_path.exists());
+        assert!(!dir_path.exists());
+
+        let steps = plan.steps();
+
+        assert_eq!(steps.len(), 3);
+
+        assert!(matches!(&steps[0].action, Action::Copy { destination, .. } if destination == &copy_path));
+        assert!(matches!(steps[0].inverses.as_slice(), [InverseOp::Delete { path }] if path == &copy_path));
+
+        assert!(matches!(&steps[1].action, Action::Mkdir { path } if path == &dir_path));
+
+        assert!(matches!(&steps[2].action, Action::Delete { path } if path == &sample_path));
+        assert!(matches!(steps[2].inverses.as_slice(), [InverseOp::Data { destination, .. }] if destination == &sample_path));
+
+        assert!(plan.to_json().is_ok());
+    }
+
+    #[test]
+    fn dry_run_test() {
+        let working = TempDir::new().unwrap();
+
+        let installer_path = working.path().join("installer");
+        let app = working.path().join("app");
+
+        //The dry run starts from an empty file system, so even the working folder has to be created
+        create_installer(format!("
+    oak.lib.ensure_dir_all({:?})
+    __copy(pathtype.absolute({:?}), pathtype.absolute({:?}))
+", app, app.join("missing"), app.join("copy")).as_str(), installer_path.as_path(), &Info::default()).unwrap();
+
+        //Copying a file that doesn't exist fails cleanly rather than panicking
+        assert!(hlc::dry_run(installer_path.as_path(), &EmulatedRegistry::new()).is_err());
+
+        create_installer(format!("
+    oak.lib.ensure_dir_all({:?})
+    __mkdir(pathtype.absolute({:?}))
+", app, app.join("inner")).as_str(), working.path().join("second").as_path(), &Info::default()).unwrap();
+
+        let paths = hlc::dry_run(working.path().join("second"), &EmulatedRegistry::new()).unwrap();
+
+        //The new folders and their ancestors are listed, but nothing from the temporary folder, and nothing was written to the disk
+        assert!(paths.contains(&working.path().to_path_buf()) && paths.contains(&app) && paths.contains(&app.join("inner")));
+        assert!(paths.iter().all(|path| path.starts_with(working.path()) || working.path().starts_with(path)));
+        assert!(!app.exists());
+    }
+
+    #[test]
+    fn memory_filesystem_test() {
+
+        use crate::filesystem::{FileSystem, MemoryFileSystem};
+        use crate::path_type::Inverse;
+
+        let fs = MemoryFileSystem::new();
+
+        let app = PathBuf::from("\\app");
+
+        fs.create_dir_all(app.join("src").as_path()).unwrap();
+        fs.write(app.join("src").join("a.txt").as_path(), & mut "hello world".as_bytes()).unwrap();
+
+        let ran = run_script(format!("
+    __mkdir(pathtype.absolute({:?}))
+    __copy(pathtype.absolute({:?}), pathtype.absolute({:?}))
+    __edit(pathtype.absolute({:?}), \"s/hello/goodbye/\")
+    __zip(pathtype.absolute({:?}), pathtype.absolute({:?}))
+    __unzip(pathtype.absolute({:?}), pathtype.absolute({:?}))
+    __delete(pathtype.absolute({:?}))
+",
+            app.join("dst"),
+            app.join("src").join("a.txt"), app.join("dst").join("a.txt"),
+            app.join("dst").join("a.txt"),
+            app.join("dst.zip"), app.join("dst"),
+            app.join("dst.zip"), app.join("out"),
+            app.join("src"),
+        ).as_str(), Inverse::new(), &fs, &EmulatedRegistry::new(), None);
+
+        //Everything happened in memory
+        assert!(!app.exists());
+
+        assert!(!fs.exists(app.join("src").as_path()));
+        assert_eq!(fs.contents(app.join("dst").join("a.txt").as_path()).unwrap(), b"goodbye world".to_vec());
+        assert_eq!(fs.contents(app.join("out").join("a.txt").as_path()).unwrap(), b"goodbye world".to_vec());
+
+        //Undo it all, restoring the deleted folder from the uninstaller
+        ran.undo(&fs, &EmulatedRegistry::new(), None);
+
+        assert!(!fs.exists(app.join("dst").as_path()));
+        assert!(!fs.exists(app.join("dst.zip").as_path()));
+        assert!(!fs.exists(app.join("out").as_path()));
+        assert_eq!(fs.contents(app.join("src").join("a.txt").as_path()).unwrap(), b"hello world".to_vec());
+    }
+
+    #[test]
+    fn emulated_registry_test() {
+
+        use crate::filesystem::MemoryFileSystem;
+        use crate::path_type::Inverse;
+
+        let working = TempDir::new().unwrap();
+
+        let hive_path = working.path().join("hive.json");
+
+        {
+            let registry = EmulatedRegistry::open(hive_path.as_path()).unwrap();
+
+            registry.create_key(&RootKey::HKCU, "SOFTWARE\\existing").unwrap();
+            registry.set_value(&RootKey::HKCU, "SOFTWARE\\existing", "kept", &Data::U32(1)).unwrap();
+            registry.set_value(&RootKey::HKCU, "SOFTWARE\\existing", "changed", &Data::U32(2)).unwrap();
+        }
+
+        let registry = EmulatedRegistry::open(hive_path.as_path()).unwrap();
+
+        let ran = run_script("
+    __reg_write_key(HKCU, \"SOFTWARE\\\\new\\\\inner\")
+    __reg_write_value(HKCU, \"SOFTWARE\\\\new\\\\inner\", \"value\", \"data\")
+    __reg_write_value(HKCU, \"SOFTWARE\\\\existing\", \"changed\", 3)
+    __reg_delete_value(HKCU, \"SOFTWARE\\\\existing\", \"kept\")
+", Inverse::new(), &MemoryFileSystem::new(), &registry, None);
+
+        //The changes were persisted
+        let reopened = EmulatedRegistry::open(hive_path.as_path()).unwrap();
+
+        assert!(matches!(reopened.value(&RootKey::HKCU, "software\\new\\inner", "value").unwrap(), Some(Data::String(s)) if s == "data"));
+        assert!(matches!(reopened.value(&RootKey::HKCU, "SOFTWARE\\existing", "changed").unwrap(), Some(Data::U32(3))));
+        assert!(reopened.value(&RootKey::HKCU, "SOFTWARE\\existing", "kept").unwrap().is_none());
+
+        ran.undo(&MemoryFileSystem::new(), &registry, None);
+
+        assert!(!registry.key_exists(&RootKey::HKCU, "SOFTWARE\\new"));
+        assert!(matches!(registry.value(&RootKey::HKCU, "SOFTWARE\\existing", "changed").unwrap(), Some(Data::U32(2))));
+        assert!(matches!(registry.value(&RootKey::HKCU, "SOFTWARE\\existing", "kept").unwrap(), Some(Data::U32(1))));
+    }
+
+    #[test]
+    fn target_root_test() {
+
+        use crate::filesystem::DiskFileSystem;
+        use crate::path_type::{reroot, Inverse, InverseOp};
+
+        let working = TempDir::new().unwrap();
+        let root = TempDir::new().unwrap();
+
+        let logical = working.path().join("logical");
+        let inner = logical.join("inner");
+
+        std::fs::create_dir_all(reroot(working.path(), Some(root.path()))).unwrap();
+
+        let ran = run_script(format!("
+    __mkdir(pathtype.absolute({:?}))
+    __mkdir(pathtype.absolute({:?}))
+", logical, inner).as_str(), Inverse::new(), &DiskFileSystem, &EmulatedRegistry::new(), Some(root.path()));
+
+        //The folders were made under the root, but the inverses name the logical paths
+        assert!(!logical.exists());
+        assert!(reroot(inner.as_path(), Some(root.path())).is_dir());
+
+        assert!(matches!(ran.ops.as_slice(), [InverseOp::Delete { path: first }, InverseOp::Delete { path: second }] if first == &inner && second == &logical));
+
+        ran.undo(&DiskFileSystem, &EmulatedRegistry::new(), Some(root.path()));
+
+        assert!(!reroot(logical.as_path(), Some(root.path())).exists());
+    }
+
+    #[test]
+    fn deduplication_test() {
+
+        use crate::filesystem::DiskFileSystem;
+        use crate::oak::{OakRead, OakWrite};
+
+        let working = TempDir::new().unwrap();
+
+        let file = working.path().join("file");
+        let folder = working.path().join("folder");
+
+        std::fs::write(file.as_path(), "contents").unwrap();
+        std::fs::create_dir(folder.as_path()).unwrap();
+        std::fs::write(folder.join("inner"), "contents").unwrap();
+
+        let archive_path = working.path().join("archive");
+
+        let (first, second, third, fourth) = {
+            let write = OakWrite::new(archive_path.as_path());
+
+            (
+                write.archive(&DiskFileSystem, file.as_path()).unwrap(),
+                write.archive(&DiskFileSystem, file.as_path()).unwrap(),
+                write.archive(&DiskFileSystem, folder.as_path()).unwrap(),
+                write.archive(&DiskFileSystem, folder.as_path()).unwrap(),
+            )
+        };
+
+        let blobs = zip::ZipArchive::new(std::fs::File::open(archive_path.as_path()).unwrap()).unwrap().file_names().filter(|name| name.starts_with("_b_")).count();
+
+        //One blob for the file, and one for the zipped folder
+        assert_eq!(blobs, 2);
+
+        let read = OakRead::new(archive_path.as_path()).unwrap();
+
+        assert_eq!(read.index()[&first], read.index()[&second]);
+        assert_eq!(read.index()[&third], read.index()[&fourth]);
+
+        let out = working.path().join("out");
+        std::fs::create_dir(out.as_path()).unwrap();
+
+        read.extract(&DiskFileSystem, second.as_str(), out.join("file").as_path()).unwrap();
+        read.extract(&DiskFileSystem, fourth.as_str(), out.join("folder").as_path()).unwrap();
+
+        assert_eq!(std::fs::read_to_string(out.join("file")).unwrap(), "contents");
+        assert_eq!(std::fs::read_to_string(out.join("folder").join("inner")).unwrap(), "contents");
+    }
+
+    #[test]
+    fn manifest_test() {
+
+        use crate::error::Error;
+        use crate::filesystem::DiskFileSystem;
+        use crate::oak::{ManifestEntry, OakRead, OakWrite};
+
+        let working = TempDir::new().unwrap();
+
+        let file = working.path().join("file");
+        std::fs::write(file.as_path(), "contents").unwrap();
+
+        let folder = working.path().join("folder");
+        std::fs::create_dir(folder.as_path()).unwrap();
+        std::fs::write(folder.join("inner"), "inner").unwrap();
+
+        let archive_path = working.path().join("archive");
 
-}
\ No newline at end of file
+        let (name, folder_name) = {
+            let write = OakWrite::new(archive_path.as_path());
+            (write.archive(&DiskFileSystem, file.as_path()).unwrap(), write.archive(&DiskFileSystem, folder.as_path()).unwrap())
+        };
+
+        let read = OakRead::new(archive_path.as_path()).unwrap();
+
+        assert_eq!(read.manifest()[&name].size, 8);
+        read.verify().unwrap();
+
+        //Folders are extracted in place, and files as they are checked
+        read.extract(&DiskFileSystem, folder_name.as_str(), working.path().join("extracted")).unwrap();
+        read.extract(&DiskFileSystem, name.as_str(), working.path().join("extracted").join("file")).unwrap();
+
+        assert_eq!(std::fs::read_to_string(working.path().join("extracted").join("inner")).unwrap(), "inner");
+        assert_eq!(std::fs::read_to_string(working.path().join("extracted").join("file")).unwrap(), "contents");
+
+        //Copy the archive, with a manifest that no longer matches the entry
+        let tampered_path = working.path().join("tampered");
+
+        {
+            let mut original = zip::ZipArchive::new(std::fs::File::open(archive_path.as_path()).unwrap()).unwrap();
+            let mut tampered = zip::ZipWriter::new(std::fs::File::create(tampered_path.as_path()).unwrap());
+
+            for i in 0..original.len() {
+                let entry = original.by_index(i).unwrap();
+
+                if entry.name() != "_manifest" {
+                    tampered.raw_copy_file(entry).unwrap();
+                }
+            }
+
+            let mut manifest = read.manifest().clone();
+            manifest.insert(name.clone(), ManifestEntry { sha256: "0".repeat(64), size: 8 });
+            manifest.insert(folder_name.clone(), ManifestEntry { sha256: "0".repeat(64), size: manifest[&folder_name].size });
+
+            tampered.start_file("_manifest", zip::write::FileOptions::default()).unwrap();
+            serde_json::to_writer(& mut tampered, &manifest).unwrap();
+
+            tampered.finish().unwrap();
+        }
+
+        let tampered = OakRead::new(tampered_path.as_path()).unwrap();
+
+        assert!(matches!(tampered.verify(), Err(Error::Corrupted(_))));
+
+        let destination = working.path().join("destination");
+
+        assert!(matches!(tampered.extract(&DiskFileSystem, name.as_str(), destination.as_path()), Err(Error::Corrupted(_))));
+        assert!(!destination.exists());
+
+        assert!(matches!(tampered.extract(&DiskFileSystem, folder_name.as_str(), destination.as_path()), Err(Error::Corrupted(_))));
+        assert!(!destination.exists());
+    }
+
+    #[test]
+    fn signing_test() {
+
+        use crate::error::Error;
+        use crate::exe_extender::Window;
+        use crate::signing;
+
+        let working = TempDir::new().unwrap();
+
+        let key_path = working.path().join("key");
+        let archive_path = working.path().join("archive");
+
+        create_installer("", archive_path.as_path(), &Info::default()).unwrap();
+
+        let public = signing::generate_key(key_path.as_path()).unwrap();
+
+        //Keys are never overwritten
+        assert!(signing::generate_key(key_path.as_path()).is_err());
+
+        let key = signing::load_key(key_path.as_path()).unwrap();
+        let signature = signing::sign(std::fs::File::open(archive_path.as_path()).unwrap(), &key).unwrap();
+
+        signing::verify(std::fs::File::open(archive_path.as_path()).unwrap(), Some(&signature), &public).unwrap();
+
+        assert!(matches!(signing::verify(std::fs::File::open(archive_path.as_path()).unwrap(), None, &public), Err(Error::Signature(_))));
+        assert!(matches!(signing::verify(std::fs::File::open(archive_path.as_path()).unwrap(), Some(&signature), &ed25519_dalek::SigningKey::generate(& mut rand::rngs::OsRng).verifying_key()), Err(Error::Signature(_))));
+
+        //Any change to the archive breaks the signature
+        std::fs::OpenOptions::new().append(true).open(archive_path.as_path()).unwrap().write_all(b"tampered").unwrap();
+
+        assert!(matches!(signing::verify(std::fs::File::open(archive_path.as_path()).unwrap(), Some(&signature), &public), Err(Error::Signature(_))));
+
+        //Signed installers carry no key for their uninstallers, which are checked against the digest the installer records instead
+        let installer_path = working.path().join("installer");
+        let uninstaller_path = working.path().join("uninstaller");
+
+        Source { path: PathBuf::from("script"), ..Source::default() }.create_installer(installer_path.as_path()).unwrap();
+
+        let signature = signing::sign(std::fs::File::open(installer_path.as_path()).unwrap(), &key).unwrap();
+
+        hlc::verify(Window::whole(std::fs::File::open(installer_path.as_path()).unwrap()).unwrap(), Some(&signature), Some(&public), installer_path.as_path()).unwrap();
+
+        assert!(!hlc::install(OakRead::new(installer_path.as_path()).unwrap(), uninstaller_path.as_path(), &EmulatedRegistry::new(), None));
+
+        let uninstaller = || Window::whole(std::fs::File::open(uninstaller_path.as_path()).unwrap()).unwrap();
+
+        //Nothing was recorded for this uninstaller yet, so it is refused
+        assert!(matches!(hlc::verify(uninstaller(), None, Some(&public), uninstaller_path.as_path()), Err(Error::Signature(_))));
+
+        signing::record(uninstaller(), uninstaller_path.as_path()).unwrap();
+
+        hlc::verify(uninstaller(), None, Some(&public), uninstaller_path.as_path()).unwrap();
+
+        //Any other uninstaller, even one signed with the installer's key, is refused
+        std::fs::OpenOptions::new().append(true).open(uninstaller_path.as_path()).unwrap().write_all(b"tampered").unwrap();
+
+        let signature = signing::sign(uninstaller(), &key).unwrap();
+
+        assert!(matches!(hlc::verify(uninstaller(), Some(&signature), Some(&public), uninstaller_path.as_path()), Err(Error::Signature(_))));
+    }
+
+    #[test]
+    fn trailer_test() {
+
+        use crate::error::Error;
+        use std::io::Read;
+        use crate::exe_extender::{extend_exe, get_archive, read_trailer};
+        use crate::oak::{OakRead, OakType};
+        use sha2::{Digest, Sha256};
+
+        let working = TempDir::new().unwrap();
+
+        let archive_path = working.path().join("archive");
+        let exe_path = working.path().join("install.exe");
+        let damaged_path = working.path().join("damaged.exe");
+
+        create_installer("", archive_path.as_path(), &Info::default()).unwrap();
+
+        //The test executable is a plain exe, so it can be used as the stub
+        assert!(read_trailer(std::env::current_exe().unwrap().as_path()).unwrap().is_none());
+
+        extend_exe(std::env::current_exe().unwrap().as_path(), archive_path.as_path(), exe_path.as_path(), None).unwrap();
+
+        let trailer = read_trailer(exe_path.as_path()).unwrap().unwrap();
+
+        let mut archive = Vec::new();
+        get_archive(exe_path.as_path(), &trailer).unwrap().0.read_to_end(& mut archive).unwrap();
+
+        assert_eq!(archive, std::fs::read(archive_path.as_path()).unwrap());
+
+        //The SHA-256 the signature is checked against is hashed in the same pass as the CRC
+        assert_eq!(get_archive(exe_path.as_path(), &trailer).unwrap().1.as_slice(), Sha256::digest(archive.as_slice()).as_slice());
+
+        //The archive can be read in place
+        let read = OakRead::open(get_archive(exe_path.as_path(), &trailer).unwrap().0).unwrap();
+
+        assert!(matches!(read.info().unwrap().oak_type, OakType::Installer));
+
+        let exe = std::fs::read(exe_path.as_path()).unwrap();
+
+        //Appended to
+        let mut appended = exe.clone();
+        appended.extend_from_slice(b"appended");
+        std::fs::write(damaged_path.as_path(), appended).unwrap();
+
+        assert!(matches!(read_trailer(damaged_path.as_path()), Err(Error::Trailer(_))));
+
+        //Missing part of the archive
+        let mut shortened = exe.clone();
+        shortened.remove(trailer.offset as usize);
+        std::fs::write(damaged_path.as_path(), shortened).unwrap();
+
+        assert!(matches!(read_trailer(damaged_path.as_path()), Err(Error::Trailer(_))));
+
+        //Corrupted archive
+        let mut corrupted = exe.clone();
+        corrupted[trailer.offset as usize] ^= 0xff;
+        std::fs::write(damaged_path.as_path(), corrupted).unwrap();
+
+        let trailer = read_trailer(damaged_path.as_path()).unwrap().unwrap();
+
+        assert!(matches!(get_archive(damaged_path.as_path(), &trailer), Err(Error::Trailer(_))));
+    }
+
+    #[test]
+    fn extract_and_verify_test() {
+
+        use crate::exe_extender::open_archive;
+
+        let working = TempDir::new().unwrap();
+
+        let file = working.path().join("file");
+        std::fs::write(file.as_path(), "contents").unwrap();
+
+        let archive_path = working.path().join("archive");
+
+        create_installer(format!("local file = ${:?}$", file).as_str(), archive_path.as_path(), &Info::default()).unwrap();
+
+        let (archive, signature) = open_archive(archive_path.as_path()).unwrap();
+
+        assert!(signature.is_none());
+
+        hlc::verify(archive, None, None, archive_path.as_path()).unwrap();
+
+        let read = OakRead::new(archive_path.as_path()).unwrap();
+
+        let extracted = working.path().join("extracted");
+
+        hlc::extract(&read, extracted.as_path()).unwrap();
+
+        let entries = read.entries();
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(std::fs::read_to_string(extracted.join(entries[0].as_str())).unwrap(), "contents");
+    }
+
+    #[test]
+    fn foreign_stub_test() {
+
+        use crate::error::Error;
+        use crate::exe_extender::{extend_exe, read_trailer};
+
+        let working = TempDir::new().unwrap();
+
+        let archive_path = working.path().join("archive");
+        let stub_path = working.path().join("project_oak.exe");
+        let exe_path = working.path().join("install.exe");
+        let rebuilt_path = working.path().join("rebuilt.exe");
+
+        create_installer("", archive_path.as_path(), &Info::default()).unwrap();
+
+        //Stands in for an executable built for another platform
+        let stub = b"MZ stub for another platform".to_vec();
+        std::fs::write(stub_path.as_path(), stub.as_slice()).unwrap();
+
+        extend_exe(stub_path.as_path(), archive_path.as_path(), exe_path.as_path(), None).unwrap();
+
+        let exe = std::fs::read(exe_path.as_path()).unwrap();
+        let trailer = read_trailer(exe_path.as_path()).unwrap().unwrap();
+
+        assert!(exe.starts_with(stub.as_slice()));
+        assert_eq!(trailer.offset, stub.len() as u64);
+
+        //An installer can be used as the stub, and only its stub is kept
+        extend_exe(exe_path.as_path(), archive_path.as_path(), rebuilt_path.as_path(), None).unwrap();
+
+        assert_eq!(std::fs::read(rebuilt_path.as_path()).unwrap(), exe);
+
+        //Anything that isn't an executable is refused
+        assert!(matches!(extend_exe(archive_path.as_path(), archive_path.as_path(), rebuilt_path.as_path(), None), Err(Error::Stub(_))));
+    }
+
+    #[test]
+    fn preprocess_test() {
+
+        use crate::error::Error;
+        use crate::preprocess::expand;
+
+        let source = r#"
+    local a = $"dir\\file.txt"$ -- a comment with $"not a reference"$
+    local b = '$ in a string' .. "it's $\"also\"$ a string"
+    local c = [==[ long $"string"$ ]] ]==] --[[ long $ comment ]]
+    local d = $'ünïcode'$ .. "$$" .. $$
+"#;
+
+        let mut paths = Vec::new();
+
+        let expanded = expand(source, "test.lua", |path| {
+            paths.push(path.to_string());
+            Ok(format!("\"_{}\"", paths.len() - 1))
+        }).unwrap();
+
+        assert_eq!(paths, vec!["dir\\file.txt".to_string(), "ünïcode".to_string()]);
+
+        assert_eq!(expanded, r#"
+    local a = "_0" -- a comment with $"not a reference"$
+    local b = '$ in a string' .. "it's $\"also\"$ a string"
+    local c = [==[ long $"string"$ ]] ]==] --[[ long $ comment ]]
+    local d = "_1" .. "$$" .. $
+"#);
+
+        //Malformed references are reported with their location
+        let error = |source: &str| {
+            match expand(source, "test.lua", |_| Ok(String::new())) {
+                Err(Error::Preprocess(message)) => {message}
+                _ => {panic!("Expected a preprocessing error")}
+            }
+        };
+
+        assert!(error("local a = 1\n  local b = $path$").starts_with("test.lua:2:13:"));
+        assert!(error("local a = $\"unfinished").starts_with("test.lua:1:11:"));
+        assert!(error("local a = $\"path\" .. b").starts_with("test.lua:1:11:"));
+
+        //A string with escapes references don't support is still skipped as a whole
+        assert_eq!(expand("local a = \"\\x24$\" .. $$", "test.lua", |_| Err("not a reference".to_string())).unwrap(), "local a = \"\\x24$\" .. $");
+
+        //As are references that can't be archived
+        assert!(matches!(expand("$'x'$", "test.lua", |_| Err("missing".to_string())), Err(Error::Preprocess(message)) if message == "test.lua:1:1: missing"));
+    }
+
+    #[test]
+    fn pattern_reference_test() {
+
+        use crate::error::Error;
+
+        let working = TempDir::new().unwrap();
+        let working_path = working.path();
+
+        let assets = working_path.join("assets");
+        let output = working_path.join("output");
+
+        std::fs::create_dir_all(assets.join("sub")).unwrap();
+        std::fs::create_dir_all(output.join("sub")).unwrap();
+
+        std::fs::write(assets.join("a.dll"), "a").unwrap();
+        std::fs::write(assets.join("sub").join("b.dll"), "b").unwrap();
+        std::fs::write(assets.join("c.txt"), "c").unwrap();
+
+        let installer_path = working_path.join("installer");
+        let uninstaller_path = working_path.join("uninstaller");
+
+        //Each matching file is copied to the same relative path under `output`
+        create_installer(format!("
+    for path, name in pairs(${:?}$) do
+        __data(name, pathtype.absolute({:?} .. \"/\" .. path))
+    end
+", assets.join("**").join("*.dll").to_string_lossy(), output.to_string_lossy()).as_str(), installer_path.as_path(), &Info::default()).unwrap();
+
+        assert_eq!(OakRead::new(installer_path.as_path()).unwrap().entries().len(), 2);
+
+        assert!(!hlc::install(OakRead::new(installer_path.as_path()).unwrap(), uninstaller_path.as_path(), &EmulatedRegistry::new(), None));
+
+        assert_eq!(std::fs::read_to_string(output.join("a.dll")).unwrap(), "a");
+        assert_eq!(std::fs::read_to_string(output.join("sub").join("b.dll")).unwrap(), "b");
+        assert!(!output.join("c.txt").exists());
+
+        //A pattern that matches nothing stops the build
+        let result = create_installer(format!("local files = ${:?}$", assets.join("*.exe").to_string_lossy()).as_str(), working_path.join("empty").as_path(), &Info::default());
+
+        assert!(matches!(result, Err(Error::Preprocess(message)) if message.contains("doesn't match any files")));
+    }
+
+    #[test]
+    fn payload_section_test() {
+
+        use crate::oak::PayloadEntry;
+
+        let working = TempDir::new().unwrap();
+        let working_path = working.path();
+
+        let assets = working_path.join("assets");
+        let output = working_path.join("output");
+
+        std::fs::create_dir_all(assets.join("debug")).unwrap();
+        std::fs::create_dir_all(output.join("debug")).unwrap();
+
+        std::fs::write(working_path.join("readme.txt"), "readme").unwrap();
+        std::fs::write(assets.join("a.dll"), "a").unwrap();
+        std::fs::write(assets.join("debug").join("b.dll"), "b").unwrap();
+        std::fs::write(assets.join("c.txt"), "c").unwrap();
+
+        let source_path = working_path.join("source.xml");
+        let installer_path = working_path.join("installer");
+        let uninstaller_path = working_path.join("uninstaller");
+
+        std::fs::write(source_path.as_path(), format!(r#"<Source>
+    <code>
+        local out = "{out}"
+        __data(oak.payload["readme"], pathtype.absolute(out .. "/readme.txt"))
+        for path, name in pairs(oak.payload["assets"]) do
+            __data(name, pathtype.absolute(out .. "/" .. path))
+        end
+    </code>
+    <info>
+        <oak_type>Installer</oak_type>
+        <u_location><Path>{uninstaller}</Path></u_location>
+        <reboot>false</reboot>
+        <elevated>false</elevated>
+    </info>
+    <files>
+        <file name="readme" path="{readme}"/>
+        <file name="assets" path="{assets}">
+            <include>**/*.dll</include>
+            <exclude>debug/**</exclude>
+        </file>
+    </files>
+</Source>"#, out = output.to_string_lossy(), uninstaller = uninstaller_path.to_string_lossy(), readme = working_path.join("readme.txt").to_string_lossy(), assets = assets.to_string_lossy())).unwrap();
+
+        Source::load_from_path(source_path.as_path()).unwrap().create_installer(installer_path.as_path()).unwrap();
+
+        //The payload can be listed without running the script
+        let read = OakRead::new(installer_path.as_path()).unwrap();
+
+        assert!(matches!(read.payload().get("readme"), Some(PayloadEntry::Single(_))));
+        assert!(matches!(read.payload().get("assets"), Some(PayloadEntry::Tree(files)) if files.keys().collect::<Vec<_>>() == vec!["a.dll"]));
+
+        assert!(!hlc::install(read, uninstaller_path.as_path(), &EmulatedRegistry::new(), None));
+
+        assert_eq!(std::fs::read_to_string(output.join("readme.txt")).unwrap(), "readme");
+        assert_eq!(std::fs::read_to_string(output.join("a.dll")).unwrap(), "a");
+        assert!(!output.join("debug").join("b.dll").exists());
+        assert!(!output.join("c.txt").exists());
+    }
+
+    #[test]
+    fn source_formats_test() {
+
+        use crate::error::Error;
+
+        let working = TempDir::new().unwrap();
+        let working_path = working.path();
+
+        let project = working_path.join("project");
+        let output = working_path.join("output");
+
+        std::fs::create_dir_all(project.join("data")).unwrap();
+        std::fs::create_dir_all(output.as_path()).unwrap();
+
+        std::fs::write(project.join("readme.txt"), "readme").unwrap();
+        std::fs::write(project.join("data").join("extra.txt"), "extra").unwrap();
+
+        //The script and both kinds of payload path are relative to the source file, not the working directory
+        std::fs::write(project.join("install.lua"), format!("
+    __data(oak.payload[\"readme\"], pathtype.absolute({:?}))
+    __data($\"data/extra.txt\"$, pathtype.absolute({:?}))
+", output.join("readme.txt").to_string_lossy(), output.join("extra.txt").to_string_lossy())).unwrap();
+
+        std::fs::write(project.join("source.toml"), format!(r#"
+script = "install.lua"
+
+[info]
+oak_type = "Installer"
+u_location = {{ Path = {:?} }}
+reboot = false
+elevated = false
+
+[[files.file]]
+name = "readme"
+path = "readme.txt"
+"#, working_path.join("uninstaller").to_string_lossy())).unwrap();
+
+        let installer_path = working_path.join("installer");
+
+        Source::load_from_path(project.join("source.toml").as_path()).unwrap().create_installer(installer_path.as_path()).unwrap();
+
+        assert!(!hlc::install(OakRead::new(installer_path.as_path()).unwrap(), working_path.join("uninstaller").as_path(), &EmulatedRegistry::new(), None));
+
+        assert_eq!(std::fs::read_to_string(output.join("readme.txt")).unwrap(), "readme");
+        assert_eq!(std::fs::read_to_string(output.join("extra.txt")).unwrap(), "extra");
+
+        //JSON works the same way, and the info can be left out
+        std::fs::write(project.join("source.json"), r#"{"script": "install.lua", "files": {"file": [{"name": "readme", "path": "readme.txt"}]}}"#).unwrap();
+
+        Source::load_from_path(project.join("source.json").as_path()).unwrap().create_installer(working_path.join("json").as_path()).unwrap();
+
+        assert_eq!(OakRead::new(working_path.join("json")).unwrap().payload().len(), 1);
+
+        //Code can be given inline or in a script, but not both
+        std::fs::write(project.join("both.json"), r#"{"code": "print(1)", "script": "install.lua"}"#).unwrap();
+
+        assert!(matches!(Source::load_from_path(project.join("both.json").as_path()), Err(Error::Source(_))));
+    }
+
+    #[test]
+    fn modules_test() {
+
+
+        let working = TempDir::new().unwrap();
+        let working_path = working.path();
+
+        let project = working_path.join("project");
+        let output = working_path.join("output");
+
+        std::fs::create_dir_all(project.join("lib").join("helpers")).unwrap();
+        std::fs::create_dir_all(project.join("lib").join("layout")).unwrap();
+        std::fs::create_dir_all(output.as_path()).unwrap();
+
+        std::fs::write(project.join("readme.txt"), "readme").unwrap();
+
+        std::fs::write(project.join("lib").join("helpers").join("version.lua"), "
+    local version = {}
+    function version.newer(a, b) return a > b end
+    return version
+").unwrap();
+
+        //Modules can reference files, relative to the source
+        std::fs::write(project.join("lib").join("layout").join("init.lua"), format!("
+    return {{ readme = $\"readme.txt\"$, destination = {:?} }}
+", output.join("readme.txt").to_string_lossy())).unwrap();
+
+        std::fs::write(project.join("install.lua"), "
+    local version = require(\"helpers.version\")
+    local layout = require(\"layout\")
+
+    assert(version.newer(2, 1))
+    assert(require(\"helpers.version\") == version)
+
+    __data(layout.readme, pathtype.absolute(layout.destination))
+").unwrap();
+
+        std::fs::write(project.join("source.json"), r#"{"script": "install.lua", "modules": "lib"}"#).unwrap();
+
+        let installer_path = working_path.join("installer");
+
+        Source::load_from_path(project.join("source.json").as_path()).unwrap().create_installer(installer_path.as_path()).unwrap();
+
+        let read = OakRead::new(installer_path.as_path()).unwrap();
+
+        assert_eq!(read.modules().keys().collect::<Vec<_>>(), vec!["helpers.version", "layout.init"]);
+
+        assert!(!hlc::install(read, working_path.join("uninstaller").as_path(), &EmulatedRegistry::new(), None));
+
+        assert_eq!(std::fs::read_to_string(output.join("readme.txt")).unwrap(), "readme");
+
+        //Modules that weren't packed aren't looked for on disk
+        create_installer("require(\"helpers.version\")", working_path.join("missing").as_path(), &Info::default()).unwrap();
+
+        assert!(hlc::install(OakRead::new(working_path.join("missing")).unwrap(), working_path.join("uninstaller2").as_path(), &EmulatedRegistry::new(), None));
+    }
+
+    #[test]
+    fn library_test() {
+
+        use crate::capabilities::Capabilities;
+
+        let working = TempDir::new().unwrap();
+        let working_path = working.path();
+
+        let assets = working_path.join("assets");
+        let output = working_path.join("output");
+
+        std::fs::create_dir_all(assets.join("sub")).unwrap();
+        std::fs::create_dir_all(output.as_path()).unwrap();
+
+        std::fs::write(assets.join("a.dll"), "a").unwrap();
+        std::fs::write(assets.join("sub").join("b.dll"), "b").unwrap();
+        std::fs::write(output.join("app.cfg"), "old").unwrap();
+        std::fs::write(output.join("settings.ini"), "mine").unwrap();
+
+        let installer_path = working_path.join("installer");
+        let uninstaller_path = working_path.join("uninstaller");
+
+        let out = output.to_string_lossy();
+
+        let info = Info {
+            capabilities: Capabilities { write_roots: Some(vec![output.clone()]), ..Capabilities::default() },
+            ..Info::default()
+        };
+
+        create_installer(format!(r#"
+    local out = {out:?}
+
+    oak.lib.install_dir_tree(${assets:?}$, out .. "/nested/tree")
+    oak.lib.replace_file_with_backup(${cfg:?}$, out .. "/app.cfg")
+    assert(not oak.lib.write_config_if_absent(out .. "/settings.ini", "default"))
+    assert(oak.lib.write_config_if_absent(out .. "/new/settings.ini", "default"))
+
+    -- Scratch files stay in the temporary folder, outside the write roots
+    oak.lib.install_dir_tree(${assets:?}$, pathtype.temp("staging/tree"))
+    assert(oak.lib.write_config_if_absent(pathtype.temp("staging/settings.ini"), "scratch"))
+    assert(not oak.lib.write_config_if_absent(pathtype.temp("staging/settings.ini"), "scratch"))
+
+    assert(not oak.lib.is_installed("app"))
+    oak.lib.add_uninstall_entry("app", {{ DisplayName = "App", DisplayVersion = "1.0" }})
+    assert(oak.lib.is_installed("app"))
+
+    oak.lib.reg_append_multi(HKLM, "Software\\App", "Paths", "one")
+    oak.lib.reg_append_multi(HKLM, "Software\\App", "Paths", "two")
+    oak.lib.reg_append_multi(HKLM, "Software\\App", "Paths", "one")
+    oak.lib.reg_remove_multi(HKLM, "Software\\App", "Paths", "two")
+"#, out = out, assets = assets.join("**").join("*.dll").to_string_lossy(), cfg = assets.join("a.dll").to_string_lossy()).as_str(), installer_path.as_path(), &info).unwrap();
+
+        let registry = EmulatedRegistry::new();
+
+        registry.create_key(&RootKey::HKLM, "Software\\App").unwrap();
+
+        assert!(!hlc::install(OakRead::new(installer_path.as_path()).unwrap(), uninstaller_path.as_path(), &registry, None));
+
+        assert_eq!(std::fs::read_to_string(output.join("nested").join("tree").join("sub").join("b.dll")).unwrap(), "b");
+        assert_eq!(std::fs::read_to_string(output.join("app.cfg")).unwrap(), "a");
+        assert_eq!(std::fs::read_to_string(output.join("app.cfg.bak")).unwrap(), "old");
+        assert_eq!(std::fs::read_to_string(output.join("settings.ini")).unwrap(), "mine");
+        assert_eq!(std::fs::read_to_string(output.join("new").join("settings.ini")).unwrap(), "default");
+
+        assert!(matches!(registry.value(&RootKey::HKLM, "Software\\App", "Paths").unwrap(), Some(Data::MultiString(lines)) if lines == vec!["one".to_string()]));
+
+        //Everything the helpers did is undone
+        hlc::uninstall(OakRead::new(uninstaller_path.as_path()).unwrap(), &registry, None);
+
+        assert!(!output.join("nested").exists());
+        assert!(!output.join("new").exists());
+        assert!(!output.join("app.cfg.bak").exists());
+        assert_eq!(std::fs::read_to_string(output.join("app.cfg")).unwrap(), "old");
+
+        assert!(registry.values(&RootKey::HKLM, "Software\\Microsoft\\Windows\\CurrentVersion\\Uninstall\\app").is_err());
+        assert!(registry.value(&RootKey::HKLM, "Software\\App", "Paths").unwrap().is_none());
+    }
+
+    #[test]
+    fn lint_test() {
+
+        use crate::error::Error;
+        use crate::lint::lint;
+        use crate::capabilities::Capabilities;
+
+        let findings = |source: &str, capabilities: &Capabilities| {
+            match lint(source, "install.lua", capabilities) {
+                Ok(()) => {Vec::new()}
+                Err(Error::Lint(findings)) => {findings.lines().map(String::from).collect::<Vec<_>>()}
+                Err(e) => {panic!("Expected lint findings, got {:?}", e)}
+            }
+        };
+
+        //Strings, comments, fields and methods are fine, as are the redirected functions
+        assert!(findings(r#"
+    local t = {}
+    t.load = "io.popen"
+    -- os.execute("shutdown")
+    for line in io.open(pathtype.absolute("C:\\file"), "r"):lines() do print(line) end
+    os.remove(pathtype.absolute("C:\\file"))
+    print(os.time(), os.getenv("PATH"), t.load, [[debug.traceback]])
+"#, &Capabilities::default()).is_empty());
+
+        let found = findings("local p = io.popen(\"cmd\")\nos.exit(1)\nlocal f = \"code\" .. load(\"x\")\ndebug.traceback()\nlocal e = os[\"execute\"]", &Capabilities::default());
+
+        assert_eq!(found.len(), 5);
+        assert!(found[0].starts_with("install.lua:1:11:") && found[0].contains("io.popen"));
+        assert!(found[1].starts_with("install.lua:2:1:") && found[1].contains("os.exit"));
+        assert!(found[2].starts_with("install.lua:3:21:") && found[2].contains("load"));
+        assert!(found[3].starts_with("install.lua:4:1:"));
+        assert!(found[4].starts_with("install.lua:5:11:"));
+
+        //Syntax errors are reported with their line
+        let found = findings("local a = 1\nlocal = 2", &Capabilities::default());
+
+        assert_eq!(found.len(), 1);
+        assert!(found[0].starts_with("install.lua:2:"));
+
+        //Literal paths must be under a declared write root, if there are any
+        let roots = Capabilities { write_roots: Some(vec![PathBuf::from("C:\\Program Files\\App")]), ..Default::default() };
+
+        assert!(findings(r#"__data("_1", pathtype.absolute("c:/program files/app/bin/app.exe"))"#, &roots).is_empty());
+        assert_eq!(findings(r#"__data("_1", pathtype.absolute("C:\\Windows\\app.exe"))"#, &roots).len(), 1);
+        assert_eq!(findings(r#"__data("_1", pathtype.absolute "C:\\Program Files\\App\\..\\Other\\app.exe")"#, &roots).len(), 1);
+        assert!(findings(r#"__data("_1", pathtype.absolute("C:\\Windows\\app.exe"))"#, &Capabilities::default()).is_empty());
+
+        //Only paths that are written to are checked
+        assert!(findings(r#"
+    oak.copy(pathtype.absolute("C:\\Windows\\app.exe"), pathtype.absolute("C:\\Program Files\\App\\app.exe"))
+    local file = io.open(pathtype.absolute("C:\\Windows\\win.ini"), "r")
+    print(oak.exists(__get_abs_path(pathtype.absolute("C:\\Windows"))))
+"#, &roots).is_empty());
+        assert_eq!(findings(r#"io.open(pathtype.absolute("C:\\Windows\\win.ini"), "w")"#, &roots).len(), 1);
+        assert_eq!(findings(r#"oak.lib.replace_file_with_backup("_1", pathtype.absolute("C:\\Program Files\\App\\a"), pathtype.absolute("C:\\Windows\\a"))"#, &roots).len(), 1);
+
+        //The build fails rather than producing an installer with an incomplete uninstaller
+        let working = TempDir::new().unwrap();
+
+        let result = create_installer("io.popen(\"cmd\")", working.path().join("installer").as_path(), &Info::default());
+
+        assert!(matches!(result, Err(Error::Lint(_))));
+    }
+
+    #[test]
+    fn sandbox_test() {
+
+        use crate::mlc::sandbox::{allowed, is_library};
+
+        let working = TempDir::new().unwrap();
+
+        let names_path = working.path().join("names");
+
+        //List every global, and every field of every table global, that scripts can reach
+        run_script(format!("
+    local names = {{}}
+
+    for name, value in pairs(_G) do
+        table.insert(names, name)
+
+        if type(value) == \"table\" and name ~= \"_G\" then
+            for field in pairs(value) do
+                table.insert(names, name .. \".\" .. tostring(field))
+            end
+        end
+    end
+
+    -- Methods of strings go through the string library too
+    assert(getmetatable(\"\").__index == string)
+
+    local file = io.open(pathtype.absolute({:?}), \"w\")
+    file:write(table.concat(names, \"\\n\"))
+    file:close()
+", names_path.to_string_lossy()).as_str(), crate::path_type::Inverse::new(), &crate::filesystem::DiskFileSystem, &EmulatedRegistry::new(), None);
+
+        let names = std::fs::read_to_string(names_path.as_path()).unwrap();
+
+        for name in names.lines() {
+            match name.split_once('.') {
+                Some((library, _)) if is_library(library) => {assert!(allowed(name), "{} is reachable but not vetted", name)}
+                Some(_) => {}
+                None => {assert!(allowed(name), "{} is reachable but not vetted", name)}
+            }
+        }
+
+        //Some of the things that used to be reachable
+        for name in ["load", "dofile", "loadfile", "debug", "package", "io.popen", "io.output", "io.lines", "os.execute", "os.exit", "string.dump", "____io_open", "__payload"] {
+            assert!(!names.lines().any(|reachable| reachable == name), "{} is reachable", name);
+        }
+
+        assert!(names.lines().any(|reachable| reachable == "oak.lib"));
+        assert!(names.lines().any(|reachable| reachable == "os.remove"));
+    }
+
+    #[test]
+    fn capabilities_test() {
+
+        use crate::capabilities::Capabilities;
+
+        let working = TempDir::new().unwrap();
+        let working_path = working.path();
+
+        let allowed = working_path.join("allowed");
+        let other = working_path.join("other");
+
+        std::fs::create_dir_all(allowed.as_path()).unwrap();
+        std::fs::create_dir_all(other.as_path()).unwrap();
+
+        let sample = working_path.join("sample");
+        std::fs::write(sample.as_path(), "sample").unwrap();
+
+        let info = Info {
+            capabilities: Capabilities {
+                write_roots: Some(vec![allowed.clone()]),
+                registry_keys: Some(vec!["HKCU\\Software\\App".to_string()]),
+                hosts: Some(vec!["example.com".to_string()]),
+            },
+            ..Info::default()
+        };
+
+        let build = |name: &str, violation: &str| {
+            let installer_path = working_path.join(name);
+
+            create_installer(format!("
+    local allowed = {:?}
+    local other = {:?}
+    local sample = {:?}
+
+    __copy(pathtype.absolute(sample), pathtype.absolute(allowed .. \"/copy\"))
+    __mkdir(pathtype.temp(\"scratch\"))
+    __reg_write_key(HKCU, \"Software\\\\App\\\\Inner\")
+    {}
+", allowed.to_string_lossy(), other.to_string_lossy(), sample.to_string_lossy(), violation).as_str(), installer_path.as_path(), &info).unwrap();
+
+            installer_path
+        };
+
+        let install = |installer_path: &Path, registry: &EmulatedRegistry| {
+            hlc::install(OakRead::new(installer_path).unwrap(), working_path.join("uninstaller").with_extension(installer_path.file_name().unwrap()).as_path(), registry, None)
+        };
+
+        //Staying within the capabilities works
+        let registry = EmulatedRegistry::new();
+
+        assert!(!install(build("within", "").as_path(), &registry));
+        assert!(allowed.join("copy").exists());
+        assert!(registry.subkeys(&RootKey::HKCU, "Software\\App\\Inner").is_ok());
+
+        std::fs::remove_file(allowed.join("copy")).unwrap();
+
+        //Anything else fails the install, which is rolled back
+        for (name, violation) in [
+            ("file", "__copy(pathtype.absolute(allowed .. \"/copy\"), pathtype.absolute(other .. \"/copy\"))"),
+            ("escape", "__mkdir(pathtype.absolute(allowed .. \"/../other/dir\"))"),
+            ("key", "__reg_write_key(HKCU, \"Software\\\\Other\")"),
+            ("host", "__download(\"https://example.org/file\", pathtype.absolute(allowed .. \"/file\"))"),
+        ] {
+            let registry = EmulatedRegistry::new();
+
+            assert!(install(build(name, violation).as_path(), &registry), "{} should have failed", name);
+
+            assert!(!allowed.join("copy").exists());
+            assert!(!other.join("copy").exists());
+            assert!(!other.join("dir").exists());
+            assert!(registry.subkeys(&RootKey::HKCU, "Software\\App\\Inner").is_err());
+        }
+
+        //Subdomains of a declared host are allowed
+        assert!(info.capabilities.check_url("https://downloads.example.com/file").is_ok());
+        assert!(info.capabilities.check_url("https://example.com.evil.org/file").is_err());
+    }
+
+    #[test]
+    fn path_traversal_test() {
+
+        use crate::error::Error;
+        use crate::filesystem::{extract_zip, DiskFileSystem};
+        use crate::path_type::enclosed;
+
+        let working = TempDir::new().unwrap();
+        let working_path = working.path();
+
+        let base = Path::new("base");
+
+        assert_eq!(enclosed(base, "a/./b/../c").unwrap(), base.join("a").join("c"));
+        assert_eq!(enclosed(base, "a\\b").unwrap(), base.join("a").join("b"));
+
+        for name in ["../a", "a/../../b", "..\\a", "/etc/passwd", "\\a", "C:\\a", "C:a", "file:stream"] {
+            assert!(matches!(enclosed(base, name), Err(Error::PathTraversal(_))), "{} should escape", name);
+        }
+
+        let crafted = |name: &str| {
+            let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+
+            zip.start_file("fine", zip::write::FileOptions::default()).unwrap();
+            zip.write_all(b"fine").unwrap();
+            zip.start_file(name, zip::write::FileOptions::default()).unwrap();
+            zip.write_all(b"evil").unwrap();
+
+            zip.finish().unwrap().into_inner()
+        };
+
+        let target = working_path.join("target");
+
+        //Extracting folders from an archive, or unzipping a file, never writes outside the destination
+        let escape = format!("../{}", working_path.file_name().unwrap().to_string_lossy());
+
+        for name in ["../evil", escape.as_str(), working_path.join("evil").to_str().unwrap()] {
+            let result = extract_zip(&DiskFileSystem, std::io::Cursor::new(crafted(name)), target.as_path());
+
+            assert!(matches!(result, Err(Error::PathTraversal(_))), "{} should have been rejected", name);
+        }
+
+        assert!(!working_path.join("evil").exists());
+
+        let archive = working_path.join("crafted.zip");
+        std::fs::write(archive.as_path(), crafted("../evil")).unwrap();
+
+        let install = |name: &str, code: String| {
+            let installer_path = working_path.join(name);
+
+            create_installer(code.as_str(), installer_path.as_path(), &Info::default()).unwrap();
+
+            hlc::install(OakRead::new(installer_path.as_path()).unwrap(), working_path.join("uninstaller").with_extension(name).as_path(), &EmulatedRegistry::new(), None)
+        };
+
+        assert!(install("unzip", format!("__unzip(pathtype.absolute({:?}), pathtype.absolute({:?}))", archive, working_path.join("unzipped"))));
+
+        assert!(!working_path.join("evil").exists());
+        assert!(!working_path.join("unzipped").exists());
+
+        //Temporary paths can't leave the temporary folder, where they would skip the uninstaller
+        assert!(install("temporary", format!("__mkdir(pathtype.temp(\"../{}\"))", "escaped")));
+
+        assert!(!install("inside", format!("__mkdir(pathtype.temp(\"a/../{}\"))", "inside")));
+    }
+
+    #[test]
+    fn download_test() {
+
+        use sha2::{Digest, Sha256};
+
+        let working = TempDir::new().unwrap();
+        let working_path = working.path();
+
+        //Every byte value, so anything that decodes the body as text would corrupt it
+        let body: Vec<u8> = (0..100 * 1024).map(|i| (i % 251) as u8).collect();
+        let sha256 = format!("{:x}", Sha256::digest(&body));
+
+        let (address, requests) = serve(body.clone());
+
+        let install = |name: &str, code: String| {
+            let installer_path = working_path.join(name);
+
+            create_installer(code.as_str(), installer_path.as_path(), &Info::default()).unwrap();
+
+            hlc::install(OakRead::new(installer_path.as_path()).unwrap(), working_path.join("uninstaller").with_extension(name).as_path(), &EmulatedRegistry::new(), None)
+        };
+
+        let folder = working_path.join("folder");
+        std::fs::create_dir(folder.as_path()).unwrap();
+
+        //A file that doesn't exist yet is downloaded to, resuming after the dropped connection
+        assert!(!install("flaky", format!("
+    __download(\"http://{}/flaky.bin\", pathtype.absolute({:?}), {:?})
+    __download(\"http://{}/data.bin\", pathtype.absolute({:?}), {:?})
+", address, working_path.join("flaky.bin"), sha256.to_uppercase(), address, folder, sha256)));
+
+        assert_eq!(std::fs::read(working_path.join("flaky.bin")).unwrap(), body);
+        assert_eq!(std::fs::read(folder.join("data.bin")).unwrap(), body);
+
+        assert!(requests.lock().unwrap().contains(&("/flaky.bin".to_string(), Some(body.len() / 2))));
+
+        //A mismatched checksum fails the install, which is rolled back
+        assert!(install("mismatch", format!("__download(\"http://{}/data.bin\", pathtype.absolute({:?}), {:?})", address, working_path.join("mismatch.bin"), "0".repeat(64))));
+
+        assert!(!working_path.join("mismatch.bin").exists());
+
+        //The server refusing isn't retried
+        assert!(install("missing", format!("__download(\"http://{}/missing\", pathtype.absolute({:?}))", address, working_path.join("missing.bin"))));
+
+        assert_eq!(requests.lock().unwrap().iter().filter(|(path, _)| path == "/missing").count(), 1);
+
+        //A file that already exists is backed up, and restored when the download is undone
+        let existing = working_path.join("existing.bin");
+        std::fs::write(existing.as_path(), "original").unwrap();
+
+        assert!(install("existing", format!("
+    __download(\"http://{}/data.bin\", pathtype.absolute({:?}))
+    error()
+", address, existing)));
+
+        assert_eq!(std::fs::read_to_string(existing.as_path()).unwrap(), "original");
+    }
+
+    #[test]
+    fn offline_test() {
+
+        use crate::capabilities::Capabilities;
+        use crate::error::Error;
+
+        let working = TempDir::new().unwrap();
+        let working_path = working.path();
+
+        let body: Vec<u8> = (0..64 * 1024).map(|i| (i % 256) as u8).collect();
+
+        let (address, requests) = serve(body.clone());
+
+        let folder = working_path.join("folder");
+        std::fs::create_dir(folder.as_path()).unwrap();
+
+        let code = format!("
+    oak.download(\"http://{}/data.bin\", pathtype.absolute({:?}))
+    __download(\"http://{}/data.bin\", pathtype.absolute({:?}))
+", address, working_path.join("data"), address, folder);
+
+        let url = format!("http://{}/data.bin", address);
+
+        //Online installers download when they are run
+        let online = working_path.join("online");
+
+        let mut source = Source { code, path: PathBuf::from("script"), base: working_path.to_path_buf(), ..Source::default() };
+
+        assert!(source.create_installer(online.as_path()).unwrap().is_empty());
+
+        assert!(requests.lock().unwrap().is_empty());
+        assert!(OakRead::new(online.as_path()).unwrap().downloads().is_empty());
+
+        //Offline installers fetch each url once, when they are built
+        let offline = working_path.join("offline");
+
+        source.offline = true;
+
+        assert_eq!(source.create_installer(offline.as_path()).unwrap(), vec![url.clone()]);
+
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        let read = OakRead::new(offline.as_path()).unwrap();
+
+        assert_eq!(read.downloads().keys().collect::<Vec<_>>(), vec![&url]);
+
+        assert!(!hlc::install(read, working_path.join("uninstaller").as_path(), &EmulatedRegistry::new(), None));
+
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        assert_eq!(std::fs::read(working_path.join("data")).unwrap(), body);
+        assert_eq!(std::fs::read(folder.join("data.bin")).unwrap(), body);
+
+        //Prefetched urls are extracted through the file system the installer runs against, so a dry run leaves the disk alone
+        let dry = working_path.join("dry");
+
+        source.code = format!("
+    oak.lib.ensure_dir_all({:?})
+    __download({:?}, pathtype.absolute({:?}))
+", dry, url, dry.join("data"));
+
+        source.create_installer(working_path.join("dry_installer").as_path()).unwrap();
+
+        assert!(hlc::dry_run(working_path.join("dry_installer"), &EmulatedRegistry::new()).unwrap().contains(&dry.join("data")));
+        assert!(!dry.exists());
+
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        //Urls outside the declared hosts aren't fetched
+        source.info.capabilities = Capabilities { hosts: Some(vec![String::from("example.com")]), ..Capabilities::default() };
+
+        assert!(matches!(source.create_installer(working_path.join("forbidden").as_path()), Err(Error::Capability(_))));
+
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+}